@@ -0,0 +1,513 @@
+use cpu::PROGRAM_START;
+use disasm;
+use disasm::Syntax;
+use instruction::Instruction;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Longest run of data bytes on one listing line
+const DATA_BYTES_PER_LINE: usize = 4;
+
+/// How control gets from one instruction to the next
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    Fallthrough,
+    Jump,
+    Call,
+    /// The instruction after the next, when a skip is taken
+    Skip,
+}
+
+/// Memory drawn by DXYN while I holds an address loaded by ANNN
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sprite {
+    pub address: u16,
+    pub rows: u16,
+    /// 2 for the 16x16 sprites of DXY0
+    pub bytes_per_row: u16,
+}
+
+impl Sprite {
+    pub fn size(&self) -> u16 {
+        self.rows * self.bytes_per_row
+    }
+}
+
+/// Static analysis of a ROM loaded at 0x200, or at the address given to `with_start`
+/// Follows jumps, calls and both sides of skips from the entry point, so sprite data and other
+/// bytes execution never reaches aren't mistaken for code. BNNN jumps depend on V0 and can't be
+/// followed, code only reached through them is shown as data.
+pub struct Analysis {
+    rom: Vec<u8>,
+    // load address and entry point
+    start: usize,
+    code: BTreeMap<u16, Instruction>,
+    jump_targets: BTreeSet<u16>,
+    subroutines: BTreeSet<u16>,
+    sprites: BTreeMap<u16, Sprite>,
+}
+
+impl Analysis {
+    pub fn new(rom: &[u8]) -> Analysis {
+        Analysis::with_start(rom, PROGRAM_START as u16)
+    }
+
+    /// Analyse a ROM that is loaded and entered at `start`, see `Cpu::with_start_address`
+    pub fn with_start(rom: &[u8], start: u16) -> Analysis {
+        let mut analysis = Analysis {
+            rom: rom.to_vec(),
+            start: start as usize,
+            code: BTreeMap::new(),
+            jump_targets: BTreeSet::new(),
+            subroutines: BTreeSet::new(),
+            sprites: BTreeMap::new(),
+        };
+        analysis.trace();
+        analysis
+    }
+
+    /// Recursive descent from the entry point, remembering I along each path to find sprites
+    fn trace(&mut self) {
+        let mut pending = vec![(self.start as u16, None)];
+        while let Some((mut address, mut i)) = pending.pop() {
+            while !self.code.contains_key(&address) {
+                let instruction = match self.decode_at(address) {
+                    Some(instruction) => instruction,
+                    None => break,
+                };
+                self.code.insert(address, instruction);
+
+                match instruction {
+                    Instruction::SetIndex(target) => i = Some(target),
+                    Instruction::SetIndexLong => i = Some(self.word_at(address + 2)),
+                    Instruction::Draw(_, _, lines) => {
+                        if let Some(sprite_address) = i {
+                            self.add_sprite(sprite_address, lines);
+                        }
+                    }
+                    Instruction::AddToIndex(_)
+                    | Instruction::FontCharacter(_)
+                    | Instruction::BigFontCharacter(_)
+                    | Instruction::StoreRegisters(_)
+                    | Instruction::LoadRegisters(_) => i = None,
+                    _ => {}
+                }
+
+                let mut next = None;
+                for (target, flow) in self.successors(address, instruction) {
+                    match flow {
+                        Flow::Fallthrough => next = Some(target),
+                        Flow::Jump => {
+                            self.jump_targets.insert(target);
+                            pending.push((target, i));
+                        }
+                        Flow::Call => {
+                            self.subroutines.insert(target);
+                            pending.push((target, i));
+                        }
+                        Flow::Skip => pending.push((target, i)),
+                    }
+                }
+                if let Instruction::Call(_) = instruction {
+                    // the subroutine may have changed I
+                    i = None;
+                }
+                match next {
+                    Some(next) => address = next,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn add_sprite(&mut self, address: u16, lines: u8) {
+        let (rows, bytes_per_row) = match lines {
+            0 => (16, 2),
+            n => (n as u16, 1),
+        };
+        if self.offset(address).is_none() {
+            // fonts and scratch memory outside the ROM
+            return;
+        }
+        let sprite = self.sprites.entry(address).or_insert(Sprite {
+            address,
+            rows,
+            bytes_per_row,
+        });
+        if rows * bytes_per_row > sprite.size() {
+            sprite.rows = rows;
+            sprite.bytes_per_row = bytes_per_row;
+        }
+    }
+
+    /// Where execution can continue after the instruction at `address`
+    pub fn successors(&self, address: u16, instruction: Instruction) -> Vec<(u16, Flow)> {
+        let next = address.wrapping_add(instruction.size());
+        match instruction {
+            Instruction::Jump(target) => vec![(target, Flow::Jump)],
+            Instruction::Call(target) => vec![(target, Flow::Call), (next, Flow::Fallthrough)],
+            Instruction::Return | Instruction::Exit | Instruction::JumpWithOffset(_) => vec![],
+            Instruction::SkipIfEqual(..)
+            | Instruction::SkipIfNotEqual(..)
+            | Instruction::SkipIfRegistersEqual(..)
+            | Instruction::SkipIfRegistersNotEqual(..)
+            | Instruction::SkipIfKeyPressed(_)
+            | Instruction::SkipIfKeyNotPressed(_) => {
+                // skips jump over the whole of F000 NNNN
+                let skipped = match self.decode_at(next) {
+                    Some(skipped) => skipped.size(),
+                    None => 2,
+                };
+                vec![
+                    (next, Flow::Fallthrough),
+                    (next.wrapping_add(skipped), Flow::Skip),
+                ]
+            }
+            _ => vec![(next, Flow::Fallthrough)],
+        }
+    }
+
+    fn offset(&self, address: u16) -> Option<usize> {
+        (address as usize)
+            .checked_sub(self.start)
+            .filter(|&offset| offset < self.rom.len())
+    }
+
+    fn word_at(&self, address: u16) -> u16 {
+        let offset = address as usize - self.start;
+        (self.rom[offset] as u16) << 8 | self.rom[offset + 1] as u16
+    }
+
+    fn decode_at(&self, address: u16) -> Option<Instruction> {
+        let offset = self.offset(address)?;
+        if offset + 2 > self.rom.len() {
+            return None;
+        }
+        match Instruction::decode(self.word_at(address)) {
+            Instruction::Unknown(_) => None,
+            Instruction::SetIndexLong if offset + 4 > self.rom.len() => None,
+            instruction => Some(instruction),
+        }
+    }
+
+    /// The instruction starting at `address`, if execution can reach it
+    pub fn instruction_at(&self, address: u16) -> Option<Instruction> {
+        self.code.get(&address).cloned()
+    }
+
+    /// Every reachable instruction by address
+    pub fn code(&self) -> &BTreeMap<u16, Instruction> {
+        &self.code
+    }
+
+    pub fn is_code(&self, address: u16) -> bool {
+        self.code
+            .range(..=address)
+            .next_back()
+            .is_some_and(|(&start, instruction)| {
+                (address as u32) < start as u32 + instruction.size() as u32
+            })
+    }
+
+    pub fn subroutines(&self) -> &BTreeSet<u16> {
+        &self.subroutines
+    }
+
+    pub fn jump_targets(&self) -> &BTreeSet<u16> {
+        &self.jump_targets
+    }
+
+    pub fn sprites(&self) -> &BTreeMap<u16, Sprite> {
+        &self.sprites
+    }
+
+    /// Subroutines are named sub_NNN, other jump targets label_NNN and sprites sprite_NNN
+    pub fn label(&self, address: u16) -> Option<String> {
+        if self.subroutines.contains(&address) {
+            Some(format!("sub_{:03X}", address))
+        } else if self.jump_targets.contains(&address) {
+            Some(format!("label_{:03X}", address))
+        } else if self.sprites.contains_key(&address) {
+            Some(format!("sprite_{:03X}", address))
+        } else {
+            None
+        }
+    }
+
+    /// The mnemonic of the instruction at `address` with its target replaced by a label
+    fn text(&self, address: u16, instruction: Instruction, syntax: Syntax) -> String {
+        let target = match instruction {
+            Instruction::Jump(target)
+            | Instruction::Call(target)
+            | Instruction::SetIndex(target)
+            | Instruction::JumpWithOffset(target) => target,
+            Instruction::SetIndexLong => self.word_at(address + 2),
+            _ => return disasm::mnemonic(instruction, syntax),
+        };
+        let label = match self.label(target) {
+            Some(label) => label,
+            None => return self.line(address, instruction.size()).text(syntax),
+        };
+        match (syntax, instruction) {
+            (Syntax::Cowgod, Instruction::Jump(_)) => format!("JP {}", label),
+            (Syntax::Cowgod, Instruction::Call(_)) => format!("CALL {}", label),
+            (Syntax::Cowgod, Instruction::SetIndex(_)) => format!("LD I, {}", label),
+            (Syntax::Cowgod, Instruction::JumpWithOffset(_)) => format!("JP V0, {}", label),
+            (Syntax::Cowgod, _) => format!("LD I, LONG {}", label),
+            (Syntax::Octo, Instruction::Jump(_)) => format!("jump {}", label),
+            // a bare label is a call in Octo
+            (Syntax::Octo, Instruction::Call(_)) => label,
+            (Syntax::Octo, Instruction::SetIndex(_)) => format!("i := {}", label),
+            (Syntax::Octo, Instruction::JumpWithOffset(_)) => format!("jump0 {}", label),
+            (Syntax::Octo, _) => format!("i := long {}", label),
+        }
+    }
+
+    fn line(&self, address: u16, size: u16) -> disasm::Line {
+        let offset = address as usize - self.start;
+        disasm::Line {
+            address,
+            bytes: self.rom[offset..offset + size as usize].to_vec(),
+            instruction: self.instruction_at(address),
+        }
+    }
+
+    fn sprite_containing(&self, address: u16) -> Option<Sprite> {
+        self.sprites
+            .range(..=address)
+            .next_back()
+            .map(|(_, &sprite)| sprite)
+            .filter(|sprite| (address as u32) < sprite.address as u32 + sprite.size() as u32)
+    }
+
+    /// A listing with labels, code where execution can reach it and data everywhere else
+    /// Sprite rows are drawn next to their bytes
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let mut listing = String::new();
+        // as usize, a rom can reach the end of the 16 bit address space
+        let end = self.start + self.rom.len();
+        let mut next = self.start;
+        while next < end {
+            let address = next as u16;
+            if let Some(label) = self.label(address) {
+                match syntax {
+                    Syntax::Cowgod => listing.push_str(&format!("{}:\n", label)),
+                    Syntax::Octo => listing.push_str(&format!(": {}\n", label)),
+                }
+            }
+
+            if let Some(instruction) = self.instruction_at(address) {
+                let size = instruction.size();
+                let line = self.line(address, size);
+                let text = self.text(address, instruction, syntax);
+                listing.push_str(&disasm::format_line(address, &line.bytes, &text, syntax));
+                listing.push('\n');
+                next += size as usize;
+                continue;
+            }
+
+            let sprite = self.sprite_containing(address);
+            let limit = match sprite {
+                Some(sprite) => sprite.bytes_per_row,
+                None => DATA_BYTES_PER_LINE as u16,
+            };
+            let mut size = 1;
+            while size < limit
+                && next + (size as usize) < end
+                && self.label(address + size).is_none()
+                && !self.code.contains_key(&(address + size))
+                && self.sprite_containing(address + size) == sprite
+            {
+                size += 1;
+            }
+            let line = self.line(address, size);
+            let mut text = disasm::data(&line.bytes, syntax);
+            if sprite.is_some() {
+                let pixels: String = line
+                    .bytes
+                    .iter()
+                    .flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1))
+                    .map(|pixel| if pixel == 1 { '#' } else { '.' })
+                    .collect();
+                match syntax {
+                    Syntax::Cowgod => text.push_str(&format!(" ; {}", pixels)),
+                    Syntax::Octo => text.push_str(&format!(" # {}", pixels)),
+                }
+            }
+            listing.push_str(&disasm::format_line(address, &line.bytes, &text, syntax));
+            listing.push('\n');
+            next += size as usize;
+        }
+        listing
+    }
+
+    /// The control flow graph in Graphviz DOT format, one node per basic block
+    /// Calls are dashed, taken skips are labelled
+    pub fn dot(&self) -> String {
+        let mut leaders = BTreeSet::new();
+        leaders.insert(self.start as u16);
+        for (&address, &instruction) in &self.code {
+            let successors = self.successors(address, instruction);
+            let ends_block = successors.len() != 1 || successors[0].1 != Flow::Fallthrough;
+            for (target, flow) in successors {
+                if ends_block || flow != Flow::Fallthrough {
+                    leaders.insert(target);
+                }
+            }
+        }
+
+        // blocks as their first address and instructions
+        let mut blocks: Vec<(u16, Vec<(u16, Instruction)>)> = Vec::new();
+        let mut expected = None;
+        for (&address, &instruction) in &self.code {
+            if leaders.contains(&address) || expected != Some(address) || blocks.is_empty() {
+                blocks.push((address, Vec::new()));
+            }
+            if let Some(block) = blocks.last_mut() {
+                block.1.push((address, instruction));
+            }
+            let successors = self.successors(address, instruction);
+            let falls_through = successors.len() == 1 && successors[0].1 == Flow::Fallthrough;
+            expected = if falls_through {
+                Some(successors[0].0)
+            } else {
+                None
+            };
+        }
+
+        let mut dot = String::from("digraph chip8 {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for &(start, ref instructions) in &blocks {
+            let mut label = String::new();
+            if let Some(name) = self.label(start) {
+                label.push_str(&format!("{}:\\l", name));
+            }
+            for &(address, instruction) in instructions {
+                let text = self.text(address, instruction, Syntax::Cowgod);
+                label.push_str(&format!(
+                    "{:04X}  {}\\l",
+                    address,
+                    text.replace('"', "\\\"")
+                ));
+            }
+            dot.push_str(&format!("    b{:03X} [label=\"{}\"];\n", start, label));
+        }
+        for &(start, ref instructions) in &blocks {
+            let (address, instruction) = instructions[instructions.len() - 1];
+            for (target, flow) in self.successors(address, instruction) {
+                if !self.code.contains_key(&target) {
+                    continue;
+                }
+                let attributes = match flow {
+                    Flow::Fallthrough | Flow::Jump => "",
+                    Flow::Call => " [style=dashed, label=\"call\"]",
+                    Flow::Skip => " [label=\"skip\"]",
+                };
+                dot.push_str(&format!(
+                    "    b{:03X} -> b{:03X}{};\n",
+                    start, target, attributes
+                ));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use analysis::*;
+
+    const ROM: [u8; 20] = [
+        0xA2, 0x0E, // I := sprite
+        0x22, 0x0A, // call 0x20A
+        0x30, 0x01, // skip if V0 == 1
+        0x12, 0x04, // jump 0x204
+        0x00, 0xFD, // exit
+        0xD0, 0x15, // draw 5 rows
+        0x00, 0xEE, // return
+        0xF0, 0x90, 0x90, 0x90, 0xF0, // sprite
+        0xFF, // never reached
+    ];
+
+    #[test]
+    fn test_code_and_data() {
+        let analysis = Analysis::new(&ROM);
+        for address in (0x200..0x20E).step_by(2) {
+            assert!(analysis.is_code(address), "{:X}", address);
+        }
+        assert!(analysis.is_code(0x20D));
+        assert!(!analysis.is_code(0x20E));
+        assert!(!analysis.is_code(0x213));
+        assert_eq!(
+            analysis.instruction_at(0x20A),
+            Some(Instruction::Draw(0, 1, 5))
+        );
+        assert!(analysis.subroutines().contains(&0x20A));
+        assert!(analysis.jump_targets().contains(&0x204));
+        assert_eq!(
+            analysis.sprites().get(&0x20E),
+            Some(&Sprite {
+                address: 0x20E,
+                rows: 5,
+                bytes_per_row: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_start_address() {
+        // jump 0x604, data, exit
+        let rom = [0x16, 0x04, 0xFF, 0xFF, 0x00, 0xFD];
+        let analysis = Analysis::with_start(&rom, 0x600);
+        assert_eq!(analysis.code().len(), 2);
+        assert!(analysis.is_code(0x604));
+        assert!(!analysis.is_code(0x602));
+        assert!(analysis.jump_targets().contains(&0x604));
+        assert!(analysis
+            .listing(Syntax::Cowgod)
+            .starts_with("0600  1604       JP label_604\n"));
+    }
+
+    #[test]
+    fn test_rom_up_to_end_of_memory() {
+        // fills 0x200 to 0xFFFF: exit, then data
+        let mut rom = vec![0; 0xFE00];
+        rom[..2].copy_from_slice(&[0x00, 0xFD]);
+        let listing = Analysis::new(&rom).listing(Syntax::Cowgod);
+        assert!(listing.starts_with("0200  00FD       EXIT\n"));
+        assert!(listing.ends_with("\nFFFE  0000       DW #0000\n"));
+        assert_eq!(listing.lines().count(), 1 + (0xFE00 - 2 + 3) / 4);
+    }
+
+    #[test]
+    fn test_listing() {
+        let analysis = Analysis::new(&ROM);
+        let listing = analysis.listing(Syntax::Cowgod);
+        assert!(listing.contains("0200  A20E       LD I, sprite_20E\n"));
+        assert!(listing.contains("0202  220A       CALL sub_20A\n"));
+        assert!(listing.contains("sub_20A:\n020A  D015"));
+        assert!(listing.contains("sprite_20E:\n020E  F0         DB #F0 ; ####....\n"));
+        assert!(listing.ends_with("0213  FF         DB #FF\n"));
+
+        let listing = analysis.listing(Syntax::Octo);
+        assert!(listing.contains("\n: label_204\nif v0 != 0x01 then"));
+        assert!(listing.contains("\nsub_20A "));
+        assert!(listing.contains("\njump label_204 "));
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = Analysis::new(&ROM).dot();
+        assert!(dot.starts_with("digraph chip8 {\n"));
+        assert!(
+            dot.contains("    b200 [label=\"0200  LD I, sprite_20E\\l0202  CALL sub_20A\\l\"];\n")
+        );
+        assert!(dot.contains("    b200 -> b20A [style=dashed, label=\"call\"];\n"));
+        assert!(dot.contains("    b200 -> b204;\n"));
+        assert!(dot.contains("    b204 -> b206;\n"));
+        assert!(dot.contains("    b204 -> b208 [label=\"skip\"];\n"));
+        assert!(dot.contains("    b206 -> b204;\n"));
+        assert!(!dot.contains("b208 ->"));
+        assert!(!dot.contains("b20A ->"));
+    }
+}
@@ -0,0 +1,868 @@
+use cpu::PROGRAM_START;
+use error::AssembleError;
+use std::collections::{HashMap, VecDeque};
+
+/// Highest address a program can reach with the XO-CHIP address space
+const MAX_ADDRESS: usize = 0xFFFF;
+/// Stops recursive macros from expanding forever
+const MAX_EXPANDED_TOKENS: usize = 1_000_000;
+
+/// A word of source and where it starts
+#[derive(Clone, Debug, PartialEq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error<T, S: Into<String>>(&self, message: S) -> Result<T, AssembleError> {
+        Err(AssembleError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        })
+    }
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Where a label still has to be filled in once it is defined
+#[derive(Clone, Copy, PartialEq)]
+enum Fixup {
+    /// The low 12 bits of the word at the offset
+    Address,
+    /// The 16 bit word after F000 at the offset
+    Long,
+}
+
+/// Open `begin`, `else` and `loop` blocks
+enum Block {
+    /// Offset of the jump that leaves the `begin` branch
+    If(usize),
+    /// Offset of the jump over the `else` branch
+    Else(usize),
+    /// Start address and the offsets of the jumps out of each `while`
+    Loop(u16, Vec<usize>),
+}
+
+/// An address operand that might refer to a label defined later
+enum Operand {
+    Value(u16),
+    Label(Token),
+}
+
+/// Assemble Octo source into a ROM to be loaded at 0x200
+///
+/// Supported are labels (`: name`), `:const`, `:alias`, `:macro`, `:call`, `:byte`, `:org`,
+/// all statements of CHIP-8, SUPER-CHIP and XO-CHIP, `if ... then`, `if ... begin ... else ... end`
+/// with `==`, `!=`, `key` and `-key`, `loop ... while ... again` and bare numbers as sprite data.
+/// Like Octo, a `jump main` is put at 0x200 when the program doesn't start with `: main`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let tokens = tokenize(source);
+    let mut assembler = Assembler {
+        end: tokens.back().cloned().unwrap_or(Token {
+            text: String::new(),
+            line: 1,
+            column: 1,
+        }),
+        tokens,
+        rom: Vec::new(),
+        here: 0,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        expanded_tokens: 0,
+    };
+    assembler.run()?;
+    Ok(assembler.rom)
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (line_index, line) in source.lines().enumerate() {
+        let mut start = None;
+        for (column, character) in line.chars().chain(Some(' ')).enumerate() {
+            if !character.is_whitespace() {
+                if start.is_none() {
+                    if character == '#' {
+                        break;
+                    }
+                    start = Some(column);
+                }
+                continue;
+            }
+            if let Some(first) = start.take() {
+                tokens.push_back(Token {
+                    text: line.chars().skip(first).take(column - first).collect(),
+                    line: line_index + 1,
+                    column: first + 1,
+                });
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i32::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut characters = text.chars();
+    match (characters.next(), characters.next(), characters.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+            digit.to_digit(16).map(|register| register as u8)
+        }
+        _ => None,
+    }
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    /// The last token of the source, for errors at the end of it
+    end: Token,
+    rom: Vec<u8>,
+    /// Offset of the next byte from 0x200
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i32>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(usize, Fixup, Token)>,
+    blocks: Vec<(Block, Token)>,
+    expanded_tokens: usize,
+}
+
+impl Assembler {
+    fn run(&mut self) -> Result<(), AssembleError> {
+        let starts_with_main = match (self.tokens.front(), self.tokens.get(1)) {
+            (Some(colon), Some(name)) => colon.text == ":" && name.text == "main",
+            _ => false,
+        };
+        let has_main = self
+            .tokens
+            .iter()
+            .zip(self.tokens.iter().skip(1))
+            .any(|(colon, name)| colon.text == ":" && name.text == "main");
+        if has_main && !starts_with_main {
+            let main = self
+                .tokens
+                .iter()
+                .find(|token| token.text == "main")
+                .cloned();
+            if let Some(main) = main {
+                self.emit_address(0x1000, Operand::Label(main));
+            }
+        }
+
+        while let Some(token) = self.tokens.pop_front() {
+            self.statement(&token)?;
+            if PROGRAM_START + self.rom.len() > MAX_ADDRESS + 1 {
+                return token.error("program does not fit into 64 KiB");
+            }
+        }
+
+        if let Some((_, token)) = self.blocks.last() {
+            return token.error(format!("'{}' is never closed", token.text));
+        }
+        for (offset, fixup, token) in self.fixups.clone() {
+            let address = match self.labels.get(&token.text) {
+                Some(&address) => address,
+                None => return token.error(format!("undefined label '{}'", token.text)),
+            };
+            match fixup {
+                Fixup::Address => {
+                    if address > 0xFFF {
+                        return token.error(format!(
+                            "'{}' is at 0x{:X}, out of reach of 12 bit addresses",
+                            token.text, address
+                        ));
+                    }
+                    let word = self.word(offset) | address;
+                    self.patch(offset, word);
+                }
+                Fixup::Long => self.patch(offset + 2, address),
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Token, AssembleError> {
+        match self.tokens.pop_front() {
+            Some(token) => Ok(token),
+            None => self.end.error("unexpected end of source"),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AssembleError> {
+        let token = self.next()?;
+        if token.text != text {
+            return token.error(format!("expected '{}', found '{}'", text, token.text));
+        }
+        Ok(token)
+    }
+
+    fn address(&self) -> u16 {
+        (PROGRAM_START + self.here) as u16
+    }
+
+    fn emit(&mut self, byte: u8) {
+        if self.here >= self.rom.len() {
+            self.rom.resize(self.here + 1, 0);
+        }
+        self.rom[self.here] = byte;
+        self.here += 1;
+    }
+
+    fn emit_word(&mut self, word: u16) {
+        self.emit((word >> 8) as u8);
+        self.emit(word as u8);
+    }
+
+    fn emit_address(&mut self, opcode: u16, operand: Operand) {
+        match operand {
+            Operand::Value(address) => self.emit_word(opcode | address),
+            Operand::Label(token) => {
+                self.fixups.push((self.here, Fixup::Address, token));
+                self.emit_word(opcode);
+            }
+        }
+    }
+
+    fn word(&self, offset: usize) -> u16 {
+        (self.rom[offset] as u16) << 8 | self.rom[offset + 1] as u16
+    }
+
+    fn patch(&mut self, offset: usize, word: u16) {
+        self.rom[offset] = (word >> 8) as u8;
+        self.rom[offset + 1] = word as u8;
+    }
+
+    /// Emit a jump to be patched once the target is known and return its offset
+    fn emit_forward_jump(&mut self) -> usize {
+        let offset = self.here;
+        self.emit_word(0x1000);
+        offset
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        let target = self.address();
+        self.patch(offset, 0x1000 | target);
+    }
+
+    fn number(&self, token: &Token) -> Result<i32, AssembleError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.constants.get(&token.text) {
+            return Ok(value);
+        }
+        if let Some(&address) = self.labels.get(&token.text) {
+            return Ok(address as i32);
+        }
+        token.error(format!("expected a number, found '{}'", token.text))
+    }
+
+    fn ranged(&mut self, min: i32, max: i32, what: &str) -> Result<i32, AssembleError> {
+        let token = self.next()?;
+        let value = self.number(&token)?;
+        if value < min || value > max {
+            return token.error(format!("{} does not fit into {}", value, what));
+        }
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, AssembleError> {
+        self.ranged(-128, 255, "a byte").map(|value| value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssembleError> {
+        self.ranged(0, 15, "a nibble").map(|value| value as u8)
+    }
+
+    /// A 12 bit (or with `long` 16 bit) address, labels may be defined later
+    fn operand(&mut self, long: bool) -> Result<Operand, AssembleError> {
+        let token = self.next()?;
+        let max = if long { 0xFFFF } else { 0xFFF };
+        let value = match parse_number(&token.text) {
+            Some(value) => value,
+            None => match (
+                self.constants.get(&token.text),
+                self.labels.get(&token.text),
+            ) {
+                (Some(&value), _) => value,
+                (None, Some(&address)) => address as i32,
+                (None, None) => {
+                    if self.register(&token).is_some() || self.macros.contains_key(&token.text) {
+                        return token.error(format!("expected an address, found '{}'", token.text));
+                    }
+                    return Ok(Operand::Label(token));
+                }
+            },
+        };
+        if value < 0 || value > max {
+            return token.error(format!("address 0x{:X} is out of range", value));
+        }
+        Ok(Operand::Value(value as u16))
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        parse_register(&token.text).or_else(|| self.aliases.get(&token.text).cloned())
+    }
+
+    fn expect_register(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        match self.register(&token) {
+            Some(register) => Ok(register),
+            None => token.error(format!("expected a register, found '{}'", token.text)),
+        }
+    }
+
+    fn name(&mut self) -> Result<Token, AssembleError> {
+        let token = self.next()?;
+        let reserved = parse_number(&token.text).is_some()
+            || parse_register(&token.text).is_some()
+            || token.text.starts_with(':');
+        if reserved {
+            return token.error(format!("'{}' can't be used as a name", token.text));
+        }
+        Ok(token)
+    }
+
+    /// Parse `X OP Y` after `if` or `while`
+    /// Returns the opcode that skips the next instruction when the condition is false and
+    /// the one that skips it when the condition is true
+    fn condition(&mut self) -> Result<(u16, u16), AssembleError> {
+        let x = self.expect_register()? as u16;
+        let operator = self.next()?;
+        let (skip_if_false, skip_if_true) = match operator.text.as_str() {
+            "key" => (0xE0A1, 0xE09E),
+            "-key" => (0xE09E, 0xE0A1),
+            "==" | "!=" => {
+                let rhs = self.next()?;
+                let (equal, not_equal) = match self.register(&rhs) {
+                    Some(y) => (0x9000 | (y as u16) << 4, 0x5000 | (y as u16) << 4),
+                    None => {
+                        let value = self.number(&rhs)?;
+                        if !(-128..=255).contains(&value) {
+                            return rhs.error(format!("{} does not fit into a byte", value));
+                        }
+                        let value = value as u8 as u16;
+                        (0x4000 | value, 0x3000 | value)
+                    }
+                };
+                if operator.text == "==" {
+                    (equal, not_equal)
+                } else {
+                    (not_equal, equal)
+                }
+            }
+            _ => {
+                return operator.error(format!(
+                    "unsupported comparison '{}', use ==, !=, key or -key",
+                    operator.text
+                ))
+            }
+        };
+        Ok((skip_if_false | x << 8, skip_if_true | x << 8))
+    }
+
+    fn statement(&mut self, token: &Token) -> Result<(), AssembleError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+                    return name.error(format!("'{}' is already defined", name.text));
+                }
+                let address = self.address();
+                self.labels.insert(name.text, address);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.number(&value)?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.expect_register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":macro" => {
+                let name = self.name()?;
+                let mut arguments = Vec::new();
+                loop {
+                    let argument = self.next()?;
+                    if argument.text == "{" {
+                        break;
+                    }
+                    arguments.push(argument.text);
+                }
+                let mut body = Vec::new();
+                let mut depth = 1;
+                loop {
+                    let token = self.next()?;
+                    match token.text.as_str() {
+                        "{" => depth += 1,
+                        "}" if depth == 1 => break,
+                        "}" => depth -= 1,
+                        _ => {}
+                    }
+                    body.push(token);
+                }
+                self.macros.insert(name.text, Macro { arguments, body });
+            }
+            ":call" => {
+                let target = self.operand(false)?;
+                self.emit_address(0x2000, target);
+            }
+            ":byte" => {
+                let value = self.byte()?;
+                self.emit(value);
+            }
+            ":org" => {
+                let address = self.ranged(PROGRAM_START as i32, MAX_ADDRESS as i32, "memory")?;
+                self.here = address as usize - PROGRAM_START;
+            }
+            "clear" => self.emit_word(0x00E0),
+            "return" | ";" => self.emit_word(0x00EE),
+            "scroll-down" => {
+                let lines = self.nibble()?;
+                self.emit_word(0x00C0 | lines as u16);
+            }
+            "scroll-up" => {
+                let lines = self.nibble()?;
+                self.emit_word(0x00D0 | lines as u16);
+            }
+            "scroll-right" => self.emit_word(0x00FB),
+            "scroll-left" => self.emit_word(0x00FC),
+            "exit" => self.emit_word(0x00FD),
+            "lores" => self.emit_word(0x00FE),
+            "hires" => self.emit_word(0x00FF),
+            "jump" => {
+                let target = self.operand(false)?;
+                self.emit_address(0x1000, target);
+            }
+            "jump0" => {
+                let target = self.operand(false)?;
+                self.emit_address(0xB000, target);
+            }
+            "sprite" => {
+                let x = self.expect_register()? as u16;
+                let y = self.expect_register()? as u16;
+                let lines = self.nibble()? as u16;
+                self.emit_word(0xD000 | x << 8 | y << 4 | lines);
+            }
+            "save" | "load" => {
+                let x = self.expect_register()? as u16;
+                let range = self.tokens.front().is_some_and(|token| token.text == "-");
+                if range {
+                    self.next()?;
+                    let y = self.expect_register()? as u16;
+                    let low = if token.text == "save" { 2 } else { 3 };
+                    self.emit_word(0x5000 | x << 8 | y << 4 | low);
+                } else {
+                    let low = if token.text == "save" { 0x55 } else { 0x65 };
+                    self.emit_word(0xF000 | x << 8 | low);
+                }
+            }
+            "bcd" => {
+                let x = self.expect_register()? as u16;
+                self.emit_word(0xF033 | x << 8);
+            }
+            "saveflags" => {
+                let x = self.expect_register()? as u16;
+                self.emit_word(0xF075 | x << 8);
+            }
+            "loadflags" => {
+                let x = self.expect_register()? as u16;
+                self.emit_word(0xF085 | x << 8);
+            }
+            "plane" => {
+                let planes = self.nibble()? as u16;
+                self.emit_word(0xF001 | planes << 8);
+            }
+            "audio" => self.emit_word(0xF002),
+            "i" => {
+                let operator = self.next()?;
+                match operator.text.as_str() {
+                    ":=" => self.assign_index()?,
+                    "+=" => {
+                        let x = self.expect_register()? as u16;
+                        self.emit_word(0xF01E | x << 8);
+                    }
+                    _ => {
+                        return operator
+                            .error(format!("expected := or +=, found '{}'", operator.text))
+                    }
+                }
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                let low = match token.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.emit_word(0xF000 | x << 8 | low);
+            }
+            "if" => {
+                let (skip_if_false, skip_if_true) = self.condition()?;
+                let keyword = self.next()?;
+                match keyword.text.as_str() {
+                    "then" => self.emit_word(skip_if_false),
+                    "begin" => {
+                        self.emit_word(skip_if_true);
+                        let jump = self.emit_forward_jump();
+                        self.blocks.push((Block::If(jump), token.clone()));
+                    }
+                    _ => {
+                        return keyword
+                            .error(format!("expected then or begin, found '{}'", keyword.text))
+                    }
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((Block::If(jump), _)) => {
+                    let over_else = self.emit_forward_jump();
+                    self.patch_jump(jump);
+                    self.blocks.push((Block::Else(over_else), token.clone()));
+                }
+                _ => return token.error("else without if ... begin"),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If(jump), _)) | Some((Block::Else(jump), _)) => self.patch_jump(jump),
+                _ => return token.error("end without if ... begin"),
+            },
+            "loop" => {
+                let start = self.address();
+                self.blocks
+                    .push((Block::Loop(start, Vec::new()), token.clone()));
+            }
+            "while" => {
+                let (_, skip_if_true) = self.condition()?;
+                self.emit_word(skip_if_true);
+                let jump = self.emit_forward_jump();
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find_map(|block| match block.0 {
+                        Block::Loop(_, ref mut exits) => Some(exits),
+                        _ => None,
+                    }) {
+                    Some(exits) => exits.push(jump),
+                    None => return token.error("while outside of a loop"),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop(start, exits), _)) => {
+                    self.emit_word(0x1000 | start);
+                    for exit in exits {
+                        self.patch_jump(exit);
+                    }
+                }
+                _ => return token.error("again without loop"),
+            },
+            _ => {
+                if let Some(x) = self.register(token) {
+                    return self.assign_register(x as u16);
+                }
+                if parse_number(&token.text).is_some() || self.constants.contains_key(&token.text) {
+                    let value = self.number(token)?;
+                    if !(-128..=255).contains(&value) {
+                        return token.error(format!("{} does not fit into a byte", value));
+                    }
+                    self.emit(value as u8);
+                    return Ok(());
+                }
+                if self.macros.contains_key(&token.text) {
+                    return self.expand(token);
+                }
+                if token.text.starts_with(':') {
+                    return token.error(format!("unsupported directive '{}'", token.text));
+                }
+                // a bare label calls the subroutine
+                self.emit_address(0x2000, Operand::Label(token.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn assign_index(&mut self) -> Result<(), AssembleError> {
+        let next = self.tokens.front().map(|token| token.text.clone());
+        match next.as_deref() {
+            Some("hex") | Some("bighex") => {
+                let low = if self.next()?.text == "hex" {
+                    0x29
+                } else {
+                    0x30
+                };
+                let x = self.expect_register()? as u16;
+                self.emit_word(0xF000 | x << 8 | low);
+            }
+            Some("long") => {
+                self.next()?;
+                match self.operand(true)? {
+                    Operand::Value(address) => {
+                        self.emit_word(0xF000);
+                        self.emit_word(address);
+                    }
+                    Operand::Label(token) => {
+                        self.fixups.push((self.here, Fixup::Long, token));
+                        self.emit_word(0xF000);
+                        self.emit_word(0);
+                    }
+                }
+            }
+            _ => {
+                let address = self.operand(false)?;
+                self.emit_address(0xA000, address);
+            }
+        }
+        Ok(())
+    }
+
+    fn assign_register(&mut self, x: u16) -> Result<(), AssembleError> {
+        let operator = self.next()?;
+        let rhs = self.next()?;
+        let y = self.register(&rhs).map(|y| x << 8 | (y as u16) << 4);
+        let register_operation = match operator.text.as_str() {
+            ":=" => 0x0,
+            "|=" => 0x1,
+            "&=" => 0x2,
+            "^=" => 0x3,
+            "+=" => 0x4,
+            "-=" => 0x5,
+            ">>=" => 0x6,
+            "=-" => 0x7,
+            "<<=" => 0xE,
+            _ => return operator.error(format!("unknown operator '{}'", operator.text)),
+        };
+        if let Some(y) = y {
+            self.emit_word(0x8000 | y | register_operation);
+            return Ok(());
+        }
+
+        let immediate = |assembler: &Assembler| -> Result<u16, AssembleError> {
+            let value = assembler.number(&rhs)?;
+            if !(-128..=255).contains(&value) {
+                return rhs.error(format!("{} does not fit into a byte", value));
+            }
+            Ok(value as u8 as u16)
+        };
+        match (operator.text.as_str(), rhs.text.as_str()) {
+            (":=", "random") => {
+                let mask = self.byte()? as u16;
+                self.emit_word(0xC000 | x << 8 | mask);
+            }
+            (":=", "delay") => self.emit_word(0xF007 | x << 8),
+            (":=", "key") => self.emit_word(0xF00A | x << 8),
+            (":=", _) => {
+                let value = immediate(self)?;
+                self.emit_word(0x6000 | x << 8 | value);
+            }
+            ("+=", _) => {
+                let value = immediate(self)?;
+                self.emit_word(0x7000 | x << 8 | value);
+            }
+            ("-=", _) => {
+                let value = immediate(self)?;
+                self.emit_word(0x7000 | x << 8 | (0x100 - value) & 0xFF);
+            }
+            _ => {
+                return rhs.error(format!(
+                    "'{}' needs a register, found '{}'",
+                    operator.text, rhs.text
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the macro's arguments in its body and assemble that in place of the call
+    fn expand(&mut self, token: &Token) -> Result<(), AssembleError> {
+        let argument_count = self.macros[&token.text].arguments.len();
+        let mut values = Vec::with_capacity(argument_count);
+        for _ in 0..argument_count {
+            values.push(self.next()?);
+        }
+        let definition = &self.macros[&token.text];
+        self.expanded_tokens += definition.body.len();
+        if self.expanded_tokens > MAX_EXPANDED_TOKENS {
+            return token.error(format!("macro '{}' expands without end", token.text));
+        }
+        for body_token in definition.body.iter().rev() {
+            let replacement = definition
+                .arguments
+                .iter()
+                .position(|argument| *argument == body_token.text)
+                .map_or_else(|| body_token.clone(), |index| values[index].clone());
+            self.tokens.push_front(replacement);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::*;
+    use disasm;
+    use disasm::Syntax;
+
+    fn error_at(source: &str) -> (usize, usize, String) {
+        let err = assemble(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn test_statements() {
+        let source = "
+            # comments are ignored
+            clear
+            v1 := 0x0A  v2 += 1  v3 := v1  v3 += v2  v4 -= 1
+            i := 0x300  i += v1  i := hex v2  i := long 0x1234
+            sprite v1 v2 5
+            bcd v3  save v3  load v3  save v1 - v2
+            delay := v1  v5 := delay  v6 := key  v7 := random 0xFF
+            scroll-down 3  hires  plane 2  exit
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![
+                0x00, 0xE0, 0x61, 0x0A, 0x72, 0x01, 0x83, 0x10, 0x83, 0x24, 0x74, 0xFF, 0xA3, 0x00,
+                0xF1, 0x1E, 0xF2, 0x29, 0xF0, 0x00, 0x12, 0x34, 0xD1, 0x25, 0xF3, 0x33, 0xF3, 0x55,
+                0xF3, 0x65, 0x51, 0x22, 0xF1, 0x15, 0xF5, 0x07, 0xF6, 0x0A, 0xC7, 0xFF, 0x00, 0xC3,
+                0x00, 0xFF, 0xF2, 0x01, 0x00, 0xFD,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_main() {
+        let source = "
+            : draw
+                i := face
+                sprite v0 v0 2
+                return
+            : main
+                draw
+                jump main
+            : face
+                0b11000011 0x3C
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![
+                0x12, 0x08, // jump main
+                0xA2, 0x0C, 0xD0, 0x02, 0x00, 0xEE, // draw
+                0x22, 0x02, 0x12, 0x08, // main
+                0xC3, 0x3C, // face
+            ]
+        );
+        // without a main label execution starts at the top
+        assert_eq!(assemble("clear").unwrap(), vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn test_const_alias_and_macros() {
+        let source = "
+            :const SPEED 3
+            :alias x v4
+            :macro move register amount { register += amount }
+            move x SPEED
+            x := SPEED
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![0x74, 0x03, 0x64, 0x03]);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            if v0 == 5 then v1 := 1
+            if v0 != v1 then clear
+            if v2 key then clear
+            loop
+                v0 += 1
+                while v0 != 10
+            again
+            if v3 == 0 begin
+                v4 := 1
+            else
+                v4 := 2
+            end
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![
+                0x40, 0x05, 0x61, 0x01, // if ... then
+                0x50, 0x10, 0x00, 0xE0, // registers
+                0xE2, 0xA1, 0x00, 0xE0, // key
+                0x70, 0x01, 0x40, 0x0A, 0x12, 0x14, 0x12, 0x0C, // loop
+                0x33, 0x00, 0x12, 0x1C, 0x64, 0x01, 0x12, 0x1E, 0x64, 0x02, // begin/else/end
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            error_at("clear\n  v1 := 256"),
+            (2, 9, "256 does not fit into a byte".to_string())
+        );
+        assert_eq!(
+            error_at("jump nowhere"),
+            (1, 6, "undefined label 'nowhere'".to_string())
+        );
+        assert_eq!(
+            error_at("sprite v0 vg 1"),
+            (1, 11, "expected a register, found 'vg'".to_string())
+        );
+        assert_eq!(
+            error_at("loop\nclear"),
+            (1, 1, "'loop' is never closed".to_string())
+        );
+        assert_eq!(
+            error_at("i :="),
+            (1, 3, "unexpected end of source".to_string())
+        );
+        assert_eq!(
+            error_at(": a\n: a"),
+            (2, 3, "'a' is already defined".to_string())
+        );
+        assert_eq!(
+            error_at("if v0 < 3 then"),
+            (
+                1,
+                7,
+                "unsupported comparison '<', use ==, !=, key or -key".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let rom = include_bytes!("../roms/BC_test.ch8");
+        let listing = disasm::disassemble(rom, Syntax::Octo);
+        assert_eq!(assemble(&listing).unwrap(), rom.to_vec());
+    }
+}
@@ -0,0 +1,303 @@
+use std::io;
+use std::io::prelude::*;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+/// One second of audio at the default sample rate
+pub const DEFAULT_BUFFER_SIZE: usize = DEFAULT_SAMPLE_RATE as usize;
+/// Pitch register value after reset, plays the pattern at 4000 bits per second
+pub const DEFAULT_PITCH: u8 = 64;
+/// Size of the XO-CHIP audio pattern in bytes, 128 one bit samples
+pub const PATTERN_SIZE: usize = 16;
+
+/// Frequency of the beep for programs that never load an audio pattern
+const BEEP_FREQUENCY: f64 = 440.0;
+/// Absorbs rounding errors when a duration is an exact number of samples
+const SAMPLE_EPSILON: f64 = 1e-6;
+/// Keeps the square wave from clipping when mixed by the backend
+const VOLUME: f32 = 0.25;
+
+/// Everything the cpu exposes to produce sound for one frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sound {
+    /// True while the sound timer is running
+    pub playing: bool,
+    /// Pattern loaded by F002, `None` until a program loads one
+    pub pattern: Option<[u8; PATTERN_SIZE]>,
+    /// FX3A pitch register
+    pub pitch: u8,
+}
+
+impl Sound {
+    /// Rate at which the bits of the pattern are played
+    /// 4000 * 2 ^ ((pitch - 64) / 48) bits per second
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+}
+
+/// Fixed capacity FIFO of samples, the oldest samples are dropped when it overflows
+pub struct RingBuffer {
+    samples: Vec<f32>,
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer {
+            samples: vec![0.0; capacity],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return;
+        }
+        let end = (self.start + self.len) % capacity;
+        self.samples[end] = sample;
+        if self.len == capacity {
+            self.start = (self.start + 1) % capacity;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Move up to `out.len()` samples into `out`, returns the number of samples written
+    pub fn pull(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.len);
+        for sample in out.iter_mut().take(count) {
+            *sample = self.samples[self.start];
+            self.start = (self.start + 1) % self.capacity();
+        }
+        self.len -= count;
+        count
+    }
+}
+
+/// Turns the sound state of the cpu into PCM samples
+/// Without a pattern a square wave beep plays while the sound timer runs,
+/// with a pattern its 128 bits are looped at the rate given by the pitch register
+pub struct AudioGenerator {
+    sample_rate: u32,
+    // position in the current waveform, in periods for the beep and in bits for patterns
+    phase: f64,
+    // fractional samples left over from the previous call to `render`
+    pending_samples: f64,
+    buffer: RingBuffer,
+}
+
+impl Default for AudioGenerator {
+    fn default() -> AudioGenerator {
+        AudioGenerator::new(DEFAULT_SAMPLE_RATE, DEFAULT_BUFFER_SIZE)
+    }
+}
+
+impl AudioGenerator {
+    pub fn new(sample_rate: u32, buffer_size: usize) -> AudioGenerator {
+        AudioGenerator {
+            sample_rate,
+            phase: 0.0,
+            pending_samples: 0.0,
+            buffer: RingBuffer::new(buffer_size),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Render `seconds` worth of samples for `sound` into the ring buffer
+    pub fn render(&mut self, sound: &Sound, seconds: f64) {
+        self.pending_samples += seconds * self.sample_rate as f64;
+        let count = (self.pending_samples + SAMPLE_EPSILON).floor();
+        self.pending_samples -= count;
+
+        for _ in 0..count as usize {
+            let sample = self.next_sample(sound);
+            self.buffer.push(sample);
+        }
+    }
+
+    /// Move rendered samples into `out`, returns the number of samples written
+    pub fn pull(&mut self, out: &mut [f32]) -> usize {
+        self.buffer.pull(out)
+    }
+
+    /// Number of rendered samples waiting to be pulled
+    pub fn available(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn next_sample(&mut self, sound: &Sound) -> f32 {
+        if !sound.playing {
+            // restart the waveform with the next sound
+            self.phase = 0.0;
+            return 0.0;
+        }
+
+        let sample_rate = self.sample_rate as f64;
+        let high = match sound.pattern {
+            Some(ref pattern) => {
+                let bit = self.phase as usize % (PATTERN_SIZE * 8);
+                self.phase =
+                    (self.phase + sound.playback_rate() / sample_rate) % (PATTERN_SIZE * 8) as f64;
+                (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1
+            }
+            None => {
+                let high = self.phase < 0.5;
+                self.phase = (self.phase + BEEP_FREQUENCY / sample_rate) % 1.0;
+                high
+            }
+        };
+
+        if high {
+            VOLUME
+        } else {
+            -VOLUME
+        }
+    }
+}
+
+/// Write mono samples as a 16 bit PCM wave file
+pub fn write_wav<W: Write>(writer: &mut W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&u32_le(36 + data_size))?;
+    writer.write_all(b"WAVE")?;
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&u32_le(16))?;
+    // PCM, one channel
+    writer.write_all(&u16_le(1))?;
+    writer.write_all(&u16_le(1))?;
+    writer.write_all(&u32_le(sample_rate))?;
+    // byte rate and block alignment
+    writer.write_all(&u32_le(sample_rate * 2))?;
+    writer.write_all(&u16_le(2))?;
+    writer.write_all(&u16_le(16))?;
+    writer.write_all(b"data")?;
+    writer.write_all(&u32_le(data_size))?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_all(&u16_le(value as u16))?;
+    }
+    Ok(())
+}
+
+fn u16_le(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn u32_le(value: u32) -> [u8; 4] {
+    [
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use audio::*;
+
+    #[test]
+    fn test_ring_buffer_drops_oldest() {
+        let mut buffer = RingBuffer::new(3);
+        for sample in 0..5 {
+            buffer.push(sample as f32);
+        }
+        let mut out = [0.0; 4];
+        assert_eq!(buffer.pull(&mut out), 3);
+        assert_eq!(out[..3], [2.0, 3.0, 4.0]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_silence_without_sound() {
+        let mut generator = AudioGenerator::new(8000, 8000);
+        let sound = Sound {
+            playing: false,
+            pattern: None,
+            pitch: DEFAULT_PITCH,
+        };
+        generator.render(&sound, 0.5);
+        let mut out = [1.0; 4000];
+        assert_eq!(generator.pull(&mut out), 4000);
+        assert!(out.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn test_square_beep() {
+        let mut generator = AudioGenerator::new(7040, 7040);
+        let sound = Sound {
+            playing: true,
+            pattern: None,
+            pitch: DEFAULT_PITCH,
+        };
+        // 16 samples per period at 440 Hz
+        generator.render(&sound, 16.0 / 7040.0);
+        let mut out = [0.0; 16];
+        assert_eq!(generator.pull(&mut out), 16);
+        assert!(out[..8].iter().all(|&sample| sample > 0.0));
+        assert!(out[8..].iter().all(|&sample| sample < 0.0));
+    }
+
+    #[test]
+    fn test_pattern_playback() {
+        // at the default pitch and a 4000 Hz sample rate every sample is one bit
+        let mut generator = AudioGenerator::new(4000, 4000);
+        let mut pattern = [0; PATTERN_SIZE];
+        pattern[0] = 0b1010_0000;
+        let sound = Sound {
+            playing: true,
+            pattern: Some(pattern),
+            pitch: DEFAULT_PITCH,
+        };
+        generator.render(&sound, 130.0 / 4000.0);
+        let mut out = [0.0; 130];
+        assert_eq!(generator.pull(&mut out), 130);
+        assert!(out[0] > 0.0);
+        assert!(out[1] < 0.0);
+        assert!(out[2] > 0.0);
+        assert!(out[3] < 0.0);
+        // the pattern loops after 128 bits
+        assert!(out[128] > 0.0);
+        assert!(out[129] < 0.0);
+    }
+
+    #[test]
+    fn test_pitch() {
+        let sound = Sound {
+            playing: true,
+            pattern: None,
+            pitch: 64 + 48,
+        };
+        assert_eq!(sound.playback_rate(), 8000.0);
+    }
+
+    #[test]
+    fn test_write_wav() {
+        let mut out = Vec::new();
+        write_wav(&mut out, 8000, &[0.0, 1.0, -1.0]).unwrap();
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[8..12], b"WAVE");
+        assert_eq!(out[24..28], [0x40, 0x1F, 0, 0]);
+        assert_eq!(out[44..50], [0, 0, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
@@ -1,89 +1,277 @@
 use cpu::Cpu;
+use error::LoadError;
+use framebuffer::{Framebuffer, LORES_HEIGHT, LORES_WIDTH};
+use frontend::piston::{PistonDisplay, PistonKeypad};
+use frontend::text;
+use frontend::{Display, NullAudio, Palette};
+use keymap::Keymap;
+use machine::Machine;
+use rewind::DEFAULT_REWIND_MEMORY;
+use savestate;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
 
 use piston_window::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+/// Window pixels per chip 8 pixel in low resolution
+pub const DEFAULT_SCALE: u32 = 10;
+/// F1 to F9 load the save slots 1 to 9, with shift held they save
+const SLOT_KEYS: [Key; 9] = [
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+];
+
+/// Piston window frontend around a `Machine`
 pub struct Chip8 {
-    cpu: Cpu,
+    machine: Machine,
     window: PistonWindow,
+    display: PistonDisplay,
+    keypad: PistonKeypad,
+    audio: NullAudio,
+    // save slots are stored next to the rom
+    rom_path: Option<PathBuf>,
+    shift_held: bool,
+    rewinding: bool,
+    // the keymap drawn over the screen, toggled with tab
+    keymap_overlay: Option<Framebuffer>,
+    // window size in pixels
+    width: u32,
+    height: u32,
+}
+
+impl Default for Chip8 {
+    fn default() -> Chip8 {
+        Chip8::new()
+    }
 }
 
 impl Chip8 {
     pub fn new() -> Chip8 {
+        Chip8::with_cpu(Cpu::default().init())
+    }
+
+    /// Run an already configured cpu, e.g. with quirks or extended memory
+    pub fn with_cpu(cpu: Cpu) -> Chip8 {
+        Chip8::with_machine(Machine::new(cpu).with_rewind(DEFAULT_REWIND_MEMORY))
+    }
+
+    /// Run an already configured machine, e.g. with a different rewind memory limit
+    pub fn with_machine(machine: Machine) -> Chip8 {
         Chip8 {
-            cpu: Cpu::default().init(),
-            window: WindowSettings::new("Chip 8 Interpreter", [640, 320])
-                .exit_on_esc(true)
-                .build()
-                .unwrap(),
+            machine,
+            window: WindowSettings::new(
+                "Chip 8 Interpreter",
+                [
+                    LORES_WIDTH as u32 * DEFAULT_SCALE,
+                    LORES_HEIGHT as u32 * DEFAULT_SCALE,
+                ],
+            )
+            .exit_on_esc(true)
+            .build()
+            .unwrap(),
+            display: PistonDisplay::default(),
+            keypad: PistonKeypad::default(),
+            audio: NullAudio,
+            rom_path: None,
+            shift_held: false,
+            rewinding: false,
+            keymap_overlay: None,
+            width: LORES_WIDTH as u32 * DEFAULT_SCALE,
+            height: LORES_HEIGHT as u32 * DEFAULT_SCALE,
         }
     }
 
-    pub fn load_rom(&mut self, path: &String) {
-        let file = File::open(path).unwrap();
-        let bytes = file.bytes();
+    /// Draw every low resolution pixel as a `scale` x `scale` square, high resolution pixels are half as large
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.width = LORES_WIDTH as u32 * scale;
+        self.height = LORES_HEIGHT as u32 * scale;
+        self.window.set_size([self.width, self.height]);
+        self
+    }
+
+    pub fn with_palette(mut self, palette: Palette) -> Self {
+        self.display.set_palette(palette);
+        self
+    }
 
-        info!("Read rom from: {}", path);
-        self.cpu.load_bytes(bytes);
+    /// Map host keys to the hex keypad with `keymap` instead of the default QWERTY layout
+    pub fn with_keymap(mut self, keymap: Keymap) -> Self {
+        self.keypad = PistonKeypad::new(keymap);
+        self
+    }
+
+    pub fn load_rom(&mut self, path: &str) -> Result<(), LoadError> {
+        self.machine.load_rom(path)?;
+        self.rom_path = Some(PathBuf::from(path));
+        Ok(())
     }
 
     pub fn run(&mut self) {
         while let Some(e) = self.window.next() {
+            self.keypad.handle_event(&e);
+            self.handle_slot_keys(&e);
+            self.handle_debug_keys(&e);
+
+            // held down to step backwards in time
+            if let Some(Button::Keyboard(Key::Backspace)) = e.press_args() {
+                self.rewinding = true;
+                self.window
+                    .set_title("Chip 8 Interpreter - rewinding".to_string());
+            }
+            if let Some(Button::Keyboard(Key::Backspace)) = e.release_args() {
+                self.rewinding = false;
+                self.window.set_title("Chip 8 Interpreter".to_string());
+            }
+
             if let Some(u) = e.update_args() {
-                self.cpu.cycle(u.dt);
+                if self.rewinding {
+                    self.machine.rewind(u.dt);
+                    self.display.present(self.machine.framebuffer());
+                } else if !self.machine.cpu().is_halted() {
+                    let result = self.machine.update_with(
+                        u.dt,
+                        &mut self.display,
+                        &mut self.keypad,
+                        &mut self.audio,
+                    );
+                    if let Err(err) = result {
+                        // keep the last frame on screen so the state can be inspected
+                        self.window
+                            .set_title(format!("Chip 8 Interpreter - halted: {}", err));
+                    }
+                }
+            }
+
+            if self.machine.cpu().has_exited() {
+                info!("Program exited");
+                break;
+            }
+
+            if let Some(Button::Keyboard(Key::Tab)) = e.press_args() {
+                self.keymap_overlay = match self.keymap_overlay {
+                    Some(_) => None,
+                    None => Some(text::render(&self.keypad.keymap().overlay())),
+                };
             }
 
-            let should_draw = self.cpu.needs_redraw();
-            let mem = self.cpu.gfx;
+            let display = &self.display;
+            let overlay = &self.keymap_overlay;
+            let (width, height) = (self.width as usize, self.height as usize);
             self.window.draw_2d(&e, |c, g| {
-                clear(color::BLACK, g);
-                let size = 10;
-                if should_draw {
-                    for y in 0..32 {
-                        for x in 0..64 {
-                            if mem[(x + (y * 64)) as usize] & 0x01 == 1 {
-                                let d = [
-                                    (x * size) as f64,
-                                    (y * size) as f64,
-                                    size as f64,
-                                    size as f64,
-                                ];
-                                Rectangle::new(color::WHITE).draw(d, &c.draw_state, c.transform, g);
-                            }
-                        }
-                    }
+                display.draw(width, &c, g);
+                if let Some(ref overlay) = *overlay {
+                    display.draw_overlay(overlay, width, height, &c, g);
                 }
             });
 
-            if let Some(Button::Keyboard(key)) = e.press_args() {
-                if key == Key::Escape {
-                    self.dump_memory();
+            if let Some(Button::Keyboard(Key::Escape)) = e.press_args() {
+                self.dump_memory();
+            }
+        }
+    }
+
+    fn handle_slot_keys(&mut self, e: &Event) {
+        match e.press_args() {
+            Some(Button::Keyboard(Key::LShift)) | Some(Button::Keyboard(Key::RShift)) => {
+                self.shift_held = true
+            }
+            Some(Button::Keyboard(key)) => {
+                if let Some(index) = SLOT_KEYS.iter().position(|&slot_key| slot_key == key) {
+                    let slot = index as u8 + 1;
+                    if self.shift_held {
+                        self.save_slot(slot);
+                    } else {
+                        self.load_slot(slot);
+                    }
                 }
-                // A - F
-                if key.code() >= 0x61 && key.code() <= 0x66 {
-                    let key_in_map = (key.code() - 87) as u8;
-                    self.cpu.handle_key_press(key_in_map);
-                } else if key.code() >= 0x30 && key.code() <= 0x39 {
-                    // 0 - 9
-                    let key_in_map = (key.code() - 48) as u8;
-                    self.cpu.handle_key_press(key_in_map);
+            }
+            _ => {}
+        }
+        if let Some(Button::Keyboard(Key::LShift)) | Some(Button::Keyboard(Key::RShift)) =
+            e.release_args()
+        {
+            self.shift_held = false;
+        }
+    }
+
+    // P pauses and continues, N steps a single instruction while paused
+    fn handle_debug_keys(&mut self, e: &Event) {
+        match e.press_args() {
+            Some(Button::Keyboard(Key::P)) => {
+                if self.machine.debugger().is_paused() {
+                    self.machine.resume();
+                    self.window.set_title("Chip 8 Interpreter".to_string());
+                    return;
                 }
+                self.machine.pause();
             }
-            if let Some(Button::Keyboard(key)) = e.release_args() {
-                // A - F
-                if key.code() >= 0x61 && key.code() <= 0x66 {
-                    let key_in_map = (key.code() - 87) as u8;
-                    self.cpu.handle_key_release(key_in_map);
-                } else if key.code() >= 0x30 && key.code() <= 0x39 {
-                    // 0 - 9
-                    let key_in_map = (key.code() - 48) as u8;
-                    self.cpu.handle_key_release(key_in_map);
+            Some(Button::Keyboard(Key::N)) if self.machine.debugger().is_paused() => {
+                // a halt shows up in the title like any other stop, movies can't replay steps
+                if let Ok(None) = self.machine.step() {
+                    info!("stepping is disabled while recording a movie");
                 }
+                self.display.present(self.machine.framebuffer());
             }
+            _ => return,
         }
+
+        let cpu = self.machine.cpu();
+        let title = match cpu.halted() {
+            Some(err) => format!("Chip 8 Interpreter - halted: {}", err),
+            None => format!(
+                "Chip 8 Interpreter - paused at 0x{:03X}: {:04X}",
+                cpu.pc(),
+                cpu.opcode_at(cpu.pc()).unwrap_or(0)
+            ),
+        };
+        self.window.set_title(title);
+    }
+
+    fn slot_path(&self, slot: u8) -> PathBuf {
+        let rom_path = self
+            .rom_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("chip8"));
+        savestate::slot_path(&rom_path, slot)
+    }
+
+    /// Save the machine state into save slot `slot`
+    pub fn save_slot(&mut self, slot: u8) {
+        let path = self.slot_path(slot);
+        let title = match self.machine.save_state(&path) {
+            Ok(()) => format!("Chip 8 Interpreter - saved slot {}", slot),
+            Err(err) => {
+                error!("{}: {}", path.display(), err);
+                format!("Chip 8 Interpreter - slot {}: {}", slot, err)
+            }
+        };
+        self.window.set_title(title);
+    }
+
+    /// Restore the machine state from save slot `slot`
+    pub fn load_slot(&mut self, slot: u8) {
+        let path = self.slot_path(slot);
+        let title = match self.machine.load_state(&path) {
+            Ok(()) => format!("Chip 8 Interpreter - loaded slot {}", slot),
+            Err(err) => {
+                error!("{}: {}", path.display(), err);
+                format!("Chip 8 Interpreter - slot {}: {}", slot, err)
+            }
+        };
+        self.window.set_title(title);
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
     }
 
     pub fn dump_memory(&self) {
@@ -93,7 +281,7 @@ impl Chip8 {
         }
 
         let mut file = File::create("chip8.memdump").unwrap();
-        file.write_all(self.cpu.dump_memory())
+        file.write_all(self.machine.cpu().dump_memory())
             .expect("Failed to write memdump");
     }
 }
@@ -0,0 +1,580 @@
+use chip8::cpu::{Cpu, KeyWaitMode, EXTENDED_MEMORY_SIZE, MEMORY_SIZE};
+use chip8::disasm::Syntax;
+use chip8::frontend::Palette;
+use chip8::random::{VipRandom, VIP_INTERPRETER_SIZE};
+use chip8::Quirks;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const USAGE: &str = "\
+usage: chip8 [run] [options] <rom>
+       chip8 info [options] <rom>
+       chip8 test [options] [--frames <n>] [--expect <screen> [--update]] <rom>
+       chip8 disasm [--octo] [--labels | --dot] <rom>
+       chip8 asm <source> [-o <rom>]
+
+options for run, info and test:
+  --quirks <preset>     vip, chip48, schip or xochip, the default keeps the classic behaviour
+  --ipf <n>             instructions per frame, 10 by default
+  --start <address>     load and start the rom here instead of 0x200
+  --seed <n>            seed for the random numbers of CXNN, random by default
+  --vip-random <dump>   random numbers like the COSMAC VIP interpreter in <dump>
+  --log <level>         error, warn, info, debug or trace, overrides RUST_LOG
+
+options for run:
+  --scale <n>           window pixels per chip 8 pixel, 10 by default
+  --palette <colours>   2 to 4 RRGGBB colours, e.g. 000000,33FF66
+  --keymap <file>       key bindings, see the readme
+  --headless            run without a window, then print the screen
+  --frames <n>          frames to run headless, 600 by default
+  --tui                 run in the terminal
+  --debug               start the debugger prompt
+  --gdb <port>          wait for gdb on 127.0.0.1:<port>
+  --record <movie>      record the key input into a movie
+  --play <movie>        replay a movie headlessly and check the final state
+";
+
+/// Frames `chip8 test` runs when `--frames` is left out, enough for the bundled test roms
+const DEFAULT_TEST_FRAMES: u64 = 120;
+const DEFAULT_HEADLESS_FRAMES: u64 = 600;
+const DEFAULT_SCALE: u32 = 10;
+
+/// Parsed command line
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    /// `--log`, a level or `env_logger` filter
+    pub log: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(RunOptions),
+    Info(MachineOptions),
+    Test(TestOptions),
+    Disasm { rom: String, output: Disassembly },
+    Asm { source: String, output: PathBuf },
+    Help,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Disassembly {
+    Linear(Syntax),
+    Labelled(Syntax),
+    Dot,
+}
+
+/// Quirks and memory layout of a platform
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    Default,
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    XoChip,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Preset, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "default" => Ok(Preset::Default),
+            "vip" | "cosmac-vip" | "chip8" => Ok(Preset::CosmacVip),
+            "chip48" => Ok(Preset::Chip48),
+            "schip" | "superchip" | "super-chip" => Ok(Preset::SuperChip),
+            "xochip" | "xo-chip" => Ok(Preset::XoChip),
+            _ => Err(format!(
+                "unknown quirks preset '{}', expected vip, chip48, schip or xochip",
+                name
+            )),
+        }
+    }
+}
+
+/// How the cpu is set up and which rom it runs, shared by run, info and test
+#[derive(Debug, PartialEq)]
+pub struct MachineOptions {
+    pub rom: String,
+    pub preset: Preset,
+    pub instructions_per_frame: Option<u32>,
+    pub start: Option<u16>,
+    pub seed: Option<u64>,
+    pub vip_random: Option<PathBuf>,
+}
+
+impl MachineOptions {
+    /// A cpu configured by the options, without the rom
+    pub fn cpu(&self) -> Result<Cpu, String> {
+        let mut cpu = Cpu::default();
+        cpu = match self.preset {
+            Preset::Default => cpu,
+            Preset::CosmacVip => cpu.with_quirks(Quirks::cosmac_vip()),
+            Preset::Chip48 => cpu.with_quirks(Quirks::chip48()),
+            Preset::SuperChip => cpu
+                .with_quirks(Quirks::super_chip())
+                .with_key_wait_mode(KeyWaitMode::Press),
+            Preset::XoChip => cpu
+                .with_quirks(Quirks::xo_chip())
+                .with_key_wait_mode(KeyWaitMode::Press)
+                .with_extended_memory(),
+        };
+        if let Some(instructions) = self.instructions_per_frame {
+            cpu = cpu.with_instructions_per_frame(instructions);
+        }
+        if let Some(start) = self.start {
+            cpu = cpu.with_start_address(start);
+        }
+        if let Some(seed) = self.seed {
+            cpu = cpu.with_seed(seed);
+        }
+        if let Some(ref path) = self.vip_random {
+            let dump = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            let source = VipRandom::from_interpreter(&dump).ok_or_else(|| {
+                format!(
+                    "{}: expected a dump of the {} byte VIP interpreter",
+                    path.display(),
+                    VIP_INTERPRETER_SIZE
+                )
+            })?;
+            cpu = cpu.with_random_source(source);
+        }
+        Ok(cpu.init())
+    }
+
+    /// The rom file, with a hint about the roms next to it when it doesn't exist
+    pub fn read_rom(&self) -> Result<Vec<u8>, String> {
+        read_rom(&self.rom)
+    }
+}
+
+/// Read the rom at `path`, with a hint about the roms next to it when it doesn't exist
+pub fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    match fs::read(path) {
+        Ok(rom) => Ok(rom),
+        Err(_) if !Path::new(path).exists() => {
+            let mut message = format!("{}: no such rom", path);
+            let directory = match Path::new(path).parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            let roms = roms_in(&directory);
+            if !roms.is_empty() {
+                message.push_str(&format!(
+                    ", roms in {}: {}",
+                    directory.display(),
+                    roms.join(", ")
+                ));
+            }
+            Err(message)
+        }
+        Err(err) => Err(format!("{}: {}", path, err)),
+    }
+}
+
+/// File names in `directory` with a rom extension, sorted
+fn roms_in(directory: &Path) -> Vec<String> {
+    let mut roms: Vec<String> = fs::read_dir(directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| {
+                    let extension = Path::new(name).extension().and_then(|e| e.to_str());
+                    matches!(
+                        extension,
+                        Some("ch8") | Some("c8") | Some("sc8") | Some("xo8")
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    roms.sort();
+    roms
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Frontend {
+    Window,
+    Terminal,
+    Headless { frames: u64 },
+    Debugger,
+    Gdb(u16),
+    Replay(PathBuf),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RunOptions {
+    pub machine: MachineOptions,
+    pub frontend: Frontend,
+    pub scale: u32,
+    pub palette: Palette,
+    pub keymap: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TestOptions {
+    pub machine: MachineOptions,
+    pub frames: u64,
+    /// Screen to compare with, as written by `golden::render`
+    pub expect: Option<PathBuf>,
+    /// Write the screen to `expect` instead of comparing
+    pub update: bool,
+}
+
+/// Parse the arguments after the program name
+pub fn parse(args: &[String]) -> Result<Cli, String> {
+    let mut args = args.to_vec();
+    let log = take_value(&mut args, "--log")?;
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        return Ok(Cli {
+            command: Command::Help,
+            log,
+        });
+    }
+
+    let command = match args.first().map(String::as_str) {
+        None => return Err("no rom given".to_string()),
+        Some("help") => Command::Help,
+        Some("run") => Command::Run(parse_run(args.split_off(1))?),
+        Some("info") => {
+            let mut args = args.split_off(1);
+            let machine = parse_machine(&mut args)?;
+            Command::Info(machine)
+        }
+        Some("test") => Command::Test(parse_test(args.split_off(1))?),
+        Some("disasm") => parse_disasm(args.split_off(1))?,
+        Some("asm") => parse_asm(args.split_off(1))?,
+        // `chip8 <rom>` runs the rom
+        Some(_) => Command::Run(parse_run(args)?),
+    };
+    Ok(Cli { command, log })
+}
+
+fn parse_run(mut args: Vec<String>) -> Result<RunOptions, String> {
+    let scale = match take_value(&mut args, "--scale")? {
+        Some(scale) => positive("--scale", &scale)? as u32,
+        None => DEFAULT_SCALE,
+    };
+    let palette = match take_value(&mut args, "--palette")? {
+        Some(palette) => Palette::parse(&palette)
+            .ok_or_else(|| format!("--palette expects 2 to 4 RRGGBB colours, got '{}'", palette))?,
+        None => Palette::default(),
+    };
+    let keymap = take_value(&mut args, "--keymap")?.map(PathBuf::from);
+    let record = take_value(&mut args, "--record")?.map(PathBuf::from);
+    let frames = take_value(&mut args, "--frames")?
+        .map(|frames| positive("--frames", &frames))
+        .transpose()?;
+
+    let mut frontends = Vec::new();
+    if take_flag(&mut args, "--headless") {
+        frontends.push(Frontend::Headless {
+            frames: frames.unwrap_or(DEFAULT_HEADLESS_FRAMES),
+        });
+    }
+    if take_flag(&mut args, "--tui") {
+        frontends.push(Frontend::Terminal);
+    }
+    if take_flag(&mut args, "--debug") {
+        frontends.push(Frontend::Debugger);
+    }
+    if let Some(port) = take_value(&mut args, "--gdb")? {
+        let port = port
+            .parse()
+            .map_err(|_| format!("--gdb expects a port number, got '{}'", port))?;
+        frontends.push(Frontend::Gdb(port));
+    }
+    if let Some(movie) = take_value(&mut args, "--play")? {
+        frontends.push(Frontend::Replay(PathBuf::from(movie)));
+    }
+    if frontends.len() > 1 {
+        return Err("choose one of --headless, --tui, --debug, --gdb and --play".to_string());
+    }
+    let frontend = frontends.pop().unwrap_or(Frontend::Window);
+
+    if frames.is_some() && !matches!(frontend, Frontend::Headless { .. }) {
+        return Err("--frames only works with --headless".to_string());
+    }
+    if record.is_some() && !matches!(frontend, Frontend::Window | Frontend::Terminal) {
+        return Err("--record needs the window or --tui to read keys from".to_string());
+    }
+
+    let machine = parse_machine(&mut args)?;
+    Ok(RunOptions {
+        machine,
+        frontend,
+        scale,
+        palette,
+        keymap,
+        record,
+    })
+}
+
+fn parse_test(mut args: Vec<String>) -> Result<TestOptions, String> {
+    let frames = match take_value(&mut args, "--frames")? {
+        Some(frames) => positive("--frames", &frames)?,
+        None => DEFAULT_TEST_FRAMES,
+    };
+    let expect = take_value(&mut args, "--expect")?.map(PathBuf::from);
+    let update = take_flag(&mut args, "--update");
+    if update && expect.is_none() {
+        return Err("--update needs --expect <screen> to write to".to_string());
+    }
+    let machine = parse_machine(&mut args)?;
+    Ok(TestOptions {
+        machine,
+        frames,
+        expect,
+        update,
+    })
+}
+
+/// The options shared by run, info and test, followed by the rom
+fn parse_machine(args: &mut Vec<String>) -> Result<MachineOptions, String> {
+    let preset = match take_value(args, "--quirks")? {
+        Some(preset) => preset.parse()?,
+        None => Preset::Default,
+    };
+    let instructions_per_frame = take_value(args, "--ipf")?
+        .map(|ipf| positive("--ipf", &ipf).map(|ipf| ipf as u32))
+        .transpose()?;
+    let start = match take_value(args, "--start")? {
+        Some(start) => {
+            let address = number("--start", &start)?;
+            let limit = if preset == Preset::XoChip {
+                EXTENDED_MEMORY_SIZE
+            } else {
+                MEMORY_SIZE
+            };
+            if address >= limit as u64 {
+                return Err(format!("--start {} is outside of memory", start));
+            }
+            Some(address as u16)
+        }
+        None => None,
+    };
+    let seed = take_value(args, "--seed")?
+        .map(|seed| number("--seed", &seed))
+        .transpose()?;
+    let vip_random = take_value(args, "--vip-random")?.map(PathBuf::from);
+    if seed.is_some() && vip_random.is_some() {
+        return Err("--seed has no effect with --vip-random, choose one".to_string());
+    }
+    let rom = single_file(args, "rom")?;
+    Ok(MachineOptions {
+        rom,
+        preset,
+        instructions_per_frame,
+        start,
+        seed,
+        vip_random,
+    })
+}
+
+fn parse_disasm(mut args: Vec<String>) -> Result<Command, String> {
+    let syntax = if take_flag(&mut args, "--octo") {
+        Syntax::Octo
+    } else {
+        Syntax::Cowgod
+    };
+    let labels = take_flag(&mut args, "--labels");
+    let dot = take_flag(&mut args, "--dot");
+    let output = match (labels, dot) {
+        (true, true) => return Err("choose one of --labels and --dot".to_string()),
+        (false, true) => Disassembly::Dot,
+        (true, false) => Disassembly::Labelled(syntax),
+        (false, false) => Disassembly::Linear(syntax),
+    };
+    let rom = single_file(&mut args, "rom")?;
+    Ok(Command::Disasm { rom, output })
+}
+
+fn parse_asm(mut args: Vec<String>) -> Result<Command, String> {
+    let output = take_value(&mut args, "-o")?.map(PathBuf::from);
+    let source = single_file(&mut args, "source file")?;
+    let output = output.unwrap_or_else(|| Path::new(&source).with_extension("ch8"));
+    Ok(Command::Asm { source, output })
+}
+
+/// The one argument left after the options were taken out
+fn single_file(args: &mut Vec<String>, what: &str) -> Result<String, String> {
+    if let Some(option) = args
+        .iter()
+        .find(|arg| arg.starts_with('-') && arg.len() > 1)
+    {
+        return Err(format!("unknown option {}", option));
+    }
+    match args.len() {
+        0 => Err(format!("no {} given", what)),
+        1 => Ok(args.remove(0)),
+        _ => Err(format!("expected one {}, got {}", what, args.join(" "))),
+    }
+}
+
+/// Remove `flag` from `args`, true if it was there
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != before
+}
+
+/// Remove `flag` and the value after it from `args`
+fn take_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, String> {
+    let index = match args.iter().position(|arg| arg == flag) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    if index + 1 >= args.len() {
+        return Err(format!("{} needs a value", flag));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(value))
+}
+
+/// A decimal or 0x prefixed hexadecimal number
+fn number(flag: &str, value: &str) -> Result<u64, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("{} expects a number, got '{}'", flag, value))
+}
+
+fn positive(flag: &str, value: &str) -> Result<u64, String> {
+    match number(flag, value)? {
+        0 => Err(format!("{} must be at least 1", flag)),
+        value if value > u32::MAX as u64 => Err(format!("{} is too large", flag)),
+        value => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cli::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn run(line: &str) -> RunOptions {
+        match parse(&args(line)).unwrap().command {
+            Command::Run(options) => options,
+            other => panic!("expected run, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_defaults() {
+        let options = run("roms/pong.ch8");
+        assert_eq!(options.machine.rom, "roms/pong.ch8");
+        assert_eq!(options.machine.preset, Preset::Default);
+        assert_eq!(options.frontend, Frontend::Window);
+        assert_eq!(options.scale, 10);
+        assert_eq!(options, run("run roms/pong.ch8"));
+    }
+
+    #[test]
+    fn test_run_options() {
+        let options = run(
+            "run --ipf 20 --scale 5 --quirks schip --start 0x600 --seed 7 --headless --frames 30 \
+             --palette 000000,00FF00 --keymap keys.cfg game.ch8",
+        );
+        assert_eq!(options.machine.instructions_per_frame, Some(20));
+        assert_eq!(options.machine.preset, Preset::SuperChip);
+        assert_eq!(options.machine.start, Some(0x600));
+        assert_eq!(options.machine.seed, Some(7));
+        assert_eq!(options.frontend, Frontend::Headless { frames: 30 });
+        assert_eq!(options.scale, 5);
+        assert_eq!(options.palette.0[1], [0, 255, 0]);
+        assert_eq!(options.keymap, Some(PathBuf::from("keys.cfg")));
+        assert_eq!(run("--gdb 1234 a.ch8").frontend, Frontend::Gdb(1234));
+
+        let cli = parse(&args("--log debug info a.ch8")).unwrap();
+        assert_eq!(cli.log, Some("debug".to_string()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |line: &str| parse(&args(line)).unwrap_err();
+        assert_eq!(error(""), "no rom given");
+        assert_eq!(error("run"), "no rom given");
+        assert_eq!(error("run --fast a.ch8"), "unknown option --fast");
+        assert_eq!(
+            error("run a.ch8 b.ch8"),
+            "expected one rom, got a.ch8 b.ch8"
+        );
+        assert_eq!(error("run --ipf 0 a.ch8"), "--ipf must be at least 1");
+        assert_eq!(
+            error("run --ipf a.ch8"),
+            "--ipf expects a number, got 'a.ch8'"
+        );
+        assert_eq!(error("run --scale"), "--scale needs a value");
+        assert_eq!(
+            error("--start 0x1000 a.ch8"),
+            "--start 0x1000 is outside of memory"
+        );
+        assert!(error("--quirks nes a.ch8").starts_with("unknown quirks preset 'nes'"));
+        assert!(error("--tui --debug a.ch8").starts_with("choose one of"));
+        assert!(error("--frames 10 a.ch8").starts_with("--frames only works"));
+        assert!(error("--headless --record a.mov a.ch8").starts_with("--record needs"));
+        assert!(error("test --update a.ch8").starts_with("--update needs"));
+        assert!(error("--seed 1 --vip-random vip.bin a.ch8").starts_with("--seed has no effect"));
+        assert!(error("disasm --labels --dot a.ch8").starts_with("choose one of"));
+    }
+
+    #[test]
+    fn test_other_commands() {
+        assert_eq!(parse(&args("--help")).unwrap().command, Command::Help);
+        assert_eq!(
+            parse(&args("disasm --octo --labels a.ch8"))
+                .unwrap()
+                .command,
+            Command::Disasm {
+                rom: "a.ch8".to_string(),
+                output: Disassembly::Labelled(Syntax::Octo),
+            }
+        );
+        assert_eq!(
+            parse(&args("asm game.8o")).unwrap().command,
+            Command::Asm {
+                source: "game.8o".to_string(),
+                output: PathBuf::from("game.ch8"),
+            }
+        );
+        match parse(&args(
+            "test --frames 60 --expect a.txt --quirks chip48 a.ch8",
+        ))
+        .unwrap()
+        .command
+        {
+            Command::Test(options) => {
+                assert_eq!(options.frames, 60);
+                assert_eq!(options.expect, Some(PathBuf::from("a.txt")));
+                assert_eq!(options.machine.preset, Preset::Chip48);
+            }
+            other => panic!("expected test, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_rom() {
+        let error = read_rom("roms/pong.ch8").unwrap_err();
+        assert!(error.starts_with("roms/pong.ch8: no such rom, roms in roms: "));
+        assert!(error.contains("BC_test.ch8"));
+    }
+
+    #[test]
+    fn test_machine_setup() {
+        let options = run("--quirks xochip --ipf 30 --start 0x300 a.ch8").machine;
+        let mut cpu = options.cpu().unwrap();
+        assert_eq!(cpu.quirks(), &Quirks::xo_chip());
+        assert_eq!(cpu.pc(), 0x300);
+        // extended memory
+        assert!(cpu.load_bytes(&[0; 0x2000]).is_ok());
+    }
+}
@@ -0,0 +1,61 @@
+use cpu::FRAME_RATE;
+
+/// Absorbs rounding errors when `dt` is an exact multiple of a frame
+const FRAME_EPSILON: f64 = 1e-9;
+/// Most frames a single `advance` hands out. Longer gaps, e.g. from dragging the window
+/// or sitting in a debugger, are dropped instead of being caught up on all at once
+pub const MAX_FRAMES_PER_ADVANCE: u32 = 4;
+
+/// Turns wall time into 60 Hz frames
+/// Elapsed time is accumulated and every full 1/60 s is one frame,
+/// leftover time carries over to the next call
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameClock {
+    pending_frames: f64,
+}
+
+impl FrameClock {
+    /// Add `dt` seconds and take the whole frames that are due, at most `MAX_FRAMES_PER_ADVANCE`
+    pub fn advance(&mut self, dt: f64) -> u32 {
+        self.pending_frames += dt * FRAME_RATE;
+        let mut frames = 0;
+        while self.pending_frames >= 1.0 - FRAME_EPSILON {
+            if frames == MAX_FRAMES_PER_ADVANCE {
+                self.pending_frames = 0.0;
+                break;
+            }
+            self.pending_frames -= 1.0;
+            frames += 1;
+        }
+        frames
+    }
+
+    /// Forget the time that did not make up a frame yet
+    pub fn reset(&mut self) {
+        self.pending_frames = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clock::*;
+
+    #[test]
+    fn test_accumulates_partial_frames() {
+        let mut clock = FrameClock::default();
+        assert_eq!(clock.advance(1.0 / 120.0), 0);
+        assert_eq!(clock.advance(1.0 / 120.0), 1);
+        assert_eq!(clock.advance(3.0 / 60.0), 3);
+        assert_eq!(clock.advance(0.4 / 60.0), 0);
+        clock.reset();
+        assert_eq!(clock.advance(0.6 / 60.0), 0);
+    }
+
+    #[test]
+    fn test_long_gaps_are_capped() {
+        let mut clock = FrameClock::default();
+        assert_eq!(clock.advance(10.0), MAX_FRAMES_PER_ADVANCE);
+        // the rest of the gap is gone
+        assert_eq!(clock.advance(0.5 / 60.0), 0);
+    }
+}
@@ -1,10 +1,24 @@
+use audio::{Sound, DEFAULT_PITCH, PATTERN_SIZE};
+use error::{CpuError, SaveStateError};
+use framebuffer::{Framebuffer, HIRES_HEIGHT, HIRES_WIDTH, LORES_HEIGHT, LORES_WIDTH, PLANE_COUNT};
+use instruction::Instruction;
+use quirks::Quirks;
 use rand::prelude::*;
-use std::fs::File;
-use std::io::Bytes;
+use random::{RandomSource, SplitMix};
+use savestate;
+use savestate::{StateReader, StateWriter};
 
 const FONTSET_START: usize = 0;
-const WIDTH: usize = 64;
-const HEIGTH: usize = 32;
+const BIG_FONTSET_START: usize = 0x50;
+/// Without extended memory the address space ends at 0xFFF
+pub const MEMORY_SIZE: usize = 4096;
+/// XO-CHIP extends the address space to 16 bit
+pub const EXTENDED_MEMORY_SIZE: usize = 0x10000;
+/// ROMs are loaded and start executing here
+pub const PROGRAM_START: usize = 0x200;
+/// Delay and sound timers count down at 60 Hz, one tick per frame
+pub const FRAME_RATE: f64 = 60.0;
+const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
 
 const FONTSET: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -25,76 +39,363 @@ const FONTSET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+/// SUPER-CHIP 8x10 font, digits A-F as drawn by Octo
+const BIG_FONTSET: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// Decides when an `FX0A` key wait completes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyWaitMode {
+    /// Resume as soon as a key goes down
+    Press,
+    /// Resume once a key pressed during the wait is released again, as on the COSMAC VIP
+    Release,
+}
+
+/// What to do with a `0NNN` call into native machine code, which cannot run on an interpreter
+pub enum MachineCall {
+    /// Skip the instruction silently
+    Ignore,
+    /// Skip the instruction and log the target address
+    Log,
+    /// Hand the target address to a host callback, then continue with the next instruction
+    Trap(Box<dyn FnMut(u16)>),
+}
+
+/// Result of a single successful `Cpu::step`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepOutcome {
+    /// An instruction was fetched and executed
+    Executed,
+    /// Fetching is suspended until `FX0A` receives a key
+    WaitingForKey,
+    /// The program ended itself with `00FD`
+    Exited,
+}
+
+/// Direction of a memory access
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A data access made by an instruction, recorded while access tracking is enabled
+/// Covers FX55, FX65, FX33, 5XY2, 5XY3, F002 and the sprite reads of DXYN, not instruction fetches
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    /// Address of the instruction responsible
+    pub pc: u16,
+    pub opcode: u16,
+    pub address: u16,
+    pub kind: AccessKind,
+    /// The byte read or written
+    pub value: u8,
+}
+
 pub struct Cpu {
     opcode: u16,
-    mem: [u8; 4096],
+    mem: Vec<u8>,
     v: [u8; 16],
     i: u16,
     pc: u16,
-    pub gfx: [u8; WIDTH * HEIGTH],
+    gfx: Framebuffer,
     stack: [u16; 16],
     stack_pointer: u16,
     key: [u8; 16],
     // timers
     delay_timer: u8,
     sound_timer: u8,
+    // F002 / FX3A
+    audio_pattern: Option<[u8; PATTERN_SIZE]>,
+    pitch: u8,
     pub redraw: bool,
+    // FX75 / FX85
+    rpl_flags: [u8; 16],
+    // FX0A
+    key_wait_mode: KeyWaitMode,
+    key_wait_register: Option<u8>,
+    key_wait_pressed: Option<u8>,
+    // BNNN / 0NNN
+    machine_call: MachineCall,
+    quirks: Quirks,
+    // FN01, bitplanes affected by drawing, clearing and scrolling
+    planes: u8,
+    // set by DXYN with the display wait quirk, ends the current frame
+    wait_for_vblank: bool,
+    // timing
+    instructions_per_frame: u32,
+    // set once an instruction failed, execution stops until the cpu is rebuilt
+    halted: Option<CpuError>,
+    // set by 00FD
+    exited: bool,
+    // identifies the loaded rom in save states
+    rom_hash: u64,
+    // data accesses of the last instruction, for watchpoints
+    track_accesses: bool,
+    accesses: Vec<MemoryAccess>,
+    instruction_pc: u16,
+    // roms are loaded and start executing here
+    start_address: u16,
+    // CXNN, the seed makes runs reproducible
+    seed: u64,
+    random: Box<dyn RandomSource>,
 }
 
 impl Default for Cpu {
     fn default() -> Cpu {
+        let seed = thread_rng().gen();
         Cpu {
             opcode: 0,
-            mem: [0; 4096],
+            mem: vec![0; MEMORY_SIZE],
             v: [0; 16],
             i: 0,
             pc: 0,
-            gfx: [0; 64 * 32],
+            gfx: Framebuffer::default(),
             stack: [0; 16],
             stack_pointer: 0,
             key: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
             redraw: false,
+            rpl_flags: [0; 16],
+            key_wait_mode: KeyWaitMode::Release,
+            key_wait_register: None,
+            key_wait_pressed: None,
+            machine_call: MachineCall::Log,
+            quirks: Quirks::default(),
+            planes: 1,
+            wait_for_vblank: false,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            halted: None,
+            exited: false,
+            rom_hash: savestate::rom_hash(&[]),
+            track_accesses: false,
+            accesses: Vec::new(),
+            instruction_pc: 0,
+            start_address: PROGRAM_START as u16,
+            seed,
+            random: Box::new(SplitMix::new(seed)),
         }
     }
 }
 
 impl Cpu {
     /// Setup fontmap and initialize program counter
-    /// Fontmap is loaded into the first 80 bytes
-    /// Programm counter starts at 0x200
+    /// Fontmap is loaded into the first 80 bytes, followed by the big SUPER-CHIP font
+    /// Programm counter starts at 0x200, or the start address if one was set
     pub fn init(mut self) -> Self {
         self.mem[0..80].clone_from_slice(&FONTSET);
-        self.pc = 0x200;
-        return self;
+        self.mem[BIG_FONTSET_START..BIG_FONTSET_START + BIG_FONTSET.len()]
+            .clone_from_slice(&BIG_FONTSET);
+        self.pc = self.start_address;
+        self
+    }
+
+    /// Choose whether `FX0A` completes on key press or on key release
+    pub fn with_key_wait_mode(mut self, mode: KeyWaitMode) -> Self {
+        self.key_wait_mode = mode;
+        self
+    }
+
+    /// Grow memory to the 64 KiB address space of XO-CHIP
+    pub fn with_extended_memory(mut self) -> Self {
+        self.mem.resize(EXTENDED_MEMORY_SIZE, 0);
+        self
+    }
+
+    /// Select the platform specific behaviour of ambiguous instructions
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Choose how `0NNN` machine code calls are handled
+    pub fn with_machine_call(mut self, behaviour: MachineCall) -> Self {
+        self.machine_call = behaviour;
+        self
+    }
+
+    /// Seed the random numbers of CXNN, by default the seed is random
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.set_seed(seed);
+        self
+    }
+
+    /// Restart the random numbers of CXNN from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.random.reseed(seed);
+    }
+
+    /// Take the random numbers of CXNN from `source` instead of the default PRNG,
+    /// the source is started from the current seed
+    pub fn with_random_source<R: RandomSource + 'static>(mut self, source: R) -> Self {
+        self.random = Box::new(source);
+        self.random.reseed(self.seed);
+        self
+    }
+
+    /// The seed the random numbers were last started from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Load roms at `address` and start executing there instead of 0x200,
+    /// e.g. 0x600 for ETI 660 programs
+    pub fn with_start_address(mut self, address: u16) -> Self {
+        self.start_address = address;
+        self.pc = address;
+        self
+    }
+
+    /// Number of instructions executed between two 60 Hz timer ticks
+    pub fn with_instructions_per_frame(mut self, instructions: u32) -> Self {
+        self.instructions_per_frame = instructions;
+        self
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    /// Address roms are loaded at
+    pub fn start_address(&self) -> u16 {
+        self.start_address
+    }
+
+    /// Name of the random source of CXNN, e.g. `splitmix` for the default
+    pub fn random_source(&self) -> &'static str {
+        self.random.name()
     }
 
     /// Load bytes intor ROM and RAM memory range
-    /// Range starts at 0x200 and ends at 0xFFF
-    pub fn load_bytes(&mut self, bytes: Bytes<File>) {
-        for (i, byte) in bytes.enumerate() {
-            self.mem[i + 512] = byte.unwrap()
+    /// Range starts at 0x200 and ends at 0xFFF, or 0xFFFF with extended memory
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), CpuError> {
+        let start = self.start_address as usize;
+        // a start address past the end of memory leaves no room at all
+        let max = self.mem.len().saturating_sub(start);
+        if max == 0 || bytes.len() > max {
+            return Err(CpuError::RomTooLarge {
+                size: bytes.len(),
+                max,
+                start: self.start_address,
+            });
         }
+        self.mem[start..start + bytes.len()].copy_from_slice(bytes);
+        self.rom_hash = savestate::rom_hash(bytes);
+        Ok(())
     }
 
     /// Fetch the opcode from memory
     /// Opcode is 2 bytes
-    fn fetch_opcode(&self) -> u16 {
-        let a = (self.mem[self.pc as usize] as u16) << 8;
-        let b = self.mem[self.pc as usize + 1] as u16;
-        return a | b;
+    fn fetch_opcode(&self) -> Result<u16, CpuError> {
+        let a = (self.read_mem(self.pc)? as u16) << 8;
+        let b = self.read_mem(self.pc.wrapping_add(1))? as u16;
+        Ok(a | b)
+    }
+
+    /// Fetch and execute a single instruction.
+    /// Once an instruction fails the cpu halts and every further step returns the same error
+    pub fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        if let Some(ref err) = self.halted {
+            return Err(err.clone());
+        }
+        if self.exited {
+            return Ok(StepOutcome::Exited);
+        }
+        // fetching is suspended until FX0A receives its key
+        if self.is_waiting_for_key() {
+            return Ok(StepOutcome::WaitingForKey);
+        }
+
+        self.accesses.clear();
+        self.instruction_pc = self.pc;
+        let result = self
+            .fetch_opcode()
+            .and_then(|opcode| self.execute_opcode(opcode));
+        if let Err(ref err) = result {
+            error!("Cpu halted: {}", err);
+            self.halted = Some(err.clone());
+        }
+        result.map(|_| StepOutcome::Executed)
+    }
+
+    /// Run one 60 Hz frame: up to `instructions_per_frame` instructions, then a timer tick.
+    /// Timers keep counting while `FX0A` blocks execution
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        self.run_frame_until(|_| false).map(|_| ())
+    }
+
+    /// Like `run_frame`, but `stop` is asked before every instruction whether to stop here.
+    /// Returns true if `stop` ended the frame early, the timers are not ticked then
+    pub fn run_frame_until<F>(&mut self, mut stop: F) -> Result<bool, CpuError>
+    where
+        F: FnMut(&Cpu) -> bool,
+    {
+        self.wait_for_vblank = false;
+        for _ in 0..self.instructions_per_frame {
+            if stop(self) {
+                return Ok(true);
+            }
+            if self.step()? != StepOutcome::Executed || self.wait_for_vblank {
+                break;
+            }
+        }
+        self.handle_timers();
+        Ok(false)
+    }
+
+    /// The error that stopped execution, if any
+    pub fn halted(&self) -> Option<&CpuError> {
+        self.halted.as_ref()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.is_some()
+    }
+
+    /// True once the program has ended itself with `00FD`
+    pub fn has_exited(&self) -> bool {
+        self.exited
     }
 
-    pub fn cycle(&mut self, dt: f64) {
-        let num_instr = (dt * 600.0).round() as u64;
-        for _ in 1..num_instr {
-            let opcode = self.fetch_opcode();
-            self.execute_opcode(opcode);
-            self.handle_timers();
+    /// Sound state for the audio generator
+    pub fn sound(&self) -> Sound {
+        Sound {
+            playing: self.sound_timer > 0,
+            pattern: self.audio_pattern,
+            pitch: self.pitch,
         }
     }
 
+    /// The display as last drawn by the program
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.gfx
+    }
+
     fn handle_timers(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
@@ -111,16 +412,240 @@ impl Cpu {
     pub fn handle_key_press(&mut self, key: u8) {
         debug!("key pressed 0x{:X}", key);
         self.key[key as usize] = 1;
+
+        if self.is_waiting_for_key() {
+            match self.key_wait_mode {
+                KeyWaitMode::Press => self.finish_key_wait(key),
+                KeyWaitMode::Release => self.key_wait_pressed = Some(key),
+            }
+        }
     }
 
     pub fn handle_key_release(&mut self, key: u8) {
         debug!("key released 0x{:X}", key);
         self.key[key as usize] = 0;
+
+        if self.key_wait_mode == KeyWaitMode::Release && self.key_wait_pressed == Some(key) {
+            self.finish_key_wait(key);
+        }
+    }
+
+    /// True while an `FX0A` instruction is blocking execution
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.key_wait_register.is_some()
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// The index register I
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    /// V0 to VF
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    /// Return addresses of the subroutines currently being executed, innermost last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer as usize]
+    }
+
+    /// Continue execution at `pc`, for debuggers
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Overwrite the index register, for debuggers
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    /// Record the data accesses of every instruction, see `memory_accesses`
+    pub fn track_memory_accesses(&mut self, enabled: bool) {
+        self.track_accesses = enabled;
+        if !enabled {
+            self.accesses.clear();
+        }
+    }
+
+    /// Data accesses of the instruction executed last, in the order they happened
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
     }
 
-    pub fn dump_memory(&self) -> &[u8; 4096] {
+    /// The two bytes at `address` as an opcode, `None` outside of memory
+    pub fn opcode_at(&self, address: u16) -> Option<u16> {
+        match (
+            self.read_mem(address),
+            self.read_mem(address.wrapping_add(1)),
+        ) {
+            (Ok(high), Ok(low)) => Some((high as u16) << 8 | low as u16),
+            _ => None,
+        }
+    }
+
+    pub fn dump_memory(&self) -> &[u8] {
         &self.mem
     }
+
+    /// Overwrite memory starting at `address`, for debuggers
+    /// Nothing is written if the range does not fit into memory
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) -> Result<(), CpuError> {
+        let start = address as usize;
+        match self.mem.get_mut(start..start + bytes.len()) {
+            Some(range) => {
+                range.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(CpuError::MemoryOutOfBounds {
+                address: start + bytes.len() - 1,
+            }),
+        }
+    }
+
+    /// Hash of the last rom loaded with `load_bytes`
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    /// Hash of `save_state`, equal for two cpus that will behave the same
+    pub fn state_hash(&self) -> u64 {
+        savestate::rom_hash(&self.save_state())
+    }
+
+    /// Serialize the machine state: memory, registers, stack, timers, keypad,
+    /// framebuffer and audio registers, see `savestate::encode` for the container.
+    /// Configuration like quirks and the instructions per frame is not part of the state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.slice(&self.mem);
+        writer.bytes(&self.v);
+        writer.u16(self.i);
+        writer.u16(self.pc);
+        writer.u16(self.opcode);
+        for address in self.stack.iter() {
+            writer.u16(*address);
+        }
+        writer.u16(self.stack_pointer);
+        writer.bytes(&self.key);
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+        writer.bool(self.audio_pattern.is_some());
+        writer.bytes(&self.audio_pattern.unwrap_or([0; PATTERN_SIZE]));
+        writer.u8(self.pitch);
+        writer.bytes(&self.rpl_flags);
+        writer.u8(self.key_wait_register.map_or(0xFF, |register| register));
+        writer.u8(self.key_wait_pressed.map_or(0xFF, |key| key));
+        writer.u8(self.planes);
+        writer.bool(self.exited);
+        writer.u16(self.gfx.width() as u16);
+        writer.u16(self.gfx.height() as u16);
+        writer.bytes(self.gfx.pixels());
+        writer.u64(self.seed);
+        let mut random = StateWriter::default();
+        self.random.save_state(&mut random);
+        writer.slice(&random.into_inner());
+        savestate::encode(self.rom_hash, &writer.into_inner())
+    }
+
+    /// Restore a state written by `save_state`.
+    /// The state has to be saved with the same rom and memory size, a state that fails to load leaves the cpu untouched.
+    /// Loading a state clears a halt, so a crashed program can be resumed from an earlier point
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let payload = savestate::decode(data, self.rom_hash)?;
+        let mut reader = StateReader::new(payload);
+
+        let mem = reader.slice()?;
+        // the memory size is a setting of the cpu, not part of the state
+        if mem.len() != self.mem.len() {
+            return Err(SaveStateError::Corrupt("invalid memory size"));
+        }
+        let mut v = [0; 16];
+        v.copy_from_slice(reader.bytes(16)?);
+        let i = reader.u16()?;
+        let pc = reader.u16()?;
+        let opcode = reader.u16()?;
+        let mut stack = [0; 16];
+        for address in stack.iter_mut() {
+            *address = reader.u16()?;
+        }
+        let stack_pointer = reader.u16()?;
+        if stack_pointer as usize > stack.len() {
+            return Err(SaveStateError::Corrupt("invalid stack pointer"));
+        }
+        let mut key = [0; 16];
+        key.copy_from_slice(reader.bytes(16)?);
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let has_pattern = reader.bool()?;
+        let mut pattern = [0; PATTERN_SIZE];
+        pattern.copy_from_slice(reader.bytes(PATTERN_SIZE)?);
+        let pitch = reader.u8()?;
+        let mut rpl_flags = [0; 16];
+        rpl_flags.copy_from_slice(reader.bytes(16)?);
+        let key_wait_register = match reader.u8()? {
+            0xFF => None,
+            register if register < 16 => Some(register),
+            _ => return Err(SaveStateError::Corrupt("invalid key wait register")),
+        };
+        let key_wait_pressed = match reader.u8()? {
+            0xFF => None,
+            key if key < 16 => Some(key),
+            _ => return Err(SaveStateError::Corrupt("invalid key")),
+        };
+        let planes = reader.u8()?;
+        if planes > 3 {
+            return Err(SaveStateError::Corrupt("invalid planes"));
+        }
+        let exited = reader.bool()?;
+        let width = reader.u16()? as usize;
+        let height = reader.u16()? as usize;
+        let pixels = reader.bytes(width * height)?.to_vec();
+        let gfx = Framebuffer::from_pixels(width, height, pixels)
+            .ok_or(SaveStateError::Corrupt("invalid display"))?;
+        let seed = reader.u64()?;
+        let mut random = StateReader::new(reader.slice()?);
+        if !reader.is_empty() {
+            return Err(SaveStateError::Truncated);
+        }
+        self.random.load_state(&mut random)?;
+
+        self.mem = mem.to_vec();
+        self.v = v;
+        self.i = i;
+        self.pc = pc;
+        self.opcode = opcode;
+        self.stack = stack;
+        self.stack_pointer = stack_pointer;
+        self.key = key;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.audio_pattern = if has_pattern { Some(pattern) } else { None };
+        self.pitch = pitch;
+        self.rpl_flags = rpl_flags;
+        self.key_wait_register = key_wait_register;
+        self.key_wait_pressed = key_wait_pressed;
+        self.planes = planes;
+        self.exited = exited;
+        self.gfx = gfx;
+        self.seed = seed;
+        self.wait_for_vblank = false;
+        self.halted = None;
+        self.redraw = true;
+        Ok(())
+    }
     /// Returns the contents of a register
     fn read_register(&self, register: u8) -> u8 {
         self.v[register as usize]
@@ -128,181 +653,183 @@ impl Cpu {
 
     /// Write `content` to `register`.
     /// Does not increment program counter
-    fn set_register(&mut self, register: u8, content: u8) {
+    pub fn set_register(&mut self, register: u8, content: u8) {
         self.v[register as usize] = content
     }
 
     /// Increment program counter by two, normal step
     fn inc_pc(&mut self) {
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
-    fn read_mem(&self, offset: u16) -> u8 {
-        self.mem[offset as usize]
+    /// Move the program counter past the next instruction
+    /// F000 NNNN is the only instruction that is four bytes long
+    fn skip_next_instruction(&mut self) {
+        self.inc_pc();
+        if self.fetch_opcode() == Ok(0xF000) {
+            self.inc_pc();
+        }
     }
 
-    fn execute_opcode(&mut self, opcode: u16) {
+    fn read_mem(&self, offset: u16) -> Result<u8, CpuError> {
+        self.mem
+            .get(offset as usize)
+            .cloned()
+            .ok_or(CpuError::MemoryOutOfBounds {
+                address: offset as usize,
+            })
+    }
+
+    /// Read memory on behalf of an instruction, recorded for watchpoints
+    fn load_data(&mut self, address: u16) -> Result<u8, CpuError> {
+        let value = self.read_mem(address)?;
+        self.record_access(address, AccessKind::Read, value);
+        Ok(value)
+    }
+
+    /// Write memory on behalf of an instruction, recorded for watchpoints
+    fn store_data(&mut self, address: u16, value: u8) -> Result<(), CpuError> {
+        self.write_mem(address, value)?;
+        self.record_access(address, AccessKind::Write, value);
+        Ok(())
+    }
+
+    fn record_access(&mut self, address: u16, kind: AccessKind, value: u8) {
+        if self.track_accesses {
+            self.accesses.push(MemoryAccess {
+                pc: self.instruction_pc,
+                opcode: self.opcode,
+                address,
+                kind,
+                value,
+            });
+        }
+    }
+
+    fn write_mem(&mut self, offset: u16, content: u8) -> Result<(), CpuError> {
+        match self.mem.get_mut(offset as usize) {
+            Some(cell) => {
+                *cell = content;
+                Ok(())
+            }
+            None => Err(CpuError::MemoryOutOfBounds {
+                address: offset as usize,
+            }),
+        }
+    }
+
+    fn execute_opcode(&mut self, opcode: u16) -> Result<(), CpuError> {
         self.opcode = opcode;
         debug!("OpCode: 0x{:X}", self.opcode);
-        match opcode & 0xF000 {
-            0x0000 => match opcode & 0x000F {
-                0x0000 => self.clear_screen(),
-                0x000E => self.return_from_sub(),
-                _ => self.op_unknown(),
-            },
-            0x1000 => {
-                let address = self.opcode & 0x0FFF;
-                self.jump_to(address);
-            }
-            0x2000 => {
-                let address = self.opcode & 0x0FFF;
-                self.call_sub_at(address);
-            }
-            0x3000 => {
-                let vx = self.read_register(self._x());
-                let nn = self._nn();
-                self.skip_if_eq(vx, nn)
+        match Instruction::decode(opcode) {
+            Instruction::ClearScreen => self.clear_screen(),
+            Instruction::Return => self.return_from_sub()?,
+            Instruction::ScrollDown(lines) => self.scroll_down(lines as usize),
+            Instruction::ScrollUp(lines) => self.scroll_up(lines as usize),
+            Instruction::ScrollRight => self.scroll_right(),
+            Instruction::ScrollLeft => self.scroll_left(),
+            Instruction::Exit => self.exit(),
+            Instruction::LowRes => self.set_resolution(false),
+            Instruction::HighRes => self.set_resolution(true),
+            Instruction::MachineCall(address) => self.call_machine_code(address),
+            Instruction::Jump(address) => self.jump_to(address),
+            Instruction::Call(address) => self.call_sub_at(address)?,
+            Instruction::SkipIfEqual(x, nn) => {
+                let vx = self.read_register(x);
+                self.skip_if_eq(vx, nn);
             }
-            0x4000 => {
-                let vx = self.read_register(self._x());
-                let nn = self._nn();
+            Instruction::SkipIfNotEqual(x, nn) => {
+                let vx = self.read_register(x);
                 self.skip_if_neq(vx, nn);
             }
-            0x5000 => {
-                let vx = self.read_register(self._x());
-                let vy = self.read_register(self._y());
+            Instruction::SkipIfRegistersEqual(x, y) => {
+                let vx = self.read_register(x);
+                let vy = self.read_register(y);
                 self.skip_if_eq(vx, vy);
             }
-            0x6000 => {
-                let vx = self._x();
-                let nn = self._nn();
-                self.set_register(vx, nn);
+            Instruction::StoreRange(x, y) => self.store_register_range(x, y)?,
+            Instruction::LoadRange(x, y) => self.fill_register_range(x, y)?,
+            Instruction::SetRegister(x, nn) => {
+                self.set_register(x, nn);
                 self.inc_pc();
             }
-            0x7000 => {
-                let vx = self._x();
-                let nn = self._nn();
-                self.add_to_register(vx, nn);
+            Instruction::AddToRegister(x, nn) => self.add_to_register(x, nn),
+            Instruction::Copy(x, y) => {
+                let vy = self.read_register(y);
+                self.set_register(x, vy);
+                self.inc_pc();
             }
-            0x8000 => match opcode & 0x000F {
-                0x0000 => {
-                    let x = self._x();
-                    let vy = self.read_register(self._y());
-                    self.set_register(x, vy);
-                    self.inc_pc();
-                }
-                0x0001 => {
-                    let x = self._x();
-                    let y = self._y();
-                    self.or(x, y);
-                }
-                0x0002 => {
-                    let x = self._x();
-                    let y = self._y();
-                    self.and(x, y);
-                }
-                0x0003 => {
-                    let x = self._x();
-                    let y = self._y();
-                    self.xor(x, y);
-                }
-                0x0004 => {
-                    let x = self._x();
-                    let vy = self.read_register(self._y());
-                    self.add_to_register_with_carry(x, vy);
-                }
-                0x0005 => {
-                    let vx = self._x();
-                    let value = self.read_register(self._y());
-                    self.subtract_from_register_with_carry(vx, value);
-                }
-                0x006 => {
-                    let vx = self._x();
-                    self.right_shift_register(vx);
-                }
-                0x007 => {
-                    let vx = self._x();
-                    let vy = self._y();
-                    self.subtract_registerx_from_registery_set_registerx(vx, vy);
-                }
-                0x00E => {
-                    let vx = self._x();
-                    self.left_shift_register(vx);
-                }
-                _ => self.op_unknown(),
-            },
-            0x9000 => {
-                let registerx_contents = self.read_register(self._x());
-                let registery_contents = self.read_register(self._y());
-                self.skip_if_neq(registerx_contents, registery_contents);
+            Instruction::Or(x, y) => self.or(x, y),
+            Instruction::And(x, y) => self.and(x, y),
+            Instruction::Xor(x, y) => self.xor(x, y),
+            Instruction::Add(x, y) => {
+                let vy = self.read_register(y);
+                self.add_to_register_with_carry(x, vy);
             }
-            0xA000 => {
-                let address = self.opcode & 0x0FFF;
-                self.set_index_register(address);
+            Instruction::Subtract(x, y) => {
+                let vy = self.read_register(y);
+                self.subtract_from_register_with_carry(x, vy);
             }
-            0xC000 => self.rand(),
-            0xD000 => self.draw(),
-            0xE000 => match opcode & 0x00FF {
-                0x009E => {
-                    let x = self._x();
-                    self.check_key_pressed(x);
-                }
-                0x00A1 => {
-                    let x = self._x();
-                    self.check_key_released(x);
-                }
-                _ => self.op_unknown(),
-            },
-            0xF000 => match opcode & 0x00FF {
-                0x001E => {
-                    let vx = self._x();
-                    self.add_vx_to_i(vx);
-                }
-                0x007 => {
-                    let vx = self._x();
-                    self.get_delay_timer(vx);
-                }
-                0x0015 => {
-                    let vx = self._x();
-                    self.set_delay_timer(vx)
-                }
-                0x0018 => {
-                    let x = self._x();
-                    self.set_sound_timer(x);
-                }
-                0x0055 => {
-                    let vx = self._x();
-                    self.store_registers_up_to(vx);
-                }
-                0x0029 => {
-                    let vx = self._x();
-                    self.set_index_register_to_character_sprite(vx);
-                }
-                0x0033 => {
-                    let vx = self.read_register(self._x());
-                    self.store_bcd(vx);
-                }
-                0x0065 => {
-                    let vx = self._x();
-                    self.fill_registers_up_to(vx);
-                }
-                _ => self.op_unknown(),
-            },
-            _ => self.op_unknown(),
+            Instruction::ShiftRight(x, y) => self.right_shift_register(x, y),
+            Instruction::SubtractReversed(x, y) => {
+                self.subtract_registerx_from_registery_set_registerx(x, y)
+            }
+            Instruction::ShiftLeft(x, y) => self.left_shift_register(x, y),
+            Instruction::SkipIfRegistersNotEqual(x, y) => {
+                let vx = self.read_register(x);
+                let vy = self.read_register(y);
+                self.skip_if_neq(vx, vy);
+            }
+            Instruction::SetIndex(address) => self.set_index_register(address),
+            Instruction::JumpWithOffset(address) => self.jump_with_offset(address),
+            Instruction::Random(x, nn) => self.rand(x, nn),
+            Instruction::Draw(x, y, n) => self.draw(x, y, n)?,
+            Instruction::SkipIfKeyPressed(x) => self.check_key_pressed(x),
+            Instruction::SkipIfKeyNotPressed(x) => self.check_key_released(x),
+            Instruction::SetIndexLong => self.set_index_register_long()?,
+            Instruction::SelectPlanes(planes) => self.select_planes(planes),
+            Instruction::LoadAudioPattern => self.load_audio_pattern()?,
+            Instruction::GetDelayTimer(x) => self.get_delay_timer(x),
+            Instruction::WaitForKey(x) => self.wait_for_key(x),
+            Instruction::SetDelayTimer(x) => self.set_delay_timer(x),
+            Instruction::SetSoundTimer(x) => self.set_sound_timer(x),
+            Instruction::AddToIndex(x) => self.add_vx_to_i(x),
+            Instruction::FontCharacter(x) => {
+                let vx = self.read_register(x);
+                self.set_index_register_to_character_sprite(vx);
+            }
+            Instruction::BigFontCharacter(x) => {
+                let vx = self.read_register(x);
+                self.set_index_register_to_big_character_sprite(vx);
+            }
+            Instruction::StoreBcd(x) => {
+                let vx = self.read_register(x);
+                self.store_bcd(vx)?;
+            }
+            Instruction::SetPitch(x) => self.set_pitch(x),
+            Instruction::StoreRegisters(x) => self.store_registers_up_to(x)?,
+            Instruction::LoadRegisters(x) => self.fill_registers_up_to(x)?,
+            Instruction::StoreFlags(x) => self.store_rpl_flags(x),
+            Instruction::LoadFlags(x) => self.load_rpl_flags(x),
+            Instruction::Unknown(_) => return self.op_unknown(),
         }
+        Ok(())
     }
 
     pub fn needs_redraw(&self) -> bool {
         self.redraw
     }
 
-    fn op_unknown(&self) {
+    fn op_unknown(&self) -> Result<(), CpuError> {
         error!(
             "Unknown opcode 0x{:X}, 0x{:X}",
             self.opcode,
             self.opcode & 0xF000
         );
-        panic!();
+        Err(CpuError::UnknownOpcode {
+            pc: self.pc,
+            opcode: self.opcode,
+        })
     }
 
     fn set_delay_timer(&mut self, vx: u8) {
@@ -315,12 +842,60 @@ impl Cpu {
         self.inc_pc();
     }
 
+    /// Loads the 16 byte audio pattern from memory starting at address I
+    /// F002
+    fn load_audio_pattern(&mut self) -> Result<(), CpuError> {
+        let mut pattern = [0; PATTERN_SIZE];
+        for (offset, byte) in pattern.iter_mut().enumerate() {
+            *byte = self.load_data(self.i.wrapping_add(offset as u16))?;
+        }
+        self.audio_pattern = Some(pattern);
+        self.inc_pc();
+        Ok(())
+    }
+
+    /// Sets the playback rate of the audio pattern to VX
+    /// FX3A
+    fn set_pitch(&mut self, vx: u8) {
+        self.pitch = self.read_register(vx);
+        self.inc_pc();
+    }
+
     fn get_delay_timer(&mut self, vx: u8) {
         let current_delay = self.delay_timer;
         self.set_register(vx, current_delay);
         self.inc_pc();
     }
 
+    /// A key press is awaited, and then stored in VX. (Blocking Operation. All instruction halted until next key event)
+    /// FX0A
+    fn wait_for_key(&mut self, register: u8) {
+        self.key_wait_register = Some(register);
+        self.key_wait_pressed = None;
+        debug!("Waiting for key into V{:X}", register);
+    }
+
+    /// Store the awaited key and resume with the instruction after FX0A
+    fn finish_key_wait(&mut self, key: u8) {
+        if let Some(register) = self.key_wait_register.take() {
+            self.key_wait_pressed = None;
+            self.set_register(register, key);
+            self.inc_pc();
+            debug!("Key wait finished with 0x{:X}", key);
+        }
+    }
+
+    /// Sets I to the 16 bit address NNNN stored in the word after the instruction
+    /// F000 NNNN
+    fn set_index_register_long(&mut self) -> Result<(), CpuError> {
+        let high = self.read_mem(self.pc.wrapping_add(2))? as u16;
+        let low = self.read_mem(self.pc.wrapping_add(3))? as u16;
+        self.i = high << 8 | low;
+        self.inc_pc();
+        self.inc_pc();
+        Ok(())
+    }
+
     /// Sets I to the address NNN.
     fn set_index_register(&mut self, address: u16) {
         self.i = address;
@@ -329,18 +904,82 @@ impl Cpu {
 
     /// Clears the screen
     fn clear_screen(&mut self) {
-        self.gfx = [0; 64 * 32];
+        self.gfx.clear(self.planes);
         self.redraw = true;
         self.inc_pc();
         debug!("Clear screen");
     }
 
+    /// Scroll display N lines down
+    /// 00CN
+    fn scroll_down(&mut self, lines: usize) {
+        self.gfx.scroll_down(lines, self.planes);
+        self.redraw = true;
+        self.inc_pc();
+    }
+
+    /// Scroll display N lines up
+    /// 00DN
+    fn scroll_up(&mut self, lines: usize) {
+        self.gfx.scroll_up(lines, self.planes);
+        self.redraw = true;
+        self.inc_pc();
+    }
+
+    /// Scroll display 4 pixels right
+    /// 00FB
+    fn scroll_right(&mut self) {
+        self.gfx.scroll_right(4, self.planes);
+        self.redraw = true;
+        self.inc_pc();
+    }
+
+    /// Scroll display 4 pixels left
+    /// 00FC
+    fn scroll_left(&mut self) {
+        self.gfx.scroll_left(4, self.planes);
+        self.redraw = true;
+        self.inc_pc();
+    }
+
+    /// Select the bitplanes used by drawing, clearing and scrolling, N is a bit mask
+    /// FN01
+    fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & ((1 << PLANE_COUNT) - 1);
+        self.inc_pc();
+        debug!("Selected planes {:b}", self.planes);
+    }
+
+    /// Exit the interpreter, the program counter stays on this instruction
+    /// 00FD
+    fn exit(&mut self) {
+        self.exited = true;
+        debug!("Exit");
+    }
+
+    /// Switch between 64x32 (00FE) and 128x64 (00FF) mode, which clears the screen
+    /// 00FE / 00FF
+    fn set_resolution(&mut self, hires: bool) {
+        if hires {
+            self.gfx.resize(HIRES_WIDTH, HIRES_HEIGHT);
+        } else {
+            self.gfx.resize(LORES_WIDTH, LORES_HEIGHT);
+        }
+        self.redraw = true;
+        self.inc_pc();
+        debug!("High resolution: {}", hires);
+    }
+
     /// Returns from a subroutine
-    fn return_from_sub(&mut self) {
+    fn return_from_sub(&mut self) -> Result<(), CpuError> {
+        if self.stack_pointer == 0 {
+            return Err(CpuError::StackUnderflow { pc: self.pc });
+        }
         self.stack_pointer -= 1;
         self.pc = self.stack[self.stack_pointer as usize];
         debug!("Return from sub");
         self.inc_pc();
+        Ok(())
     }
 
     /// Jumps to address at NNN
@@ -349,20 +988,49 @@ impl Cpu {
         debug!("Jumping to {:X}", address);
     }
 
+    /// Jumps to the address NNN plus V0.
+    /// With the SUPER-CHIP quirk the opcode is read as BXNN and jumps to XNN plus VX
+    /// BNNN
+    fn jump_with_offset(&mut self, address: u16) {
+        let register = if self.quirks.jump_with_vx {
+            self._x()
+        } else {
+            0
+        };
+        let offset = self.read_register(register) as u16;
+        self.jump_to((address + offset) & 0x0FFF);
+    }
+
+    /// Calls machine code routine at NNN.
+    /// There is no host CPU to run it on, so the call is ignored, logged or trapped to the host
+    /// 0NNN
+    fn call_machine_code(&mut self, address: u16) {
+        match self.machine_call {
+            MachineCall::Ignore => {}
+            MachineCall::Log => warn!("Ignoring machine code call to 0x{:X}", address),
+            MachineCall::Trap(ref mut callback) => callback(address),
+        }
+        self.inc_pc();
+    }
+
     /// Calls subroutine at NNN
-    fn call_sub_at(&mut self, address: u16) {
+    fn call_sub_at(&mut self, address: u16) -> Result<(), CpuError> {
+        if self.stack_pointer as usize >= self.stack.len() {
+            return Err(CpuError::StackOverflow { pc: self.pc });
+        }
         //store current program counter
         self.stack[self.stack_pointer as usize] = self.pc;
         self.stack_pointer += 1;
         self.pc = address;
         debug!("Calling {:X}", address);
+        Ok(())
     }
 
     /// Skips the next instruction if VX equals NN. (Usually the next instruction is a jump to skip a code block)
     /// pseudo c `if(Vx==NN)`
     fn skip_if_eq(&mut self, x: u8, y: u8) {
         if x == y {
-            self.inc_pc();
+            self.skip_next_instruction();
         }
         self.inc_pc();
     }
@@ -371,7 +1039,7 @@ impl Cpu {
     /// pseudo c `if(Vx!=NN)`
     fn skip_if_neq(&mut self, x: u8, y: u8) {
         if x != y {
-            self.inc_pc();
+            self.skip_next_instruction();
         }
         self.inc_pc();
     }
@@ -433,9 +1101,15 @@ impl Cpu {
     }
 
     /// Stores the least significant bit of VX in VF and then shifts VX to the right by 1.[2]
+    /// With the shift quirk VY is shifted and the result stored in VX
     /// 8XY6
-    fn right_shift_register(&mut self, register: u8) {
-        let vx = self.read_register(register);
+    fn right_shift_register(&mut self, register: u8, registery: u8) {
+        let source = if self.quirks.shift_uses_vy {
+            registery
+        } else {
+            register
+        };
+        let vx = self.read_register(source);
         let least_significant = vx & 0x1;
         let result = vx >> 1;
         self.set_register(register, result);
@@ -444,9 +1118,15 @@ impl Cpu {
     }
 
     /// Stores the most significant bit of VX in VF and then shifts VX to the left by 1.[3]
+    /// With the shift quirk VY is shifted and the result stored in VX
     /// 8XYE
-    fn left_shift_register(&mut self, register: u8) {
-        let vx = self.read_register(register);
+    fn left_shift_register(&mut self, register: u8, registery: u8) {
+        let source = if self.quirks.shift_uses_vy {
+            registery
+        } else {
+            register
+        };
+        let vx = self.read_register(source);
 
         let mut most_significant = 0;
         if vx >= 128 {
@@ -467,6 +1147,7 @@ impl Cpu {
 
         let result = vx ^ vy;
         self.set_register(registerx, result);
+        self.reset_vf_after_logic();
         self.inc_pc();
     }
 
@@ -478,6 +1159,7 @@ impl Cpu {
 
         let result = vx | vy;
         self.set_register(registerx, result);
+        self.reset_vf_after_logic();
         self.inc_pc();
     }
 
@@ -489,103 +1171,182 @@ impl Cpu {
 
         let result = vx & vy;
         self.set_register(registerx, result);
+        self.reset_vf_after_logic();
         self.inc_pc();
     }
 
+    /// The COSMAC VIP left VF in an undefined state after logic operations, in practice 0
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.vf_reset {
+            self.set_register(0xF, 0);
+        }
+    }
+
     /// Fills gfx buffer with sprite data
-    ///
-    fn draw(&mut self) {
-        let x = self._x();
-        let start_x = self.read_register(x) as u16;
-        let y = self._y();
-        let start_y = self.read_register(y) as u16;
-        let number_of_lines = self.opcode & 0x000F;
-
-        let mut raster: [[bool; WIDTH]; HEIGTH] = [[false; 64]; 32];
+    /// DXY0 draws a 16x16 sprite made of two bytes per line
+    /// The start position always wraps, pixels beyond the edges are clipped or wrapped depending on the quirk
+    fn draw(&mut self, x: u8, y: u8, lines: u8) -> Result<(), CpuError> {
+        let width = self.gfx.width() as u16;
+        let height = self.gfx.height() as u16;
+        let start_x = self.read_register(x) as u16 % width;
+        let start_y = self.read_register(y) as u16 % height;
+        let (number_of_lines, bytes_per_line) = match lines as u16 {
+            0 => (16, 2),
+            n => (n, 1),
+        };
+
         self.set_register(0xF, 0);
-        for line in 0..number_of_lines {
-            let pixel = self.mem[(self.i + line) as usize];
-            for x_pos in 0..8 {
-                if (pixel >> (7 - x_pos)) & 1 == 1 {
-                    let x_in_raster = ((start_x + x_pos) % WIDTH as u16) as usize;
-                    let y_in_raster = ((line + start_y) % HEIGTH as u16) as usize;
-
-                    raster[y_in_raster][x_in_raster] = true;
-                    // need to flip the pixel
-                    // check if we need carry
-                    let offset = ((start_x as u16 + x_pos as u16) as u16)
-                        + (((line + start_y) % HEIGTH as u16) * WIDTH as u16);
-                    if offset >= self.gfx.len() as u16 {
-                        debug!(
-                            "Out of bounds {}, x: {}, y:{}: number of lines: {}",
-                            offset, x_in_raster, y_in_raster, number_of_lines
-                        );
-                        panic!();
-                    }
-                    if self.gfx[offset as usize] == 1 {
-                        self.set_register(0xF, 1);
+        // every selected plane reads its own copy of the sprite, one after the other
+        let sprite_size = number_of_lines * bytes_per_line;
+        let mut sprite_start = self.i;
+        for plane in 0..PLANE_COUNT {
+            let plane_mask = 1 << plane;
+            if self.planes & plane_mask == 0 {
+                continue;
+            }
+            for line in 0..number_of_lines {
+                for byte in 0..bytes_per_line {
+                    let address = sprite_start.wrapping_add(line * bytes_per_line + byte);
+                    let pixel = self.load_data(address)?;
+                    for bit in 0..8 {
+                        if (pixel >> (7 - bit)) & 1 == 1 {
+                            let x_pos = byte * 8 + bit;
+                            let clipped = start_x + x_pos >= width || line + start_y >= height;
+                            if clipped && self.quirks.clip_sprites {
+                                continue;
+                            }
+                            let x_in_raster = ((start_x + x_pos) % width) as usize;
+                            let y_in_raster = ((line + start_y) % height) as usize;
+
+                            // need to flip the pixel
+                            // check if we need carry
+                            if self.gfx.toggle(x_in_raster, y_in_raster, plane_mask) {
+                                self.set_register(0xF, 1);
+                            }
+                        }
                     }
-                    self.gfx[offset as usize] ^= 1;
                 }
             }
+            sprite_start = sprite_start.wrapping_add(sprite_size);
         }
 
-        // for (line_nr, line) in raster.iter().enumerate() {
-        //     print!("|");
-        //     for (col_nr, col) in line.iter().enumerate() {
-        //         if *col {
-        //             print!("*");
-        //         } else {
-        //             print!(".");
-        //         }
-        //     }
-        //     println!("|");
-        // }
-
         self.redraw = true;
+        self.wait_for_vblank = self.quirks.display_wait;
         self.inc_pc();
+        Ok(())
     }
 
     /// Sets I to the location of the sprite for the character in VX. Characters 0-F (in hexadecimal) are represented by a 4x5 font.
     /// FX29
-    fn set_index_register_to_character_sprite(&mut self, sprite: u8) {
-        let address_of_sprite = FONTSET_START + (sprite * 5) as usize;
+    fn set_index_register_to_character_sprite(&mut self, digit: u8) {
+        let address_of_sprite = FONTSET_START + (digit & 0x0F) as usize * 5;
+        self.set_index_register(address_of_sprite as u16);
+    }
+
+    /// Sets I to the 8x10 sprite for the digit in VX from the big SUPER-CHIP font
+    /// FX30
+    fn set_index_register_to_big_character_sprite(&mut self, digit: u8) {
+        let address_of_sprite = BIG_FONTSET_START + (digit & 0x0F) as usize * 10;
         self.set_index_register(address_of_sprite as u16);
     }
 
+    /// Stores V0 to VX in the RPL user flags of the HP-48
+    /// FX75
+    fn store_rpl_flags(&mut self, registerx: u8) {
+        for idx in 0..=registerx {
+            self.rpl_flags[idx as usize] = self.read_register(idx);
+        }
+        self.inc_pc();
+    }
+
+    /// Fills V0 to VX from the RPL user flags
+    /// FX85
+    fn load_rpl_flags(&mut self, registerx: u8) {
+        for idx in 0..=registerx {
+            let flag = self.rpl_flags[idx as usize];
+            self.set_register(idx, flag);
+        }
+        self.inc_pc();
+    }
+
+    /// Stores VX to VY in memory starting at address I, in reverse order if X is larger than Y.
+    /// I is left unmodified
+    /// 5XY2
+    fn store_register_range(&mut self, x: u8, y: u8) -> Result<(), CpuError> {
+        for (offset, register) in Cpu::register_range(x, y).enumerate() {
+            let content = self.read_register(register);
+            let address = self.i.wrapping_add(offset as u16);
+            self.store_data(address, content)?;
+        }
+        self.inc_pc();
+        Ok(())
+    }
+
+    /// Fills VX to VY from memory starting at address I, in reverse order if X is larger than Y.
+    /// I is left unmodified
+    /// 5XY3
+    fn fill_register_range(&mut self, x: u8, y: u8) -> Result<(), CpuError> {
+        for (offset, register) in Cpu::register_range(x, y).enumerate() {
+            let content = self.load_data(self.i.wrapping_add(offset as u16))?;
+            self.set_register(register, content);
+        }
+        self.inc_pc();
+        Ok(())
+    }
+
+    /// Registers from X to Y, both included, counting down if X is larger than Y
+    fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = u8>> {
+        if x <= y {
+            Box::new(x..=y)
+        } else {
+            Box::new((y..=x).rev())
+        }
+    }
+
     /// Fills V0 to VX (including VX) with values from memory starting at address I.
     /// The offset from I is increased by 1 for each value written, but I itself is left unmodified.
     /// FF65
-    fn fill_registers_up_to(&mut self, end_register: u8) {
+    fn fill_registers_up_to(&mut self, end_register: u8) -> Result<(), CpuError> {
         for idx in 0..=end_register {
-            let content = self.read_mem(self.i + idx as u16);
+            let content = self.load_data(self.i.wrapping_add(idx as u16))?;
             self.set_register(idx, content);
         }
-        let i = self.i;
-        self.set_index_register(i + end_register as u16 + 1u16);
-
-        // self.inc_pc();
+        if self.quirks.load_store_increments_i {
+            let i = self.i;
+            self.set_index_register(i.wrapping_add(end_register as u16 + 1));
+        } else {
+            self.inc_pc();
+        }
+        Ok(())
     }
 
     /// Stores V0 to VX (including VX) in memory starting at address I. The offset from I is increased by 1 for each value written, but I itself is left unmodified.
     /// FX55
-    fn store_registers_up_to(&mut self, registerx: u8) {
+    fn store_registers_up_to(&mut self, registerx: u8) -> Result<(), CpuError> {
         for idx in 0..=registerx {
             let register_content = self.read_register(idx);
-            let store_address = self.i + idx as u16;
-            self.mem[store_address as usize] = register_content;
+            let store_address = self.i.wrapping_add(idx as u16);
+            self.store_data(store_address, register_content)?;
         }
-        let i = self.i;
-        self.set_index_register(i + registerx as u16 + 1u16);
-        // self.inc_pc();
+        if self.quirks.load_store_increments_i {
+            let i = self.i;
+            self.set_index_register(i.wrapping_add(registerx as u16 + 1));
+        } else {
+            self.inc_pc();
+        }
+        Ok(())
     }
 
     /// Adds VX to I.[4]
     /// FX1E
     fn add_vx_to_i(&mut self, vx: u8) {
         let register_content = self.read_register(vx);
-        let result = self.i + register_content as u16;
-        self.set_index_register(result);
+        let result = self.i as usize + register_content as usize;
+        if self.quirks.add_to_i_sets_vf {
+            let overflow = if result >= self.mem.len() { 1 } else { 0 };
+            self.set_register(0xF, overflow);
+        }
+        self.set_index_register(result as u16);
         // self.inc_pc();
     }
 
@@ -593,19 +1354,19 @@ impl Cpu {
     /// with the most significant of three digits at the address in I,
     /// the middle digit at I plus 1, and the least significant digit at I plus 2
     /// FX33
-    fn store_bcd(&mut self, x: u8) {
-        self.mem[self.i as usize] = x / 100;
-        self.mem[(self.i + 1) as usize] = (x / 10) % 10;
-        self.mem[(self.i + 2) as usize] = (x % 100) % 10;
+    fn store_bcd(&mut self, x: u8) -> Result<(), CpuError> {
+        let i = self.i;
+        self.store_data(i, x / 100)?;
+        self.store_data(i.wrapping_add(1), (x / 10) % 10)?;
+        self.store_data(i.wrapping_add(2), (x % 100) % 10)?;
         self.inc_pc();
+        Ok(())
     }
 
     /// Sets VX to the result of a bitwise and operation on a random number (Typically: 0 to 255) and NN.
     /// CXNN
-    fn rand(&mut self) {
-        let x = self._x();
-        let nn = self._nn();
-        let random_nr: u8 = thread_rng().gen_range(0, 255);
+    fn rand(&mut self, x: u8, nn: u8) {
+        let random_nr = self.random.next_byte();
 
         self.set_register(x, random_nr & nn);
 
@@ -617,7 +1378,7 @@ impl Cpu {
     fn check_key_pressed(&mut self, register: u8) {
         let vx = self.read_register(register);
         if self.key[vx as usize] != 0 {
-            self.inc_pc();
+            self.skip_next_instruction();
         }
         debug!("Checking if key pressed: 0x{:X}", vx);
         self.inc_pc();
@@ -628,7 +1389,7 @@ impl Cpu {
     fn check_key_released(&mut self, register: u8) {
         let vx = self.read_register(register);
         if self.key[vx as usize] == 0 {
-            self.inc_pc();
+            self.skip_next_instruction();
         }
         debug!("Checking if key released: 0x{:X}", vx);
 
@@ -654,6 +1415,7 @@ impl Cpu {
 #[cfg(test)]
 mod tests {
     use cpu::*;
+    use random::Sequence;
     #[test]
     fn test_x_y() {
         let mut cpu = Cpu::default().init();
@@ -672,7 +1434,7 @@ mod tests {
     #[test]
     fn test_set_index_register() {
         let mut cpu = Cpu::default().init();
-        cpu.execute_opcode(0xA123);
+        cpu.execute_opcode(0xA123).unwrap();
 
         assert_eq!(cpu.i, 0x123);
     }
@@ -680,30 +1442,30 @@ mod tests {
     #[test]
     fn test_clear_screen() {
         let mut cpu = Cpu::default().init();
-        cpu.gfx = [1; 64 * 32];
-        assert_eq!(cpu.gfx[1], 1);
+        cpu.gfx.toggle(1, 0, 1);
+        assert_eq!(cpu.gfx.get(1, 0), 1);
 
-        cpu.execute_opcode(0x00E0);
+        cpu.execute_opcode(0x00E0).unwrap();
 
-        assert_eq!(cpu.gfx[1], 0);
+        assert_eq!(cpu.gfx.get(1, 0), 0);
     }
 
     //    #[test]
     //    fn test_00ee() {
     //        let mut cpu = Cpu::default().init();
-    //        cpu.execute_opcode(0x00EE);
+    //        cpu.execute_opcode(0x00EE).unwrap();
     //    }
 
     #[test]
     fn test_jump_to() {
         let mut cpu = Cpu::default().init();
-        cpu.execute_opcode(0x1FFF);
+        cpu.execute_opcode(0x1FFF).unwrap();
     }
 
     #[test]
     fn test_2nnn() {
         let mut cpu = Cpu::default().init();
-        cpu.execute_opcode(0x2123);
+        cpu.execute_opcode(0x2123).unwrap();
         assert_eq!(cpu.pc, 0x123);
     }
 
@@ -713,7 +1475,7 @@ mod tests {
         // set register 2 to 0xFF
         cpu.v[2] = 0xFF;
         // if v[2] == 0xFF
-        cpu.execute_opcode(0x32FF);
+        cpu.execute_opcode(0x32FF).unwrap();
         // skip 4, starting at 0x200
         assert_eq!(cpu.pc, 0x204);
         // set register 2 to 0xF0
@@ -721,7 +1483,7 @@ mod tests {
         cpu.v[2] = 0xF0;
         // reset pc
         cpu.pc = 0x200;
-        cpu.execute_opcode(0x32FF);
+        cpu.execute_opcode(0x32FF).unwrap();
         assert_eq!(cpu.pc, 0x202);
     }
 
@@ -731,7 +1493,7 @@ mod tests {
         // set register 2 to 0xFF
         cpu.v[2] = 0xFF;
         // if v[2] != 0xFF
-        cpu.execute_opcode(0x42FD);
+        cpu.execute_opcode(0x42FD).unwrap();
         // skip 4, starting at 0x200
         assert_eq!(cpu.pc, 0x204);
         // set register 2 to 0xF0
@@ -739,7 +1501,7 @@ mod tests {
         cpu.v[2] = 0xF0;
         // reset pc
         cpu.pc = 0x200;
-        cpu.execute_opcode(0x42F0);
+        cpu.execute_opcode(0x42F0).unwrap();
         assert_eq!(cpu.pc, 0x202);
     }
 
@@ -748,21 +1510,21 @@ mod tests {
         let mut cpu = Cpu::default().init();
         cpu.v[5] = 0xFF;
         cpu.v[6] = 0xFF;
-        cpu.execute_opcode(0x5560);
+        cpu.execute_opcode(0x5560).unwrap();
         assert_eq!(cpu.pc, 0x204);
     }
 
     #[test]
     fn test_6xnn() {
         let mut cpu = Cpu::default().init();
-        cpu.execute_opcode(0x62FF);
+        cpu.execute_opcode(0x62FF).unwrap();
         assert_eq!(cpu.v[2], 0xFF);
     }
 
     #[test]
     fn test_7xnn() {
         let mut cpu = Cpu::default().init();
-        cpu.execute_opcode(0x72FF);
+        cpu.execute_opcode(0x72FF).unwrap();
         assert_eq!(cpu.v[2], 0xFF);
     }
 
@@ -770,7 +1532,7 @@ mod tests {
     fn test_7xnn_overflow() {
         let mut cpu = Cpu::default().init();
         cpu.v[2] = 0xFF;
-        cpu.execute_opcode(0x7205);
+        cpu.execute_opcode(0x7205).unwrap();
         assert_eq!(cpu.v[2], 0x04);
     }
 
@@ -779,22 +1541,31 @@ mod tests {
         let mut cpu = Cpu::default().init();
         cpu.set_register(2, 0xFF);
         cpu.set_register(3, 0x0F);
-        cpu.execute_opcode(0x8235);
+        cpu.execute_opcode(0x8235).unwrap();
         assert_eq!(cpu.read_register(2), 0xF0);
         assert_eq!(cpu.pc, 0x202);
         cpu.set_register(2, 0x0F);
         cpu.set_register(3, 0xFF);
-        cpu.execute_opcode(0x8235);
+        cpu.execute_opcode(0x8235).unwrap();
         assert_eq!(cpu.read_register(0xF), 0);
     }
 
     #[test]
     fn test_set_idx_to_sprite() {
         let mut cpu = Cpu::default().init();
-        cpu.execute_opcode(0xF029);
+        cpu.execute_opcode(0xF029).unwrap();
         assert_eq!(cpu.i, FONTSET_START as u16);
-        cpu.execute_opcode(0xF129);
+        cpu.set_register(1, 1);
+        cpu.execute_opcode(0xF129).unwrap();
         assert_eq!(cpu.i, (FONTSET_START + 5) as u16);
+        // the digit comes from VX, not from X
+        cpu.set_register(3, 7);
+        cpu.execute_opcode(0xF329).unwrap();
+        assert_eq!(cpu.i, (FONTSET_START + 7 * 5) as u16);
+        // only the low nibble counts
+        cpu.set_register(3, 0x1A);
+        cpu.execute_opcode(0xF329).unwrap();
+        assert_eq!(cpu.i, (FONTSET_START + 0xA * 5) as u16);
     }
 
     #[test]
@@ -805,7 +1576,7 @@ mod tests {
         cpu.mem[2] = 0x2;
         cpu.mem[3] = 0x3;
         // fill registers 0 up to (including) 3
-        cpu.execute_opcode(0xF365);
+        cpu.execute_opcode(0xF365).unwrap();
         assert_eq!(cpu.read_register(0), 0xF);
         assert_eq!(cpu.read_register(1), 0x1);
         assert_eq!(cpu.read_register(2), 0x2);
@@ -817,10 +1588,10 @@ mod tests {
         let mut cpu = Cpu::default().init();
         // 0x94 == 148 dec
         cpu.set_register(0xE, 0x94);
-        cpu.execute_opcode(0xFE33);
-        assert_eq!(cpu.read_mem(cpu.i), 1);
-        assert_eq!(cpu.read_mem(cpu.i + 1), 4);
-        assert_eq!(cpu.read_mem(cpu.i + 2), 8);
+        cpu.execute_opcode(0xFE33).unwrap();
+        assert_eq!(cpu.read_mem(cpu.i).unwrap(), 1);
+        assert_eq!(cpu.read_mem(cpu.i + 1).unwrap(), 4);
+        assert_eq!(cpu.read_mem(cpu.i + 2).unwrap(), 8);
     }
 
     #[test]
@@ -829,13 +1600,668 @@ mod tests {
         cpu.set_register(0x1, 5);
         cpu.set_register(0x2, 5);
         // add 5 + 5 in register 0x1
-        cpu.execute_opcode(0x8124);
+        cpu.execute_opcode(0x8124).unwrap();
         assert_eq!(cpu.read_register(0x1), 10);
         assert_eq!(cpu.read_register(0xF), 0);
         cpu.set_register(0x1, 0xFF);
         cpu.set_register(0x2, 0x02);
-        cpu.execute_opcode(0x8124);
+        cpu.execute_opcode(0x8124).unwrap();
         assert_eq!(cpu.read_register(0xF), 1);
         assert_eq!(cpu.read_register(0x1), 1);
     }
+
+    #[test]
+    fn test_fx0a_blocks_until_key() {
+        let mut cpu = Cpu::default().init();
+        // FX0A at 0x200 followed by 6XNN
+        cpu.mem[0x200] = 0xF3;
+        cpu.mem[0x201] = 0x0A;
+        cpu.mem[0x202] = 0x61;
+        cpu.mem[0x203] = 0xFF;
+        for _ in 0..6 {
+            cpu.run_frame().unwrap();
+        }
+        assert!(cpu.is_waiting_for_key());
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.read_register(0x1), 0);
+    }
+
+    #[test]
+    fn test_fx0a_on_press() {
+        let mut cpu = Cpu::default().init().with_key_wait_mode(KeyWaitMode::Press);
+        cpu.execute_opcode(0xF30A).unwrap();
+        assert!(cpu.is_waiting_for_key());
+        cpu.handle_key_press(0xB);
+        assert!(!cpu.is_waiting_for_key());
+        assert_eq!(cpu.read_register(0x3), 0xB);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_fx0a_on_release() {
+        let mut cpu = Cpu::default()
+            .init()
+            .with_key_wait_mode(KeyWaitMode::Release);
+        // key held before the wait started must not complete it
+        cpu.handle_key_press(0x4);
+        cpu.execute_opcode(0xF30A).unwrap();
+        cpu.handle_key_release(0x4);
+        assert!(cpu.is_waiting_for_key());
+
+        cpu.handle_key_press(0x7);
+        assert!(cpu.is_waiting_for_key());
+        cpu.handle_key_release(0x7);
+        assert!(!cpu.is_waiting_for_key());
+        assert_eq!(cpu.read_register(0x3), 0x7);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_bnnn() {
+        let mut cpu = Cpu::default().init();
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        cpu.execute_opcode(0xB300).unwrap();
+        assert_eq!(cpu.pc, 0x310);
+    }
+
+    #[test]
+    fn test_bxnn_quirk() {
+        let quirks = Quirks {
+            jump_with_vx: true,
+            ..Quirks::default()
+        };
+        let mut cpu = Cpu::default().init().with_quirks(quirks);
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        cpu.execute_opcode(0xB300).unwrap();
+        assert_eq!(cpu.pc, 0x320);
+    }
+
+    #[test]
+    fn test_0nnn_ignored() {
+        let mut cpu = Cpu::default().init().with_machine_call(MachineCall::Ignore);
+        cpu.execute_opcode(0x0123).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_0nnn_trap() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let called = Rc::new(Cell::new(0));
+        let seen = called.clone();
+        let mut cpu = Cpu::default()
+            .init()
+            .with_machine_call(MachineCall::Trap(Box::new(move |address| {
+                seen.set(address)
+            })));
+        cpu.execute_opcode(0x0123).unwrap();
+        assert_eq!(called.get(), 0x123);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_unknown_opcode_halts() {
+        let mut cpu = Cpu::default().init();
+        cpu.mem[0x200] = 0xE1;
+        cpu.mem[0x201] = 0x00;
+        assert_eq!(
+            cpu.step(),
+            Err(CpuError::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0xE100
+            })
+        );
+        assert!(cpu.is_halted());
+        // stays halted
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut cpu = Cpu::default().init();
+        assert_eq!(
+            cpu.execute_opcode(0x00EE),
+            Err(CpuError::StackUnderflow { pc: 0x200 })
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut cpu = Cpu::default().init();
+        for _ in 0..16 {
+            cpu.execute_opcode(0x2200).unwrap();
+        }
+        assert_eq!(
+            cpu.execute_opcode(0x2200),
+            Err(CpuError::StackOverflow { pc: 0x200 })
+        );
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut cpu = Cpu::default().init();
+        cpu.i = 0xFFF;
+        assert_eq!(
+            cpu.execute_opcode(0xF133),
+            Err(CpuError::MemoryOutOfBounds { address: 0x1000 })
+        );
+    }
+
+    #[test]
+    fn test_rom_too_large() {
+        let mut cpu = Cpu::default().init();
+        assert!(cpu.load_bytes(&[0; 0xE00]).is_ok());
+        assert_eq!(
+            cpu.load_bytes(&[0; 0xE01]),
+            Err(CpuError::RomTooLarge {
+                size: 0xE01,
+                max: 0xE00,
+                start: 0x200,
+            })
+        );
+        assert_eq!(
+            cpu.load_bytes(&[0; 0xE01]).unwrap_err().to_string(),
+            "rom is 3585 bytes, but only 3584 bytes fit between 0x200 and 0xFFF"
+        );
+    }
+
+    #[test]
+    fn test_start_address() {
+        let mut cpu = Cpu::default().with_start_address(0x600).init();
+        cpu.load_bytes(&[0x60, 0x42]).unwrap();
+        assert_eq!(cpu.pc(), 0x600);
+        cpu.step().unwrap();
+        assert_eq!(cpu.v[0], 0x42);
+        assert!(cpu.load_bytes(&[0; 0xA01]).is_err());
+
+        let mut cpu = Cpu::default().with_start_address(0x1200).init();
+        assert_eq!(
+            cpu.load_bytes(&[0x60, 0x42]),
+            Err(CpuError::RomTooLarge {
+                size: 2,
+                max: 0,
+                start: 0x1200,
+            })
+        );
+        assert_eq!(
+            cpu.load_bytes(&[0x60, 0x42]).unwrap_err().to_string(),
+            "rom is 2 bytes, but memory ends before 0x1200"
+        );
+    }
+
+    #[test]
+    fn test_draw_wraps_around_edges() {
+        let mut cpu = Cpu::default().init();
+        // sprite for "0" at the bottom right corner
+        cpu.v[0] = 62;
+        cpu.v[1] = 30;
+        cpu.execute_opcode(0xD015).unwrap();
+        assert_eq!(cpu.gfx.get(62, 30), 1);
+        assert_eq!(cpu.gfx.get(1, 31), 1);
+        assert_eq!(cpu.gfx.get(1, 0), 1);
+    }
+
+    #[test]
+    fn test_timers_tick_at_60hz() {
+        // a rom that loops forever: 1200 jumps to itself
+        let mut cpu = Cpu::default().init().with_instructions_per_frame(30);
+        cpu.load_bytes(&[0x12, 0x00]).unwrap();
+        cpu.delay_timer = 255;
+        for _ in 0..60 {
+            cpu.run_frame().unwrap();
+        }
+        assert_eq!(cpu.delay_timer, 255 - 60);
+    }
+
+    #[test]
+    fn test_instructions_per_frame() {
+        // 7001 repeated, counts executed instructions in V0
+        let mut cpu = Cpu::default().init().with_instructions_per_frame(7);
+        cpu.load_bytes(&[0x70, 0x01].repeat(100)).unwrap();
+        cpu.run_frame().unwrap();
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.read_register(0), 14);
+    }
+
+    #[test]
+    fn test_shift_quirk() {
+        let mut cpu = Cpu::default().init();
+        cpu.v[1] = 0x04;
+        cpu.v[2] = 0x81;
+        cpu.execute_opcode(0x8126).unwrap();
+        assert_eq!(cpu.read_register(1), 0x02);
+        assert_eq!(cpu.read_register(0xF), 0);
+
+        let mut cpu = Cpu::default().init().with_quirks(Quirks::cosmac_vip());
+        cpu.v[1] = 0x04;
+        cpu.v[2] = 0x81;
+        cpu.execute_opcode(0x8126).unwrap();
+        assert_eq!(cpu.read_register(1), 0x40);
+        assert_eq!(cpu.read_register(0xF), 1);
+        cpu.execute_opcode(0x812E).unwrap();
+        assert_eq!(cpu.read_register(1), 0x02);
+        assert_eq!(cpu.read_register(0xF), 1);
+    }
+
+    #[test]
+    fn test_load_store_quirk() {
+        let mut cpu = Cpu::default().init().with_quirks(Quirks::super_chip());
+        cpu.i = 0x300;
+        cpu.execute_opcode(0xF255).unwrap();
+        assert_eq!(cpu.i, 0x300);
+        assert_eq!(cpu.pc, 0x202);
+
+        let mut cpu = Cpu::default().init().with_quirks(Quirks::cosmac_vip());
+        cpu.i = 0x300;
+        cpu.execute_opcode(0xF265).unwrap();
+        assert_eq!(cpu.i, 0x303);
+    }
+
+    #[test]
+    fn test_vf_reset_quirk() {
+        let mut cpu = Cpu::default().init().with_quirks(Quirks::cosmac_vip());
+        cpu.v[0xF] = 1;
+        cpu.execute_opcode(0x8121).unwrap();
+        assert_eq!(cpu.read_register(0xF), 0);
+
+        let mut cpu = Cpu::default().init().with_quirks(Quirks::chip48());
+        cpu.v[0xF] = 1;
+        cpu.execute_opcode(0x8121).unwrap();
+        assert_eq!(cpu.read_register(0xF), 1);
+    }
+
+    #[test]
+    fn test_clip_quirk() {
+        let mut cpu = Cpu::default().init().with_quirks(Quirks::cosmac_vip());
+        cpu.v[0] = 62;
+        cpu.v[1] = 30;
+        cpu.execute_opcode(0xD015).unwrap();
+        assert_eq!(cpu.gfx.get(62, 30), 1);
+        assert_eq!(cpu.gfx.get(1, 31), 0);
+        assert_eq!(cpu.gfx.get(1, 0), 0);
+    }
+
+    #[test]
+    fn test_display_wait_quirk() {
+        // D005 followed by 7001 ten times
+        let mut rom = vec![0xD0, 0x05];
+        rom.extend([0x70, 0x01].repeat(10));
+        let mut cpu = Cpu::default()
+            .init()
+            .with_quirks(Quirks::cosmac_vip())
+            .with_instructions_per_frame(10);
+        cpu.load_bytes(&rom).unwrap();
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.read_register(0), 10);
+    }
+
+    #[test]
+    fn test_add_to_i_quirk() {
+        let quirks = Quirks {
+            add_to_i_sets_vf: true,
+            ..Quirks::default()
+        };
+        let mut cpu = Cpu::default().init().with_quirks(quirks);
+        cpu.i = 0xFFF;
+        cpu.v[1] = 1;
+        cpu.execute_opcode(0xF11E).unwrap();
+        assert_eq!(cpu.read_register(0xF), 1);
+        cpu.i = 0x100;
+        cpu.execute_opcode(0xF11E).unwrap();
+        assert_eq!(cpu.read_register(0xF), 0);
+    }
+
+    #[test]
+    fn test_index_wraps_at_end_of_memory() {
+        let quirks = Quirks {
+            add_to_i_sets_vf: true,
+            ..Quirks::cosmac_vip()
+        };
+        let mut cpu = Cpu::default()
+            .init()
+            .with_quirks(quirks)
+            .with_extended_memory();
+        cpu.i = 0xFFFF;
+        cpu.v[0] = 1;
+        cpu.execute_opcode(0xF01E).unwrap();
+        assert_eq!(cpu.i, 0);
+        assert_eq!(cpu.read_register(0xF), 1);
+        // 64K memory only overflows past 0xFFFF
+        cpu.i = 0x1000;
+        cpu.execute_opcode(0xF01E).unwrap();
+        assert_eq!(cpu.read_register(0xF), 0);
+
+        cpu.i = 0xFFFF;
+        cpu.execute_opcode(0xF055).unwrap();
+        assert_eq!(cpu.i, 0);
+        cpu.i = 0xFFFF;
+        cpu.execute_opcode(0xF065).unwrap();
+        assert_eq!(cpu.i, 0);
+    }
+
+    #[test]
+    fn test_hires_switch() {
+        let mut cpu = Cpu::default().init();
+        cpu.execute_opcode(0x00FF).unwrap();
+        assert_eq!(cpu.framebuffer().width(), 128);
+        assert_eq!(cpu.framebuffer().height(), 64);
+        cpu.v[0] = 120;
+        cpu.v[1] = 60;
+        cpu.execute_opcode(0xD011).unwrap();
+        assert_eq!(cpu.gfx.get(120, 60), 1);
+        cpu.execute_opcode(0x00FE).unwrap();
+        assert_eq!(cpu.framebuffer().width(), 64);
+    }
+
+    #[test]
+    fn test_00cn_scroll_down() {
+        let mut cpu = Cpu::default().init();
+        cpu.gfx.toggle(3, 0, 1);
+        cpu.execute_opcode(0x00C2).unwrap();
+        assert_eq!(cpu.gfx.get(3, 0), 0);
+        assert_eq!(cpu.gfx.get(3, 2), 1);
+    }
+
+    #[test]
+    fn test_00fb_00fc_scroll() {
+        let mut cpu = Cpu::default().init();
+        cpu.gfx.toggle(3, 1, 1);
+        cpu.execute_opcode(0x00FB).unwrap();
+        assert_eq!(cpu.gfx.get(7, 1), 1);
+        cpu.execute_opcode(0x00FC).unwrap();
+        cpu.execute_opcode(0x00FC).unwrap();
+        assert_eq!(cpu.gfx.get(3, 1), 0);
+        assert_eq!(cpu.gfx.get(7, 1), 0);
+        assert!(cpu.gfx.pixels().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn test_00fd_exit() {
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&[0x00, 0xFD]).unwrap();
+        assert_eq!(cpu.step(), Ok(StepOutcome::Executed));
+        assert!(cpu.has_exited());
+        assert_eq!(cpu.step(), Ok(StepOutcome::Exited));
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn test_dxy0_big_sprite() {
+        let mut cpu = Cpu::default().init();
+        cpu.execute_opcode(0x00FF).unwrap();
+        cpu.i = 0x300;
+        for line in 0..16 {
+            cpu.mem[0x300 + line * 2] = 0x80;
+            cpu.mem[0x301 + line * 2] = 0x01;
+        }
+        cpu.execute_opcode(0xD010).unwrap();
+        assert_eq!(cpu.gfx.get(0, 0), 1);
+        assert_eq!(cpu.gfx.get(15, 15), 1);
+        assert_eq!(cpu.gfx.get(1, 0), 0);
+        assert_eq!(cpu.read_register(0xF), 0);
+        cpu.execute_opcode(0xD010).unwrap();
+        assert_eq!(cpu.read_register(0xF), 1);
+    }
+
+    #[test]
+    fn test_fx30_big_font() {
+        let mut cpu = Cpu::default().init();
+        cpu.v[2] = 3;
+        cpu.execute_opcode(0xF230).unwrap();
+        assert_eq!(cpu.i, (BIG_FONTSET_START + 30) as u16);
+        assert_eq!(cpu.read_mem(cpu.i).unwrap(), 0x3C);
+    }
+
+    #[test]
+    fn test_rpl_flags() {
+        let mut cpu = Cpu::default().init();
+        cpu.v[0] = 1;
+        cpu.v[1] = 2;
+        cpu.v[2] = 3;
+        cpu.execute_opcode(0xF275).unwrap();
+        cpu.v = [0; 16];
+        cpu.execute_opcode(0xF185).unwrap();
+        assert_eq!(cpu.read_register(0), 1);
+        assert_eq!(cpu.read_register(1), 2);
+        assert_eq!(cpu.read_register(2), 0);
+    }
+
+    #[test]
+    fn test_extended_memory() {
+        let mut cpu = Cpu::default().init();
+        assert!(cpu.load_bytes(&[0; 0x1000]).is_err());
+        let mut cpu = Cpu::default().init().with_extended_memory();
+        assert!(cpu.load_bytes(&[0; 0x1000]).is_ok());
+        assert_eq!(cpu.dump_memory().len(), 0x10000);
+    }
+
+    #[test]
+    fn test_f000_long_index() {
+        let mut cpu = Cpu::default().init().with_extended_memory();
+        cpu.load_bytes(&[0xF0, 0x00, 0xAB, 0xCD]).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.i, 0xABCD);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn test_skip_over_long_index() {
+        let mut cpu = Cpu::default().init().with_extended_memory();
+        // 3000 skips the four byte F000 NNNN
+        cpu.load_bytes(&[0x30, 0x00, 0xF0, 0x00, 0xAB, 0xCD])
+            .unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn test_5xy2_5xy3() {
+        let mut cpu = Cpu::default().init();
+        cpu.i = 0x300;
+        cpu.v[2] = 0x12;
+        cpu.v[3] = 0x13;
+        cpu.v[4] = 0x14;
+        cpu.execute_opcode(0x5242).unwrap();
+        assert_eq!(cpu.mem[0x300..0x303], [0x12, 0x13, 0x14]);
+        assert_eq!(cpu.i, 0x300);
+
+        cpu.execute_opcode(0x5753).unwrap();
+        assert_eq!(cpu.read_register(7), 0x12);
+        assert_eq!(cpu.read_register(6), 0x13);
+        assert_eq!(cpu.read_register(5), 0x14);
+    }
+
+    #[test]
+    fn test_fn01_bitplanes() {
+        let mut cpu = Cpu::default().init();
+        cpu.i = 0x300;
+        // one line for each plane
+        cpu.mem[0x300] = 0x80;
+        cpu.mem[0x301] = 0xC0;
+        cpu.execute_opcode(0xF301).unwrap();
+        cpu.execute_opcode(0xD001).unwrap();
+        assert_eq!(cpu.gfx.get(0, 0), 3);
+        assert_eq!(cpu.gfx.get(1, 0), 2);
+
+        cpu.execute_opcode(0xF201).unwrap();
+        cpu.execute_opcode(0x00E0).unwrap();
+        assert_eq!(cpu.gfx.get(0, 0), 1);
+        assert_eq!(cpu.gfx.get(1, 0), 0);
+    }
+
+    #[test]
+    fn test_00dn_scroll_up() {
+        let mut cpu = Cpu::default().init();
+        cpu.gfx.toggle(3, 5, 1);
+        cpu.execute_opcode(0x00D2).unwrap();
+        assert_eq!(cpu.gfx.get(3, 3), 1);
+        assert_eq!(cpu.gfx.get(3, 5), 0);
+    }
+
+    #[test]
+    fn test_f002_fx3a_audio() {
+        let mut cpu = Cpu::default().init();
+        assert_eq!(cpu.sound().pattern, None);
+        cpu.i = 0x300;
+        cpu.mem[0x300] = 0xAA;
+        cpu.mem[0x30F] = 0x55;
+        cpu.execute_opcode(0xF002).unwrap();
+        let pattern = cpu.sound().pattern.unwrap();
+        assert_eq!(pattern[0], 0xAA);
+        assert_eq!(pattern[15], 0x55);
+
+        cpu.v[4] = 112;
+        cpu.execute_opcode(0xF43A).unwrap();
+        assert_eq!(cpu.sound().pitch, 112);
+
+        assert!(!cpu.sound().playing);
+        cpu.v[1] = 2;
+        cpu.execute_opcode(0xF118).unwrap();
+        assert!(cpu.sound().playing);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&[0x12, 0x00]).unwrap();
+        cpu.v[3] = 0x42;
+        cpu.i = 0x321;
+        cpu.execute_opcode(0x2400).unwrap();
+        cpu.delay_timer = 9;
+        cpu.execute_opcode(0x00FF).unwrap();
+        cpu.gfx.toggle(7, 7, 1);
+        cpu.handle_key_press(0xA);
+        let state = cpu.save_state();
+
+        let mut restored = Cpu::default().init();
+        restored.load_bytes(&[0x12, 0x00]).unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.v, cpu.v);
+        assert_eq!(restored.i, 0x321);
+        assert_eq!(restored.pc, cpu.pc);
+        assert_eq!(restored.stack(), cpu.stack());
+        assert_eq!(restored.delay_timer, 9);
+        assert_eq!(restored.key[0xA], 1);
+        assert_eq!(restored.gfx, cpu.gfx);
+        assert!(restored.gfx.is_hires());
+        assert_eq!(restored.mem, cpu.mem);
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn test_load_state_for_other_rom() {
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&[0x12, 0x00]).unwrap();
+        let state = cpu.save_state();
+
+        let mut other = Cpu::default().init();
+        other.load_bytes(&[0x13, 0x00]).unwrap();
+        other.v[0] = 1;
+        match other.load_state(&state) {
+            Err(SaveStateError::RomMismatch { .. }) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(other.v[0], 1);
+    }
+
+    #[test]
+    fn test_load_corrupt_state() {
+        let corrupt = |cpu: &Cpu, reason| {
+            let mut restored = Cpu::default().init();
+            restored.load_bytes(&[0x12, 0x00]).unwrap();
+            match restored.load_state(&cpu.save_state()) {
+                Err(SaveStateError::Corrupt(found)) => assert_eq!(found, reason),
+                result => panic!("unexpected result {:?}", result),
+            }
+        };
+
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&[0x12, 0x00]).unwrap();
+        cpu.planes = 4;
+        corrupt(&cpu, "invalid planes");
+
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&[0x12, 0x00]).unwrap();
+        cpu.gfx.toggle(3, 3, 4);
+        corrupt(&cpu, "invalid display");
+
+        let mut cpu = Cpu::default().init().with_extended_memory();
+        cpu.load_bytes(&[0x12, 0x00]).unwrap();
+        corrupt(&cpu, "invalid memory size");
+    }
+
+    #[test]
+    fn test_rand_from_source() {
+        let mut cpu = Cpu::default()
+            .init()
+            .with_random_source(Sequence::new(&[0xFF, 0xA5]));
+        cpu.execute_opcode(0xC0FF).unwrap();
+        assert_eq!(cpu.v[0], 0xFF);
+        cpu.execute_opcode(0xC10F).unwrap();
+        assert_eq!(cpu.v[1], 0x05);
+
+        let mut first = Cpu::default().init().with_seed(7);
+        let mut second = Cpu::default().init().with_seed(7);
+        for _ in 0..20 {
+            first.execute_opcode(0xC2FF).unwrap();
+            second.execute_opcode(0xC2FF).unwrap();
+            assert_eq!(first.v[2], second.v[2]);
+        }
+    }
+
+    #[test]
+    fn test_save_state_keeps_random_numbers() {
+        let mut cpu = Cpu::default().init().with_seed(3);
+        cpu.load_bytes(&[0x12, 0x00]).unwrap();
+        cpu.execute_opcode(0xC0FF).unwrap();
+        let state = cpu.save_state();
+        cpu.execute_opcode(0xC0FF).unwrap();
+
+        let mut restored = Cpu::default().init();
+        restored.load_bytes(&[0x12, 0x00]).unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.seed(), 3);
+        restored.execute_opcode(0xC0FF).unwrap();
+        assert_eq!(restored.v[0], cpu.v[0]);
+
+        // the state of another source doesn't fit
+        let mut other = Cpu::default()
+            .init()
+            .with_random_source(Sequence::new(&[1]));
+        other.load_bytes(&[0x12, 0x00]).unwrap();
+        other.v[0] = 1;
+        assert!(other.load_state(&state).is_err());
+        assert_eq!(other.v[0], 1);
+    }
+
+    #[test]
+    fn test_memory_access_tracking() {
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&[0xF0, 0x33, 0xF0, 0x33]).unwrap();
+        cpu.i = 0x300;
+        cpu.v[0] = 123;
+        cpu.step().unwrap();
+        assert!(cpu.memory_accesses().is_empty());
+
+        cpu.track_memory_accesses(true);
+        cpu.step().unwrap();
+        let accesses = cpu.memory_accesses();
+        assert_eq!(accesses.len(), 3);
+        assert_eq!(
+            accesses[2],
+            MemoryAccess {
+                pc: 0x202,
+                opcode: 0xF033,
+                address: 0x302,
+                kind: AccessKind::Write,
+                value: 3,
+            }
+        );
+    }
 }
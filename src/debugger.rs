@@ -0,0 +1,579 @@
+use cpu::{AccessKind, Cpu, MemoryAccess, StepOutcome};
+use error::CpuError;
+use std::fmt;
+
+/// How a conditional breakpoint compares a register with its value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn matches(self, a: u8, b: u8) -> bool {
+        match self {
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        }
+    }
+}
+
+/// `V[register] comparison value`, e.g. V3 == 0x10
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub register: u8,
+    pub comparison: Comparison,
+    pub value: u8,
+}
+
+impl Condition {
+    pub fn matches(&self, cpu: &Cpu) -> bool {
+        self.comparison
+            .matches(cpu.registers()[self.register as usize], self.value)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "V{:X} {} 0x{:02X}",
+            self.register,
+            self.comparison.symbol(),
+            self.value
+        )
+    }
+}
+
+/// Stops execution before the instruction at `address` runs,
+/// if there is a condition only when it holds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:03X}", self.address)?;
+        if let Some(ref condition) = self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which accesses a watchpoint reacts to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Watch {
+    Read,
+    Write,
+    /// Reads and writes
+    Access,
+}
+
+/// Stops execution after an instruction read or wrote memory from `start` to `end`, both included
+/// Only data accesses count: FX55, FX65, FX33, 5XY2, 5XY3, F002 and the sprite reads of DXYN
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub watch: Watch,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind_matches = matches!(
+            (self.watch, access.kind),
+            (Watch::Access, _)
+                | (Watch::Read, AccessKind::Read)
+                | (Watch::Write, AccessKind::Write)
+        );
+        kind_matches && access.address >= self.start && access.address <= self.end
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let watch = match self.watch {
+            Watch::Read => "read",
+            Watch::Write => "write",
+            Watch::Access => "access",
+        };
+        if self.start == self.end {
+            write!(f, "{} 0x{:03X}", watch, self.start)
+        } else {
+            write!(f, "{} 0x{:03X}-0x{:03X}", watch, self.start, self.end)
+        }
+    }
+}
+
+/// Why the debugger paused execution
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// `pause` was called
+    Paused,
+    /// A single step finished
+    Step,
+    /// Step over or step out reached its target
+    StepDone,
+    Breakpoint(Breakpoint),
+    /// An instruction accessed watched memory, execution stops behind it
+    Watchpoint(Watchpoint, MemoryAccess),
+    Halted(CpuError),
+    Exited,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Paused => write!(f, "paused"),
+            StopReason::Step | StopReason::StepDone => write!(f, "stepped"),
+            StopReason::Breakpoint(ref breakpoint) => write!(f, "breakpoint {}", breakpoint),
+            StopReason::Watchpoint(ref watchpoint, ref access) => {
+                let (verb, preposition) = match access.kind {
+                    AccessKind::Read => ("read", "from"),
+                    AccessKind::Write => ("wrote", "to"),
+                };
+                write!(
+                    f,
+                    "watchpoint {}: {:04X} at 0x{:03X} {} 0x{:02X} {} 0x{:03X}",
+                    watchpoint,
+                    access.opcode,
+                    access.pc,
+                    verb,
+                    access.value,
+                    preposition,
+                    access.address
+                )
+            }
+            StopReason::Halted(ref err) => write!(f, "halted: {}", err),
+            StopReason::Exited => write!(f, "program exited"),
+        }
+    }
+}
+
+// where a running step over or step out stops
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    // behind the 2NNN being stepped over, with the stack as deep as before the call
+    Return { address: u16, depth: usize },
+    // once the current subroutine returned
+    Depth(usize),
+}
+
+/// Breakpoints and execution control on top of `Cpu::run_frame_until`
+/// While paused `run_frame` executes nothing and the timers stand still,
+/// a running step over or step out ends at its target or at the next breakpoint.
+/// A halt or exit of the program is reported in `last_stop` without pausing,
+/// the cpu stops by itself and a rewind or loaded state can continue right away
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    paused: bool,
+    target: Option<Target>,
+    // execution resumes at this address, its breakpoint must not stop it again
+    resume_from: Option<u16>,
+    last_stop: Option<StopReason>,
+}
+
+impl Debugger {
+    /// Stop whenever the instruction at `address` is about to run
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.add_conditional_breakpoint(address, None);
+    }
+
+    /// Stop at `address` if `condition` holds, replaces an existing breakpoint at `address`
+    pub fn add_conditional_breakpoint(&mut self, address: u16, condition: Option<Condition>) {
+        self.remove_breakpoint(address);
+        self.breakpoints.push(Breakpoint { address, condition });
+    }
+
+    /// Returns false if there was no breakpoint at `address`
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints
+            .retain(|breakpoint| breakpoint.address != address);
+        self.breakpoints.len() != count
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Stop behind instructions accessing memory from `start` to `end`,
+    /// replaces an existing watchpoint starting at `start`
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, watch: Watch) {
+        self.remove_watchpoint(start);
+        self.watchpoints.push(Watchpoint { start, end, watch });
+    }
+
+    /// Returns false if no watchpoint starts at `start`
+    pub fn remove_watchpoint(&mut self, start: u16) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.start != start);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Why execution stopped the last time
+    pub fn last_stop(&self) -> Option<&StopReason> {
+        self.last_stop.as_ref()
+    }
+
+    pub fn pause(&mut self) {
+        self.stop(StopReason::Paused);
+    }
+
+    /// Continue running until the next breakpoint
+    pub fn resume(&mut self, cpu: &Cpu) {
+        self.paused = false;
+        self.target = None;
+        self.resume_from = Some(cpu.pc());
+    }
+
+    /// Execute exactly one instruction and stay paused
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<StepOutcome, CpuError> {
+        cpu.track_memory_accesses(!self.watchpoints.is_empty());
+        let result = cpu.step();
+        let reason = Debugger::watchpoint_hit(&self.watchpoints, cpu).unwrap_or(StopReason::Step);
+        self.stop(reason);
+        match result {
+            Err(ref err) => self.last_stop = Some(StopReason::Halted(err.clone())),
+            Ok(StepOutcome::Exited) => self.last_stop = Some(StopReason::Exited),
+            Ok(_) => {}
+        }
+        result
+    }
+
+    /// Like `step`, but a 2NNN call runs until the subroutine returned
+    /// The call finishes over the following frames of `run_frame`
+    pub fn step_over(&mut self, cpu: &mut Cpu) -> Result<(), CpuError> {
+        match cpu.opcode_at(cpu.pc()) {
+            Some(opcode) if opcode & 0xF000 == 0x2000 => {
+                let target = Target::Return {
+                    address: cpu.pc().wrapping_add(2),
+                    depth: cpu.stack().len(),
+                };
+                self.resume(cpu);
+                self.target = Some(target);
+                Ok(())
+            }
+            _ => self.step(cpu).map(|_| ()),
+        }
+    }
+
+    /// Run until the current subroutine returned, returns false outside of a subroutine
+    pub fn step_out(&mut self, cpu: &Cpu) -> bool {
+        let depth = cpu.stack().len();
+        if depth == 0 {
+            return false;
+        }
+        self.resume(cpu);
+        self.target = Some(Target::Depth(depth - 1));
+        true
+    }
+
+    /// Run one frame of `cpu` unless paused, stopping at breakpoints and step targets.
+    /// Returns the reason if execution stopped during this frame
+    pub fn run_frame(&mut self, cpu: &mut Cpu) -> Result<Option<StopReason>, CpuError> {
+        if self.paused {
+            return Ok(None);
+        }
+
+        cpu.track_memory_accesses(!self.watchpoints.is_empty());
+        let resumed = self.resume_from;
+        let mut hit = None;
+        let result = {
+            let breakpoints = &self.breakpoints;
+            let watchpoints = &self.watchpoints;
+            let target = self.target;
+            let resume_from = &mut self.resume_from;
+            cpu.run_frame_until(|cpu| {
+                // FX0A keeps the pc on the breakpoint until its key arrives
+                let resuming = resume_from.take() == Some(cpu.pc())
+                    || (cpu.is_waiting_for_key() && resumed == Some(cpu.pc()));
+                let reached = match target {
+                    Some(Target::Return { address, depth }) => {
+                        cpu.pc() == address && cpu.stack().len() == depth
+                    }
+                    Some(Target::Depth(depth)) => cpu.stack().len() <= depth,
+                    None => false,
+                };
+                if reached {
+                    hit = Some(StopReason::StepDone);
+                    return true;
+                }
+                if resuming {
+                    return false;
+                }
+                // the accesses of the instruction that ran before this check
+                hit = Debugger::watchpoint_hit(watchpoints, cpu);
+                if hit.is_some() {
+                    return true;
+                }
+                hit = breakpoints
+                    .iter()
+                    .find(|breakpoint| {
+                        breakpoint.address == cpu.pc()
+                            && match breakpoint.condition {
+                                Some(condition) => condition.matches(cpu),
+                                None => true,
+                            }
+                    })
+                    .map(|breakpoint| StopReason::Breakpoint(*breakpoint));
+                hit.is_some()
+            })
+        };
+
+        match result {
+            Err(err) => {
+                self.target = None;
+                self.last_stop = Some(StopReason::Halted(err.clone()));
+                Err(err)
+            }
+            Ok(_) if cpu.has_exited() => {
+                self.target = None;
+                self.last_stop = Some(StopReason::Exited);
+                Ok(self.last_stop.clone())
+            }
+            Ok(_) => {
+                if let Some(ref reason) = hit {
+                    self.stop(reason.clone());
+                } else if cpu.is_waiting_for_key() && resumed == Some(cpu.pc()) {
+                    self.resume_from = resumed;
+                }
+                Ok(hit)
+            }
+        }
+    }
+
+    fn watchpoint_hit(watchpoints: &[Watchpoint], cpu: &Cpu) -> Option<StopReason> {
+        for access in cpu.memory_accesses() {
+            if let Some(watchpoint) = watchpoints.iter().find(|w| w.matches(access)) {
+                return Some(StopReason::Watchpoint(*watchpoint, *access));
+            }
+        }
+        None
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        self.paused = true;
+        self.target = None;
+        self.resume_from = None;
+        self.last_stop = Some(reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use debugger::*;
+
+    // 0x200: call 0x206, 0x202: V1 += 1, 0x204: jump 0x202
+    // 0x206: V0 += 1, 0x208: return
+    const PROGRAM: [u8; 10] = [0x22, 0x06, 0x71, 0x01, 0x12, 0x02, 0x70, 0x01, 0x00, 0xEE];
+
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&PROGRAM).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::default();
+        debugger.add_breakpoint(0x206);
+        let stop = debugger.run_frame(&mut cpu).unwrap();
+        assert_eq!(
+            stop,
+            Some(StopReason::Breakpoint(Breakpoint {
+                address: 0x206,
+                condition: None,
+            }))
+        );
+        assert_eq!(cpu.pc(), 0x206);
+        assert!(debugger.is_paused());
+        assert_eq!(debugger.run_frame(&mut cpu).unwrap(), None);
+        assert_eq!(cpu.pc(), 0x206);
+
+        // continuing does not stop at the same breakpoint right away
+        debugger.resume(&cpu);
+        assert_eq!(debugger.run_frame(&mut cpu).unwrap(), None);
+        assert_ne!(cpu.pc(), 0x206);
+    }
+
+    #[test]
+    fn test_continue_from_key_wait() {
+        let mut cpu = Cpu::default().init();
+        // 0x200: V0 = key, 0x202: V1 += 1, 0x204: jump 0x200
+        cpu.load_bytes(&[0xF0, 0x0A, 0x71, 0x01, 0x12, 0x00])
+            .unwrap();
+        let mut debugger = Debugger::default();
+        debugger.add_breakpoint(0x200);
+        assert!(debugger.run_frame(&mut cpu).unwrap().is_some());
+
+        debugger.resume(&cpu);
+        for _ in 0..3 {
+            assert_eq!(debugger.run_frame(&mut cpu).unwrap(), None);
+            assert!(cpu.is_waiting_for_key());
+        }
+        cpu.handle_key_press(0x5);
+        cpu.handle_key_release(0x5);
+        assert_eq!(cpu.pc(), 0x202);
+        // the loop comes back to the breakpoint
+        assert!(debugger.run_frame(&mut cpu).unwrap().is_some());
+        assert_eq!(cpu.pc(), 0x200);
+        assert_eq!(cpu.registers()[1], 1);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::default();
+        debugger.add_conditional_breakpoint(
+            0x204,
+            Some(Condition {
+                register: 1,
+                comparison: Comparison::Equal,
+                value: 3,
+            }),
+        );
+        debugger.run_frame(&mut cpu).unwrap();
+        assert_eq!(cpu.pc(), 0x204);
+        assert_eq!(cpu.registers()[1], 3);
+    }
+
+    #[test]
+    fn test_step_over_call() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::default();
+        debugger.pause();
+        debugger.step_over(&mut cpu).unwrap();
+        let stop = debugger.run_frame(&mut cpu).unwrap();
+        assert_eq!(stop, Some(StopReason::StepDone));
+        assert_eq!(cpu.pc(), 0x202);
+        assert_eq!(cpu.registers()[0], 1);
+        assert!(cpu.stack().is_empty());
+
+        // other instructions are single steps
+        debugger.step_over(&mut cpu).unwrap();
+        assert_eq!(cpu.pc(), 0x204);
+        assert_eq!(debugger.last_stop(), Some(&StopReason::Step));
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::default();
+        debugger.pause();
+        assert!(!debugger.step_out(&cpu));
+        debugger.step(&mut cpu).unwrap();
+        assert_eq!(cpu.pc(), 0x206);
+        assert!(debugger.step_out(&cpu));
+        debugger.run_frame(&mut cpu).unwrap();
+        assert_eq!(cpu.pc(), 0x202);
+        assert!(cpu.stack().is_empty());
+        assert!(debugger.is_paused());
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut cpu = Cpu::default().init();
+        // I := 0x300, V0 := 0x0A, V1 := 0x0B, store V0-V1, loop
+        cpu.load_bytes(&[0xA3, 0x00, 0x60, 0x0A, 0x61, 0x0B, 0xF1, 0x55, 0x12, 0x08])
+            .unwrap();
+        let mut debugger = Debugger::default();
+        debugger.add_watchpoint(0x301, 0x301, Watch::Write);
+        let stop = debugger.run_frame(&mut cpu).unwrap();
+        let access = MemoryAccess {
+            pc: 0x206,
+            opcode: 0xF155,
+            address: 0x301,
+            kind: AccessKind::Write,
+            value: 0x0B,
+        };
+        assert_eq!(
+            stop,
+            Some(StopReason::Watchpoint(
+                Watchpoint {
+                    start: 0x301,
+                    end: 0x301,
+                    watch: Watch::Write,
+                },
+                access
+            ))
+        );
+        assert_eq!(cpu.pc(), 0x208);
+        assert_eq!(
+            stop.unwrap().to_string(),
+            "watchpoint write 0x301: F155 at 0x206 wrote 0x0B to 0x301"
+        );
+
+        // reads don't trigger a write watchpoint
+        debugger.resume(&cpu);
+        debugger.add_watchpoint(0x000, 0x0FF, Watch::Write);
+        assert_eq!(debugger.run_frame(&mut cpu).unwrap(), None);
+    }
+
+    #[test]
+    fn test_watchpoint_on_sprite_read() {
+        let mut cpu = Cpu::default().init();
+        // I := 0x300, draw 2 lines at V0, V0
+        cpu.load_bytes(&[0xA3, 0x00, 0xD0, 0x02]).unwrap();
+        let mut debugger = Debugger::default();
+        debugger.add_watchpoint(0x301, 0x3FF, Watch::Read);
+        debugger.pause();
+        debugger.step(&mut cpu).unwrap();
+        assert_eq!(debugger.last_stop(), Some(&StopReason::Step));
+        debugger.step(&mut cpu).unwrap();
+        match debugger.last_stop() {
+            Some(&StopReason::Watchpoint(_, access)) => {
+                assert_eq!(access.address, 0x301);
+                assert_eq!(access.opcode, 0xD002);
+            }
+            stop => panic!("unexpected stop {:?}", stop),
+        }
+    }
+
+    #[test]
+    fn test_halt_is_reported() {
+        let mut cpu = Cpu::default().init();
+        cpu.load_bytes(&[0xFF, 0xFF]).unwrap();
+        let mut debugger = Debugger::default();
+        assert!(debugger.run_frame(&mut cpu).is_err());
+        match debugger.last_stop() {
+            Some(&StopReason::Halted(CpuError::UnknownOpcode { pc: 0x200, .. })) => {}
+            stop => panic!("unexpected stop {:?}", stop),
+        }
+        assert!(!debugger.is_paused());
+    }
+}
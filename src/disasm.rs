@@ -0,0 +1,301 @@
+use cpu::PROGRAM_START;
+use instruction::Instruction;
+
+/// Mnemonic flavour of the listing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Syntax {
+    /// The assembly notation of Cowgod's technical reference, e.g. `LD V1, #0A`
+    Cowgod,
+    /// Octo's high level notation, e.g. `v1 := 0x0A`
+    Octo,
+}
+
+/// One instruction, or one data word the decoder doesn't understand
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// None for data
+    pub instruction: Option<Instruction>,
+}
+
+impl Line {
+    /// The mnemonic of the instruction, or a data directive
+    pub fn text(&self, syntax: Syntax) -> String {
+        match self.instruction {
+            Some(Instruction::SetIndexLong) => {
+                let address = (self.bytes[2] as u16) << 8 | self.bytes[3] as u16;
+                match syntax {
+                    Syntax::Cowgod => format!("LD I, LONG #{:04X}", address),
+                    Syntax::Octo => format!("i := long 0x{:04X}", address),
+                }
+            }
+            Some(instruction) => mnemonic(instruction, syntax),
+            None => data(&self.bytes, syntax),
+        }
+    }
+
+    /// The full listing line with address and raw bytes
+    pub fn format(&self, syntax: Syntax) -> String {
+        format_line(self.address, &self.bytes, &self.text(syntax), syntax)
+    }
+}
+
+/// A listing line showing `text` next to the address and raw bytes
+pub fn format_line(address: u16, bytes: &[u8], text: &str, syntax: Syntax) -> String {
+    let raw = bytes
+        .chunks(2)
+        .map(|word| word.iter().map(|byte| format!("{:02X}", byte)).collect())
+        .collect::<Vec<String>>()
+        .join(" ");
+    match syntax {
+        Syntax::Cowgod => format!("{:04X}  {:<10} {}", address, raw, text),
+        // the address goes into a comment so the listing stays valid Octo
+        Syntax::Octo => format!("{:<24} # {:04X}  {}", text, address, raw),
+    }
+}
+
+/// Decode `rom` word by word as it would be loaded at 0x200
+/// Words that aren't instructions, like sprite data, become data lines
+pub fn decode(rom: &[u8]) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = (PROGRAM_START + offset) as u16;
+        if offset + 1 == rom.len() {
+            lines.push(Line {
+                address,
+                bytes: vec![rom[offset]],
+                instruction: None,
+            });
+            break;
+        }
+        let opcode = (rom[offset] as u16) << 8 | rom[offset + 1] as u16;
+        let instruction = match Instruction::decode(opcode) {
+            Instruction::Unknown(_) => None,
+            // the address word of F000 NNNN is cut off
+            Instruction::SetIndexLong if offset + 4 > rom.len() => None,
+            instruction => Some(instruction),
+        };
+        let size = instruction.map_or(2, |instruction| instruction.size() as usize);
+        lines.push(Line {
+            address,
+            bytes: rom[offset..offset + size].to_vec(),
+            instruction,
+        });
+        offset += size;
+    }
+    lines
+}
+
+/// A listing of the whole ROM, one line per instruction
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> String {
+    let mut listing = String::new();
+    for line in decode(rom) {
+        listing.push_str(&line.format(syntax));
+        listing.push('\n');
+    }
+    listing
+}
+
+/// Raw bytes as a data directive
+pub fn data(bytes: &[u8], syntax: Syntax) -> String {
+    match (syntax, bytes.len()) {
+        (Syntax::Cowgod, 2) => format!("DW #{:02X}{:02X}", bytes[0], bytes[1]),
+        (Syntax::Cowgod, _) => {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("#{:02X}", byte)).collect();
+            format!("DB {}", bytes.join(", "))
+        }
+        (Syntax::Octo, _) => bytes
+            .iter()
+            .map(|byte| format!("0x{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(" "),
+    }
+}
+
+/// The mnemonic of a single instruction
+/// F000 is printed without its address, which is only known from the following word
+pub fn mnemonic(instruction: Instruction, syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Cowgod => cowgod(instruction),
+        Syntax::Octo => octo(instruction),
+    }
+}
+
+fn cowgod(instruction: Instruction) -> String {
+    use instruction::Instruction::*;
+    match instruction {
+        ClearScreen => "CLS".to_string(),
+        Return => "RET".to_string(),
+        ScrollDown(n) => format!("SCD #{:X}", n),
+        ScrollUp(n) => format!("SCU #{:X}", n),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        LowRes => "LOW".to_string(),
+        HighRes => "HIGH".to_string(),
+        MachineCall(address) => format!("SYS #{:03X}", address),
+        Jump(address) => format!("JP #{:03X}", address),
+        Call(address) => format!("CALL #{:03X}", address),
+        SkipIfEqual(x, nn) => format!("SE V{:X}, #{:02X}", x, nn),
+        SkipIfNotEqual(x, nn) => format!("SNE V{:X}, #{:02X}", x, nn),
+        SkipIfRegistersEqual(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        StoreRange(x, y) => format!("LD [I], V{:X}-V{:X}", x, y),
+        LoadRange(x, y) => format!("LD V{:X}-V{:X}, [I]", x, y),
+        SetRegister(x, nn) => format!("LD V{:X}, #{:02X}", x, nn),
+        AddToRegister(x, nn) => format!("ADD V{:X}, #{:02X}", x, nn),
+        Copy(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Subtract(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        SubtractReversed(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        SkipIfRegistersNotEqual(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        SetIndex(address) => format!("LD I, #{:03X}", address),
+        JumpWithOffset(address) => format!("JP V0, #{:03X}", address),
+        Random(x, nn) => format!("RND V{:X}, #{:02X}", x, nn),
+        Draw(x, y, n) => format!("DRW V{:X}, V{:X}, #{:X}", x, y, n),
+        SkipIfKeyPressed(x) => format!("SKP V{:X}", x),
+        SkipIfKeyNotPressed(x) => format!("SKNP V{:X}", x),
+        SetIndexLong => "LD I, LONG".to_string(),
+        SelectPlanes(n) => format!("PLANE #{:X}", n),
+        LoadAudioPattern => "AUDIO".to_string(),
+        GetDelayTimer(x) => format!("LD V{:X}, DT", x),
+        WaitForKey(x) => format!("LD V{:X}, K", x),
+        SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+        SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+        AddToIndex(x) => format!("ADD I, V{:X}", x),
+        FontCharacter(x) => format!("LD F, V{:X}", x),
+        BigFontCharacter(x) => format!("LD HF, V{:X}", x),
+        StoreBcd(x) => format!("LD B, V{:X}", x),
+        SetPitch(x) => format!("LD PITCH, V{:X}", x),
+        StoreRegisters(x) => format!("LD [I], V{:X}", x),
+        LoadRegisters(x) => format!("LD V{:X}, [I]", x),
+        StoreFlags(x) => format!("LD R, V{:X}", x),
+        LoadFlags(x) => format!("LD V{:X}, R", x),
+        Unknown(opcode) => data(&[(opcode >> 8) as u8, opcode as u8], Syntax::Cowgod),
+    }
+}
+
+fn octo(instruction: Instruction) -> String {
+    use instruction::Instruction::*;
+    match instruction {
+        ClearScreen => "clear".to_string(),
+        Return => "return".to_string(),
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        LowRes => "lores".to_string(),
+        HighRes => "hires".to_string(),
+        // Octo has no mnemonic for machine code calls
+        MachineCall(address) => data(&[(address >> 8) as u8, address as u8], Syntax::Octo),
+        Jump(address) => format!("jump 0x{:03X}", address),
+        Call(address) => format!(":call 0x{:03X}", address),
+        // Octo conditions say when the next instruction runs, the opcodes when it is skipped
+        SkipIfEqual(x, nn) => format!("if v{:x} != 0x{:02X} then", x, nn),
+        SkipIfNotEqual(x, nn) => format!("if v{:x} == 0x{:02X} then", x, nn),
+        SkipIfRegistersEqual(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        StoreRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        SetRegister(x, nn) => format!("v{:x} := 0x{:02X}", x, nn),
+        AddToRegister(x, nn) => format!("v{:x} += 0x{:02X}", x, nn),
+        Copy(x, y) => format!("v{:x} := v{:x}", x, y),
+        Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        Add(x, y) => format!("v{:x} += v{:x}", x, y),
+        Subtract(x, y) => format!("v{:x} -= v{:x}", x, y),
+        ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        SubtractReversed(x, y) => format!("v{:x} =- v{:x}", x, y),
+        ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        SkipIfRegistersNotEqual(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        SetIndex(address) => format!("i := 0x{:03X}", address),
+        JumpWithOffset(address) => format!("jump0 0x{:03X}", address),
+        Random(x, nn) => format!("v{:x} := random 0x{:02X}", x, nn),
+        Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        SkipIfKeyPressed(x) => format!("if v{:x} -key then", x),
+        SkipIfKeyNotPressed(x) => format!("if v{:x} key then", x),
+        SetIndexLong => "i := long".to_string(),
+        SelectPlanes(n) => format!("plane {}", n),
+        LoadAudioPattern => "audio".to_string(),
+        GetDelayTimer(x) => format!("v{:x} := delay", x),
+        WaitForKey(x) => format!("v{:x} := key", x),
+        SetDelayTimer(x) => format!("delay := v{:x}", x),
+        SetSoundTimer(x) => format!("buzzer := v{:x}", x),
+        AddToIndex(x) => format!("i += v{:x}", x),
+        FontCharacter(x) => format!("i := hex v{:x}", x),
+        BigFontCharacter(x) => format!("i := bighex v{:x}", x),
+        StoreBcd(x) => format!("bcd v{:x}", x),
+        SetPitch(x) => format!("pitch := v{:x}", x),
+        StoreRegisters(x) => format!("save v{:x}", x),
+        LoadRegisters(x) => format!("load v{:x}", x),
+        StoreFlags(x) => format!("saveflags v{:x}", x),
+        LoadFlags(x) => format!("loadflags v{:x}", x),
+        Unknown(opcode) => data(&[(opcode >> 8) as u8, opcode as u8], Syntax::Octo),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use disasm::*;
+
+    // v1 := 0x0A, F000 0x0300, unknown word, odd trailing byte
+    const ROM: [u8; 9] = [0x61, 0x0A, 0xF0, 0x00, 0x03, 0x00, 0xFF, 0xFF, 0x80];
+
+    #[test]
+    fn test_decode() {
+        let lines = decode(&ROM);
+        let addresses: Vec<u16> = lines.iter().map(|line| line.address).collect();
+        assert_eq!(addresses, vec![0x200, 0x202, 0x206, 0x208]);
+        assert_eq!(
+            lines[0].instruction,
+            Some(Instruction::SetRegister(1, 0x0A))
+        );
+        assert_eq!(lines[1].bytes, vec![0xF0, 0x00, 0x03, 0x00]);
+        assert_eq!(lines[2].instruction, None);
+        assert_eq!(lines[3].bytes, vec![0x80]);
+    }
+
+    #[test]
+    fn test_cowgod_listing() {
+        assert_eq!(
+            disassemble(&ROM, Syntax::Cowgod),
+            "0200  610A       LD V1, #0A\n\
+             0202  F000 0300  LD I, LONG #0300\n\
+             0206  FFFF       DW #FFFF\n\
+             0208  80         DB #80\n"
+        );
+    }
+
+    #[test]
+    fn test_octo_listing() {
+        let listing = disassemble(&ROM, Syntax::Octo);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "v1 := 0x0A               # 0200  610A");
+        assert!(lines[1].starts_with("i := long 0x0300 "));
+        assert!(lines[2].starts_with("0xFF 0xFF "));
+        assert!(lines[3].starts_with("0x80 "));
+    }
+
+    #[test]
+    fn test_octo_conditions() {
+        assert_eq!(
+            mnemonic(Instruction::decode(0x3105), Syntax::Octo),
+            "if v1 != 0x05 then"
+        );
+        assert_eq!(
+            mnemonic(Instruction::decode(0xE2A1), Syntax::Octo),
+            "if v2 key then"
+        );
+        assert_eq!(
+            mnemonic(Instruction::decode(0xD01F), Syntax::Cowgod),
+            "DRW V0, V1, #F"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Reasons the cpu stops executing a program
+#[derive(Clone, Debug, PartialEq)]
+pub enum CpuError {
+    /// The opcode at `pc` is not part of the instruction set
+    UnknownOpcode { pc: u16, opcode: u16 },
+    /// 2NNN was executed with all 16 stack slots in use
+    StackOverflow { pc: u16 },
+    /// 00EE was executed with an empty stack
+    StackUnderflow { pc: u16 },
+    /// An instruction accessed memory outside of the address space
+    MemoryOutOfBounds { address: usize },
+    /// The rom does not fit into memory starting at `start`, usually 0x200
+    RomTooLarge { size: usize, max: usize, start: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode 0x{:04X} at 0x{:03X}", opcode, pc)
+            }
+            CpuError::StackOverflow { pc } => write!(f, "stack overflow at 0x{:03X}", pc),
+            CpuError::StackUnderflow { pc } => write!(f, "stack underflow at 0x{:03X}", pc),
+            CpuError::MemoryOutOfBounds { address } => {
+                write!(f, "memory access out of bounds at 0x{:X}", address)
+            }
+            CpuError::RomTooLarge {
+                size,
+                max: 0,
+                start,
+            } => write!(
+                f,
+                "rom is {} bytes, but memory ends before 0x{:03X}",
+                size, start
+            ),
+            CpuError::RomTooLarge { size, max, start } => write!(
+                f,
+                "rom is {} bytes, but only {} bytes fit between 0x{:03X} and 0x{:03X}",
+                size,
+                max,
+                start,
+                start as usize + max - 1
+            ),
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+/// Failure to load a rom from disk
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Cpu(CpuError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref err) => write!(f, "could not read rom: {}", err),
+            LoadError::Cpu(ref err) => write!(f, "could not load rom: {}", err),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        LoadError::Io(err)
+    }
+}
+
+impl From<CpuError> for LoadError {
+    fn from(err: CpuError) -> LoadError {
+        LoadError::Cpu(err)
+    }
+}
+
+/// Failure to restore a save state
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    /// The data does not start with the save state magic
+    NotASaveState,
+    /// The state was written by an incompatible version of the emulator
+    UnsupportedVersion(u16),
+    /// The data ends early or has trailing bytes
+    Truncated,
+    /// The checksum does not match, the file is damaged
+    ChecksumMismatch,
+    /// The state was saved while a different rom was loaded
+    RomMismatch {
+        expected: u64,
+        found: u64,
+    },
+    /// The checksum is fine but the contents are impossible
+    Corrupt(&'static str),
+    /// A movie is being recorded, which could not replay a jump to another state
+    Recording,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::Io(ref err) => write!(f, "could not access save state: {}", err),
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::ChecksumMismatch => write!(f, "save state checksum mismatch"),
+            SaveStateError::RomMismatch { expected, found } => write!(
+                f,
+                "save state belongs to a different rom (rom hash {:016X}, state was saved with {:016X})",
+                expected, found
+            ),
+            SaveStateError::Corrupt(reason) => write!(f, "save state is corrupt: {}", reason),
+            SaveStateError::Recording => write!(f, "can't load a state while recording a movie"),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(err: io::Error) -> SaveStateError {
+        SaveStateError::Io(err)
+    }
+}
+
+/// A mistake in assembly source, lines and columns count from 1
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+/// Failure to read or replay a movie
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    /// The movie file is malformed, lines count from 1
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// The movie was recorded with a different rom
+    RomMismatch {
+        expected: u64,
+        found: u64,
+    },
+    /// The replaying cpu is set up differently than the recording one
+    SettingMismatch {
+        setting: &'static str,
+        recorded: String,
+        found: String,
+    },
+    /// The replay ended in a different state than the recording
+    Desync {
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Io(ref err) => write!(f, "could not access movie: {}", err),
+            MovieError::Parse { line, ref message } => {
+                write!(f, "movie line {}: {}", line, message)
+            }
+            MovieError::RomMismatch { expected, found } => write!(
+                f,
+                "movie was recorded with a different rom (rom hash {:016X}, movie has {:016X})",
+                found, expected
+            ),
+            MovieError::SettingMismatch {
+                setting,
+                ref recorded,
+                ref found,
+            } => write!(
+                f,
+                "movie was recorded with {} {}, but this run uses {}",
+                setting, recorded, found
+            ),
+            MovieError::Desync { expected, found } => write!(
+                f,
+                "replay desynced, final state hash is {:016X} instead of {:016X}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> MovieError {
+        MovieError::Io(err)
+    }
+}
+
+/// Failure to read a keymap file
+#[derive(Debug)]
+pub enum KeymapError {
+    Io(io::Error),
+    /// The keymap file is malformed, lines count from 1
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeymapError::Io(ref err) => write!(f, "could not read keymap: {}", err),
+            KeymapError::Parse { line, ref message } => {
+                write!(f, "keymap line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl Error for KeymapError {}
+
+impl From<io::Error> for KeymapError {
+    fn from(err: io::Error) -> KeymapError {
+        KeymapError::Io(err)
+    }
+}
@@ -0,0 +1,177 @@
+/// Resolution of the original CHIP-8 display
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+/// Resolution of the SUPER-CHIP high resolution mode
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+/// XO-CHIP draws into two bitplanes, giving four colours
+pub const PLANE_COUNT: usize = 2;
+/// Mask selecting every bitplane
+pub const ALL_PLANES: u8 = 0b11;
+
+/// Display memory, one byte per pixel
+/// Each bit of a pixel belongs to one bitplane, so a pixel holds a colour index from 0 to 3.
+/// Classic programs only draw into the first plane and produce the colours 0 and 1.
+/// The resolution changes when the program switches between low and high resolution
+#[derive(Clone, Debug, PartialEq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer::new(LORES_WIDTH, LORES_HEIGHT)
+    }
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    /// Rebuild a framebuffer from saved pixels, `None` if the size is not a display mode
+    /// or a pixel uses other planes than the two bitplanes
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<u8>) -> Option<Framebuffer> {
+        let valid_size = (width, height) == (LORES_WIDTH, LORES_HEIGHT)
+            || (width, height) == (HIRES_WIDTH, HIRES_HEIGHT);
+        if !valid_size || pixels.len() != width * height || pixels.iter().any(|&pixel| pixel > 3) {
+            return None;
+        }
+        Some(Framebuffer {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    /// Row major colour indices
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// Colour index of the pixel at `x`, `y`
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[x + y * self.width]
+    }
+
+    /// Flip the pixel at `x`, `y` in the bitplanes selected by `planes`
+    /// Returns true if the pixel was lit in one of those planes before, which is a sprite collision
+    pub fn toggle(&mut self, x: usize, y: usize, planes: u8) -> bool {
+        let offset = x + y * self.width;
+        let was_set = self.pixels[offset] & planes != 0;
+        self.pixels[offset] ^= planes;
+        was_set
+    }
+
+    /// Clear the selected bitplanes
+    pub fn clear(&mut self, planes: u8) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= !planes;
+        }
+    }
+
+    /// Switch the resolution, which clears the screen
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![0; width * height];
+    }
+
+    /// Move the selected bitplanes down by `lines`, the top rows become dark
+    pub fn scroll_down(&mut self, lines: usize, planes: u8) {
+        self.shift(0, lines as isize, planes);
+    }
+
+    /// Move the selected bitplanes up by `lines`, the bottom rows become dark
+    pub fn scroll_up(&mut self, lines: usize, planes: u8) {
+        self.shift(0, -(lines as isize), planes);
+    }
+
+    /// Move the selected bitplanes right by `columns`, the leftmost columns become dark
+    pub fn scroll_right(&mut self, columns: usize, planes: u8) {
+        self.shift(columns as isize, 0, planes);
+    }
+
+    /// Move the selected bitplanes left by `columns`, the rightmost columns become dark
+    pub fn scroll_left(&mut self, columns: usize, planes: u8) {
+        self.shift(-(columns as isize), 0, planes);
+    }
+
+    /// Move the selected bitplanes by `dx`, `dy`, leaving the other planes in place
+    fn shift(&mut self, dx: isize, dy: isize, planes: u8) {
+        let source = self.pixels.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let from_x = x as isize - dx;
+                let from_y = y as isize - dy;
+                let inside = from_x >= 0
+                    && from_y >= 0
+                    && (from_x as usize) < self.width
+                    && (from_y as usize) < self.height;
+                let moved = if inside {
+                    source[from_x as usize + from_y as usize * self.width] & planes
+                } else {
+                    0
+                };
+                let offset = x + y * self.width;
+                self.pixels[offset] = (self.pixels[offset] & !planes) | moved;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use framebuffer::*;
+
+    #[test]
+    fn test_toggle_planes() {
+        let mut fb = Framebuffer::default();
+        assert!(!fb.toggle(1, 1, 0b01));
+        assert!(!fb.toggle(1, 1, 0b10));
+        assert_eq!(fb.get(1, 1), 3);
+        assert!(fb.toggle(1, 1, 0b10));
+        assert_eq!(fb.get(1, 1), 1);
+    }
+
+    #[test]
+    fn test_scroll_only_selected_planes() {
+        let mut fb = Framebuffer::default();
+        fb.toggle(0, 0, ALL_PLANES);
+        fb.scroll_down(1, 0b10);
+        assert_eq!(fb.get(0, 0), 1);
+        assert_eq!(fb.get(0, 1), 2);
+        fb.scroll_up(1, 0b10);
+        fb.scroll_right(2, ALL_PLANES);
+        assert_eq!(fb.get(0, 0), 0);
+        assert_eq!(fb.get(2, 0), 3);
+        fb.scroll_left(3, ALL_PLANES);
+        assert!(fb.pixels().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn test_clear_selected_planes() {
+        let mut fb = Framebuffer::default();
+        fb.toggle(5, 5, ALL_PLANES);
+        fb.clear(0b01);
+        assert_eq!(fb.get(5, 5), 2);
+    }
+}
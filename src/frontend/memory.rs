@@ -0,0 +1,67 @@
+use framebuffer::Framebuffer;
+use frontend::{AudioSink, Display, KeyEvent, Keypad};
+
+/// Remembers the last presented frame
+#[derive(Default)]
+pub struct MemoryDisplay {
+    frame: Option<Framebuffer>,
+    frames_presented: usize,
+}
+
+impl MemoryDisplay {
+    /// The most recently presented frame
+    pub fn frame(&self) -> Option<&Framebuffer> {
+        self.frame.as_ref()
+    }
+
+    pub fn frames_presented(&self) -> usize {
+        self.frames_presented
+    }
+}
+
+impl Display for MemoryDisplay {
+    fn present(&mut self, framebuffer: &Framebuffer) {
+        self.frame = Some(framebuffer.clone());
+        self.frames_presented += 1;
+    }
+}
+
+/// Key events queued by the caller, delivered on the next poll
+#[derive(Default)]
+pub struct MemoryKeypad {
+    pending: Vec<KeyEvent>,
+}
+
+impl MemoryKeypad {
+    pub fn press(&mut self, key: u8) {
+        self.pending.push(KeyEvent::Pressed(key));
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.pending.push(KeyEvent::Released(key));
+    }
+}
+
+impl Keypad for MemoryKeypad {
+    fn poll(&mut self) -> Vec<KeyEvent> {
+        self.pending.drain(..).collect()
+    }
+}
+
+/// Collects every queued sample
+#[derive(Default)]
+pub struct MemoryAudio {
+    samples: Vec<f32>,
+}
+
+impl MemoryAudio {
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+}
+
+impl AudioSink for MemoryAudio {
+    fn queue(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
@@ -0,0 +1,93 @@
+use framebuffer::Framebuffer;
+
+pub mod memory;
+#[cfg(feature = "piston")]
+pub mod piston;
+pub mod prompt;
+#[cfg(all(feature = "tui", unix))]
+pub mod terminal;
+pub mod text;
+
+// A frontend supplies a `Display`, a `Keypad` and an `AudioSink` and hands them to
+// `Machine::run_frame_with`, new frontends need no changes to the cpu.
+
+/// A change of state of one of the 16 hex keys
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyEvent {
+    Pressed(u8),
+    Released(u8),
+}
+
+/// Colours of the four values a pixel can take with two bitplanes, as RGB
+/// Classic programs only use the first two
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette(pub [[u8; 3]; 4]);
+
+/// Black and white, orange and grey for the XO-CHIP planes
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette([[0, 0, 0], [255, 255, 255], [255, 102, 0], [102, 102, 102]])
+    }
+}
+
+impl Palette {
+    /// Read two to four comma separated `RRGGBB` colours, e.g. `000000,33FF66`.
+    /// Colours that are left out keep their default
+    pub fn parse(text: &str) -> Option<Palette> {
+        let colours: Vec<&str> = text.split(',').map(str::trim).collect();
+        if colours.len() < 2 || colours.len() > 4 {
+            return None;
+        }
+        let mut palette = Palette::default();
+        for (entry, colour) in palette.0.iter_mut().zip(colours) {
+            let colour = colour.trim_start_matches('#');
+            let value = u32::from_str_radix(colour, 16)
+                .ok()
+                .filter(|_| colour.len() == 6)?;
+            *entry = [(value >> 16) as u8, (value >> 8) as u8, value as u8];
+        }
+        Some(palette)
+    }
+}
+
+/// Shows the framebuffer
+pub trait Display {
+    /// Called once after every emulated frame
+    fn present(&mut self, framebuffer: &Framebuffer);
+}
+
+/// Source of hex keypad input
+pub trait Keypad {
+    /// Key events that happened since the last poll, oldest first
+    fn poll(&mut self) -> Vec<KeyEvent>;
+}
+
+/// Consumer of the generated PCM samples
+pub trait AudioSink {
+    /// Called once after every emulated frame with the samples rendered for it
+    fn queue(&mut self, samples: &[f32]);
+}
+
+/// Audio sink for frontends without sound output
+#[derive(Default)]
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn queue(&mut self, _samples: &[f32]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use frontend::*;
+
+    #[test]
+    fn test_parse_palette() {
+        let palette = Palette::parse("#102030, 33ff66").unwrap();
+        assert_eq!(palette.0[0], [0x10, 0x20, 0x30]);
+        assert_eq!(palette.0[1], [0x33, 0xFF, 0x66]);
+        assert_eq!(palette.0[2], Palette::default().0[2]);
+        assert!(Palette::parse("000000").is_none());
+        assert!(Palette::parse("000000,FFF").is_none());
+        assert!(Palette::parse("000000,GGGGGG").is_none());
+    }
+}
@@ -0,0 +1,276 @@
+use framebuffer::Framebuffer;
+use frontend::{Display, KeyEvent, Keypad, Palette};
+use gamepad::{Gamepad, Hat, HostKeyEvent};
+use keymap::{HostKeyboard, Keymap};
+use piston_window::*;
+
+/// Largest size of an overlay pixel in window pixels
+const OVERLAY_PIXEL_SIZE: usize = 4;
+
+/// Keeps the last presented frame and draws it on piston render events
+#[derive(Default)]
+pub struct PistonDisplay {
+    frame: Framebuffer,
+    palette: Palette,
+}
+
+fn colour(palette: &Palette, value: u8) -> [f32; 4] {
+    let [red, green, blue] = palette.0[value as usize];
+    [
+        red as f32 / 255.0,
+        green as f32 / 255.0,
+        blue as f32 / 255.0,
+        1.0,
+    ]
+}
+
+impl PistonDisplay {
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    /// Draw the frame into a window that is `window_width` pixels wide
+    pub fn draw<G: Graphics>(&self, window_width: usize, c: &Context, g: &mut G) {
+        clear(colour(&self.palette, 0), g);
+        // 10 pixels per chip 8 pixel in low resolution, 5 in high resolution
+        let size = window_width / self.frame.width();
+        for y in 0..self.frame.height() {
+            for x in 0..self.frame.width() {
+                let value = self.frame.get(x, y);
+                if value != 0 {
+                    let d = [
+                        (x * size) as f64,
+                        (y * size) as f64,
+                        size as f64,
+                        size as f64,
+                    ];
+                    Rectangle::new(colour(&self.palette, value)).draw(
+                        d,
+                        &c.draw_state,
+                        c.transform,
+                        g,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Draw `overlay`, e.g. the keymap, centered on a dimmed frame
+    pub fn draw_overlay<G: Graphics>(
+        &self,
+        overlay: &Framebuffer,
+        window_width: usize,
+        window_height: usize,
+        c: &Context,
+        g: &mut G,
+    ) {
+        rectangle(
+            [0.0, 0.0, 0.0, 0.75],
+            [0.0, 0.0, window_width as f64, window_height as f64],
+            c.transform,
+            g,
+        );
+        let size = (window_width / overlay.width().max(1))
+            .min(window_height / overlay.height().max(1))
+            .clamp(1, OVERLAY_PIXEL_SIZE);
+        let left = (window_width - (overlay.width() * size).min(window_width)) / 2;
+        let top = (window_height - (overlay.height() * size).min(window_height)) / 2;
+        for y in 0..overlay.height() {
+            for x in 0..overlay.width() {
+                if overlay.get(x, y) != 0 {
+                    let d = [
+                        (left + x * size) as f64,
+                        (top + y * size) as f64,
+                        size as f64,
+                        size as f64,
+                    ];
+                    Rectangle::new(colour(&self.palette, 1)).draw(d, &c.draw_state, c.transform, g);
+                }
+            }
+        }
+    }
+}
+
+impl Display for PistonDisplay {
+    fn present(&mut self, framebuffer: &Framebuffer) {
+        if self.frame != *framebuffer {
+            self.frame.clone_from(framebuffer);
+        }
+    }
+}
+
+/// Collects keyboard and controller events from the piston event loop and maps them with a `Keymap`
+pub struct PistonKeypad {
+    keyboard: HostKeyboard,
+    gamepad: Gamepad,
+    pending: Vec<KeyEvent>,
+}
+
+impl Default for PistonKeypad {
+    fn default() -> PistonKeypad {
+        PistonKeypad::new(Keymap::default())
+    }
+}
+
+impl PistonKeypad {
+    pub fn new(keymap: Keymap) -> PistonKeypad {
+        PistonKeypad {
+            gamepad: Gamepad::new(keymap.deadzone()),
+            keyboard: HostKeyboard::new(keymap),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        self.keyboard.keymap()
+    }
+
+    /// Translate a piston event into hex keypad events
+    pub fn handle_event(&mut self, e: &Event) {
+        let mut host_events = Vec::new();
+        if let Some(button) = e.press_args() {
+            host_events.extend(self.button(button, true));
+        }
+        if let Some(button) = e.release_args() {
+            host_events.extend(self.button(button, false));
+        }
+        if let Some(args) = e.controller_axis_args() {
+            host_events.extend(self.gamepad.axis(args.id, args.axis, args.position));
+        }
+
+        for host_event in host_events {
+            let event = match host_event {
+                HostKeyEvent::Pressed(name) => self.keyboard.press(&name),
+                HostKeyEvent::Released(name) => self.keyboard.release(&name),
+            };
+            if let Some(event) = event {
+                self.pending.push(event);
+            }
+        }
+    }
+
+    fn button(&mut self, button: Button, pressed: bool) -> Vec<HostKeyEvent> {
+        match button {
+            Button::Keyboard(key) if pressed => vec![HostKeyEvent::Pressed(key_name(key))],
+            Button::Keyboard(key) => vec![HostKeyEvent::Released(key_name(key))],
+            Button::Controller(args) => self.gamepad.button(args.button, pressed),
+            // hats report their new direction, whether it's a press or not
+            Button::Hat(args) => self.gamepad.hat(args.id, args.which, hat(args.state)),
+            Button::Mouse(_) => Vec::new(),
+        }
+    }
+}
+
+fn hat(state: HatState) -> Hat {
+    match state {
+        HatState::Centered => Hat::Centered,
+        HatState::Up => Hat::Up,
+        HatState::Down => Hat::Down,
+        HatState::Left => Hat::Left,
+        HatState::Right => Hat::Right,
+        HatState::LeftUp => Hat::LeftUp,
+        HatState::RightUp => Hat::RightUp,
+        HatState::LeftDown => Hat::LeftDown,
+        HatState::RightDown => Hat::RightDown,
+    }
+}
+
+/// Name of `key` in keymaps, e.g. `Q`, `1`, `Up` or `NumPad1`
+pub fn key_name(key: Key) -> String {
+    let name = format!("{:?}", key);
+    // the number row is D0 to D9
+    match name.strip_prefix('D') {
+        Some(digit) if digit.len() == 1 && digit.as_bytes()[0].is_ascii_digit() => {
+            digit.to_string()
+        }
+        _ => name,
+    }
+}
+
+impl Keypad for PistonKeypad {
+    fn poll(&mut self) -> Vec<KeyEvent> {
+        self.pending.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use frontend::piston::*;
+
+    #[test]
+    fn test_key_names() {
+        assert_eq!(key_name(Key::D1), "1");
+        assert_eq!(key_name(Key::Q), "Q");
+        assert_eq!(key_name(Key::Up), "Up");
+        assert_eq!(key_name(Key::NumPad1), "NumPad1");
+        assert_eq!(key_name(Key::D), "D");
+    }
+
+    fn button(button: Button, state: ButtonState) -> Event {
+        Event::Input(Input::Button(ButtonArgs {
+            state,
+            button,
+            scancode: None,
+        }))
+    }
+
+    fn axis(axis: u8, position: f64) -> Event {
+        Event::Input(Input::Move(Motion::ControllerAxis(
+            ControllerAxisArgs::new(0, axis, position),
+        )))
+    }
+
+    #[test]
+    fn test_controller_events() {
+        let keymap = Keymap::parse(
+            "deadzone = 0.4\n[brix.ch8]\n4 = Q Axis0- HatLeft\n6 = E Axis0+ HatRight\n5 = W Button0\n",
+            "brix.ch8",
+        )
+        .unwrap();
+        let mut keypad = PistonKeypad::new(keymap);
+
+        keypad.handle_event(&axis(0, -0.2));
+        assert_eq!(keypad.poll(), vec![]);
+        keypad.handle_event(&axis(0, -0.8));
+        keypad.handle_event(&axis(0, 0.9));
+        keypad.handle_event(&axis(0, 0.1));
+        assert_eq!(
+            keypad.poll(),
+            vec![
+                KeyEvent::Pressed(0x4),
+                KeyEvent::Released(0x4),
+                KeyEvent::Pressed(0x6),
+                KeyEvent::Released(0x6),
+            ]
+        );
+
+        let controller = Button::Controller(ControllerButton::new(0, 0));
+        keypad.handle_event(&button(controller, ButtonState::Press));
+        keypad.handle_event(&button(controller, ButtonState::Release));
+        assert_eq!(
+            keypad.poll(),
+            vec![KeyEvent::Pressed(0x5), KeyEvent::Released(0x5)]
+        );
+
+        // the stick and the d-pad hold the same key
+        keypad.handle_event(&axis(0, -1.0));
+        let left = Button::Hat(ControllerHat::new(0, 0, HatState::Left));
+        keypad.handle_event(&button(left, ButtonState::Press));
+        keypad.handle_event(&axis(0, 0.0));
+        assert_eq!(keypad.poll(), vec![KeyEvent::Pressed(0x4)]);
+        let centered = Button::Hat(ControllerHat::new(0, 0, HatState::Centered));
+        keypad.handle_event(&button(centered, ButtonState::Release));
+        assert_eq!(keypad.poll(), vec![KeyEvent::Released(0x4)]);
+    }
+
+    #[test]
+    fn test_keypad_uses_keymap() {
+        let mut keypad = PistonKeypad::default();
+        keypad.handle_event(&button(Button::Keyboard(Key::Q), ButtonState::Press));
+        keypad.handle_event(&button(Button::Keyboard(Key::Q), ButtonState::Release));
+        assert_eq!(
+            keypad.poll(),
+            vec![KeyEvent::Pressed(0x4), KeyEvent::Released(0x4)]
+        );
+    }
+}
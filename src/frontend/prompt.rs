@@ -0,0 +1,355 @@
+use debugger::{Comparison, Condition, Watch};
+use machine::Machine;
+use std::io;
+use std::io::prelude::*;
+
+/// Frames `continue`, `next` and `finish` run before giving the prompt back,
+/// so a program that never reaches a breakpoint can't hang the debugger
+pub const CONTINUE_FRAME_LIMIT: u32 = 600;
+
+const HELP: &str = "\
+break ADDR [if VX OP NN]  set a breakpoint, OP is one of == != < <= > >=
+delete ADDR               remove the breakpoint at ADDR
+breakpoints               list breakpoints
+watch ADDR [LEN]          stop after writes to LEN bytes at ADDR, rwatch for reads,
+                          awatch for both
+unwatch ADDR              remove the watchpoint starting at ADDR
+watchpoints               list watchpoints
+step                      execute one instruction
+next                      like step, but run 2NNN calls to their return
+finish                    run until the current subroutine returns
+continue                  run until a breakpoint is hit
+registers                 show V0-VF, I, PC, the stack and the timers
+memory ADDR [LEN]         show LEN bytes of memory at ADDR
+press K / release K       press or release hex key K
+quit                      leave the debugger
+numbers are hexadecimal, with or without 0x";
+
+/// A debugger command as typed at the prompt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Break(u16, Option<Condition>),
+    Delete(u16),
+    Breakpoints,
+    Watch(u16, u16, Watch),
+    Unwatch(u16),
+    Watchpoints,
+    Step,
+    Next,
+    Finish,
+    Continue,
+    Registers,
+    Memory(u16, u16),
+    Press(u8),
+    Release(u8),
+    Help,
+    Quit,
+}
+
+/// Parse one line of input, short forms like `b`, `s`, `n` and `c` work as in gdb
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let argument = |index: usize| -> Result<u16, String> {
+        words
+            .get(index)
+            .ok_or_else(|| format!("{} needs an argument", words[0]))
+            .and_then(|word| parse_number(word))
+    };
+
+    let command = match words.first() {
+        None => return Err("empty command".to_string()),
+        Some(&word) => word,
+    };
+    let parsed = match command {
+        "b" | "break" => {
+            let condition = match words.len() {
+                2 => None,
+                6 if words[2] == "if" => Some(parse_condition(&words[3..])?),
+                _ => return Err("usage: break ADDR [if VX OP NN]".to_string()),
+            };
+            Command::Break(argument(1)?, condition)
+        }
+        "d" | "delete" => Command::Delete(argument(1)?),
+        "bl" | "breakpoints" => Command::Breakpoints,
+        "watch" | "rwatch" | "awatch" => {
+            let watch = match command {
+                "watch" => Watch::Write,
+                "rwatch" => Watch::Read,
+                _ => Watch::Access,
+            };
+            let length = if words.len() > 2 { argument(2)? } else { 1 };
+            if length == 0 {
+                return Err("can't watch 0 bytes".to_string());
+            }
+            let start = argument(1)?;
+            Command::Watch(start, start.saturating_add(length - 1), watch)
+        }
+        "unwatch" => Command::Unwatch(argument(1)?),
+        "wl" | "watchpoints" => Command::Watchpoints,
+        "s" | "step" => Command::Step,
+        "n" | "next" => Command::Next,
+        "f" | "finish" => Command::Finish,
+        "c" | "continue" => Command::Continue,
+        "r" | "registers" => Command::Registers,
+        "x" | "memory" => {
+            let length = if words.len() > 2 { argument(2)? } else { 16 };
+            Command::Memory(argument(1)?, length)
+        }
+        "press" => Command::Press(parse_key(argument(1)?)?),
+        "release" => Command::Release(parse_key(argument(1)?)?),
+        "h" | "help" => Command::Help,
+        "q" | "quit" => Command::Quit,
+        _ => return Err(format!("unknown command '{}', try help", command)),
+    };
+    Ok(parsed)
+}
+
+fn parse_number(word: &str) -> Result<u16, String> {
+    let digits = word.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex number", word))
+}
+
+fn parse_key(key: u16) -> Result<u8, String> {
+    if key < 16 {
+        Ok(key as u8)
+    } else {
+        Err(format!("there is no key {:X}", key))
+    }
+}
+
+fn parse_condition(words: &[&str]) -> Result<Condition, String> {
+    let register = match words[0].to_uppercase().trim_start_matches('V') {
+        digit if digit.len() == 1 => parse_number(digit)? as u8,
+        _ => return Err(format!("'{}' is not a register", words[0])),
+    };
+    let comparison = match words[1] {
+        "==" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessOrEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        other => return Err(format!("unknown comparison '{}'", other)),
+    };
+    let value = parse_number(words[2])?;
+    if value > 0xFF {
+        return Err(format!("{:X} does not fit into a register", value));
+    }
+    Ok(Condition {
+        register,
+        comparison,
+        value: value as u8,
+    })
+}
+
+/// Execute `command` against `machine` and describe the result on `out`
+/// Returns false once the user wants to quit
+pub fn execute<W: Write>(machine: &mut Machine, command: Command, out: &mut W) -> io::Result<bool> {
+    match command {
+        Command::Break(address, condition) => {
+            machine
+                .debugger_mut()
+                .add_conditional_breakpoint(address, condition);
+            writeln!(out, "breakpoint at 0x{:03X}", address)?;
+        }
+        Command::Delete(address) => {
+            if !machine.debugger_mut().remove_breakpoint(address) {
+                writeln!(out, "no breakpoint at 0x{:03X}", address)?;
+            }
+        }
+        Command::Breakpoints => {
+            for breakpoint in machine.debugger().breakpoints() {
+                writeln!(out, "{}", breakpoint)?;
+            }
+        }
+        Command::Watch(start, end, watch) => {
+            machine.debugger_mut().add_watchpoint(start, end, watch);
+            let watchpoint = machine.debugger().watchpoints().last().cloned();
+            if let Some(watchpoint) = watchpoint {
+                writeln!(out, "watchpoint {}", watchpoint)?;
+            }
+        }
+        Command::Unwatch(start) => {
+            if !machine.debugger_mut().remove_watchpoint(start) {
+                writeln!(out, "no watchpoint at 0x{:03X}", start)?;
+            }
+        }
+        Command::Watchpoints => {
+            for watchpoint in machine.debugger().watchpoints() {
+                writeln!(out, "{}", watchpoint)?;
+            }
+        }
+        Command::Step => {
+            // a halt is reported by `where_stopped`
+            let _ = machine.step();
+            where_stopped(machine, out)?;
+        }
+        Command::Next => {
+            let _ = machine.step_over();
+            run_until_stopped(machine, out)?;
+        }
+        Command::Finish => {
+            if machine.step_out() {
+                run_until_stopped(machine, out)?;
+            } else {
+                writeln!(out, "not in a subroutine")?;
+            }
+        }
+        Command::Continue => {
+            machine.resume();
+            run_until_stopped(machine, out)?;
+        }
+        Command::Registers => write_registers(machine, out)?,
+        Command::Memory(address, length) => {
+            let memory = machine.cpu().dump_memory();
+            let start = (address as usize).min(memory.len());
+            let end = (start + length as usize).min(memory.len());
+            for (row, bytes) in memory[start..end].chunks(16).enumerate() {
+                write!(out, "{:04X}:", start + row * 16)?;
+                for byte in bytes {
+                    write!(out, " {:02X}", byte)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        Command::Press(key) => machine.handle_key_press(key),
+        Command::Release(key) => machine.handle_key_release(key),
+        Command::Help => writeln!(out, "{}", HELP)?,
+        Command::Quit => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Read commands from `input` until it ends or `quit` is entered
+/// The machine is paused first, so nothing runs before the first command
+pub fn run<R: BufRead, W: Write>(machine: &mut Machine, input: R, mut out: W) -> io::Result<()> {
+    machine.pause();
+    where_stopped(machine, &mut out)?;
+    write!(out, "(chip8) ")?;
+    out.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            match parse_command(&line) {
+                Ok(command) => {
+                    if !execute(machine, command, &mut out)? {
+                        return Ok(());
+                    }
+                }
+                Err(message) => writeln!(out, "{}", message)?,
+            }
+        }
+        write!(out, "(chip8) ")?;
+        out.flush()?;
+    }
+    writeln!(out)
+}
+
+fn run_until_stopped<W: Write>(machine: &mut Machine, out: &mut W) -> io::Result<()> {
+    let mut frames = 0;
+    while !machine.debugger().is_paused()
+        && !machine.cpu().is_halted()
+        && !machine.cpu().has_exited()
+    {
+        if frames == CONTINUE_FRAME_LIMIT {
+            machine.pause();
+            writeln!(out, "still running after {} frames", frames)?;
+            break;
+        }
+        // a halt is reported by `where_stopped`
+        let _ = machine.run_frame();
+        frames += 1;
+    }
+    where_stopped(machine, out)
+}
+
+fn where_stopped<W: Write>(machine: &Machine, out: &mut W) -> io::Result<()> {
+    let cpu = machine.cpu();
+    if let Some(err) = cpu.halted() {
+        return writeln!(out, "halted: {}", err);
+    }
+    if cpu.has_exited() {
+        return writeln!(out, "program exited");
+    }
+    if let Some(reason) = machine.debugger().last_stop() {
+        write!(out, "{}, ", reason)?;
+    }
+    match cpu.opcode_at(cpu.pc()) {
+        Some(opcode) => writeln!(out, "pc 0x{:03X}: {:04X}", cpu.pc(), opcode),
+        None => writeln!(out, "pc 0x{:03X}", cpu.pc()),
+    }
+}
+
+fn write_registers<W: Write>(machine: &Machine, out: &mut W) -> io::Result<()> {
+    let cpu = machine.cpu();
+    for (register, value) in cpu.registers().iter().enumerate() {
+        write!(out, "V{:X} {:02X}", register, value)?;
+        if register % 8 == 7 {
+            writeln!(out)?;
+        } else {
+            write!(out, "  ")?;
+        }
+    }
+    writeln!(
+        out,
+        "PC {:03X}  I {:03X}  DT {:02X}  ST {:02X}",
+        cpu.pc(),
+        cpu.i(),
+        cpu.delay_timer(),
+        cpu.sound_timer()
+    )?;
+    write!(out, "stack:")?;
+    for address in cpu.stack() {
+        write!(out, " {:03X}", address)?;
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use frontend::prompt::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse_command("b 0x206"), Ok(Command::Break(0x206, None)));
+        assert_eq!(
+            parse_command("break 204 if v1 >= 3"),
+            Ok(Command::Break(
+                0x204,
+                Some(Condition {
+                    register: 1,
+                    comparison: Comparison::GreaterOrEqual,
+                    value: 3,
+                })
+            ))
+        );
+        assert_eq!(parse_command("x 200"), Ok(Command::Memory(0x200, 16)));
+        assert_eq!(
+            parse_command("rwatch 300 10"),
+            Ok(Command::Watch(0x300, 0x30F, Watch::Read))
+        );
+        assert!(parse_command("break").is_err());
+        assert!(parse_command("press 10").is_err());
+        assert!(parse_command("jump").is_err());
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let mut machine = Machine::default();
+        // call 0x206, V1 += 1, jump 0x202, V0 += 1, return
+        machine
+            .load_bytes(&[0x22, 0x06, 0x71, 0x01, 0x12, 0x02, 0x70, 0x01, 0x00, 0xEE])
+            .unwrap();
+        let script = "next\nbreak 204 if V1 == 2\ncontinue\nregisters\nquit\nstep\n";
+        let mut out = Vec::new();
+        run(&mut machine, script.as_bytes(), &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("paused, pc 0x200: 2206"));
+        assert!(out.contains("stepped, pc 0x202: 7101"));
+        assert!(out.contains("breakpoint 0x204 if V1 == 0x02, pc 0x204: 1202"));
+        assert!(out.contains("V0 01  V1 02"));
+        // nothing runs after quit
+        assert_eq!(machine.cpu().pc(), 0x204);
+    }
+}
@@ -0,0 +1,406 @@
+use cpu::Cpu;
+use error::CpuError;
+use framebuffer::Framebuffer;
+use frontend::{Display, KeyEvent, Keypad, NullAudio};
+use keymap::Keymap;
+use libc;
+use machine::Machine;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Terminals only report key presses, a key counts as released when it
+/// has not been repeated for this many frames
+const KEY_HOLD_FRAMES: u32 = 8;
+/// ESC ends the session, like in the window frontend
+const QUIT_KEY: u8 = 0x1B;
+const FRAME_DURATION_NANOS: u64 = 1_000_000_000 / 60;
+
+/// Foreground and background SGR codes for the palette: black, white, orange and grey
+const FOREGROUND: [u8; 4] = [30, 37, 33, 90];
+const BACKGROUND: [u8; 4] = [40, 47, 43, 100];
+
+/// Puts the terminal into raw, non blocking mode and restores it when dropped
+pub struct RawTerminal {
+    original: libc::termios,
+    original_flags: libc::c_int,
+}
+
+impl RawTerminal {
+    pub fn enable() -> io::Result<RawTerminal> {
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            // keep translating \n to \r\n on output
+            raw.c_oflag = original.c_oflag;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original_flags = libc::fcntl(libc::STDIN_FILENO, libc::F_GETFL);
+            libc::fcntl(
+                libc::STDIN_FILENO,
+                libc::F_SETFL,
+                original_flags | libc::O_NONBLOCK,
+            );
+
+            // alternate screen, hidden cursor
+            print!("\x1b[?1049h\x1b[?25l");
+            io::stdout().flush()?;
+            Ok(RawTerminal {
+                original,
+                original_flags,
+            })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, self.original_flags);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Hex keypad read from raw terminal input, with emulated key releases
+#[derive(Default)]
+pub struct TerminalKeypad {
+    keymap: Keymap,
+    // frames left until a held key counts as released
+    held: [u32; 16],
+    quit: bool,
+}
+
+impl TerminalKeypad {
+    pub fn new(keymap: Keymap) -> TerminalKeypad {
+        TerminalKeypad {
+            keymap,
+            ..TerminalKeypad::default()
+        }
+    }
+
+    /// True once ESC was pressed on its own, not as the start of an escape sequence
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    /// Turn the bytes typed since the last frame into key events
+    pub fn handle_input(&mut self, input: &[u8]) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        let mut repeated = [false; 16];
+        let (names, quit) = key_names(input);
+        self.quit |= quit;
+        for name in names {
+            if let Some(key) = self.keymap.key_for(&name) {
+                if self.held[key as usize] == 0 {
+                    events.push(KeyEvent::Pressed(key));
+                }
+                self.held[key as usize] = KEY_HOLD_FRAMES;
+                repeated[key as usize] = true;
+            }
+        }
+
+        for (key, frames) in self.held.iter_mut().enumerate() {
+            if *frames > 0 && !repeated[key] {
+                *frames -= 1;
+                if *frames == 0 {
+                    events.push(KeyEvent::Released(key as u8));
+                }
+            }
+        }
+        events
+    }
+
+    fn read_stdin() -> Vec<u8> {
+        let mut input = Vec::new();
+        let mut buffer = [0; 64];
+        loop {
+            let read = unsafe {
+                libc::read(
+                    libc::STDIN_FILENO,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if read <= 0 {
+                break;
+            }
+            input.extend_from_slice(&buffer[..read as usize]);
+        }
+        input
+    }
+}
+
+/// Names of the keys in `input` as used in keymaps, and whether a lone ESC asked to quit.
+/// Arrow keys arrive as CSI (`ESC [ A`) or SS3 (`ESC O A`) sequences and are named
+/// `Up`, `Down`, `Left` and `Right`, other sequences such as function keys are dropped
+fn key_names(input: &[u8]) -> (Vec<String>, bool) {
+    let mut names = Vec::new();
+    let mut quit = false;
+    let mut index = 0;
+    while index < input.len() {
+        let byte = input[index];
+        index += 1;
+        if byte != QUIT_KEY {
+            names.extend(key_name(byte));
+            continue;
+        }
+        let final_byte = match input.get(index) {
+            // nothing else arrived with it, so it was typed on its own
+            None => {
+                quit = true;
+                break;
+            }
+            Some(b'[') => {
+                // parameter and intermediate bytes, then the final byte
+                index += 1;
+                while input
+                    .get(index)
+                    .is_some_and(|&byte| (0x20..0x40).contains(&byte))
+                {
+                    index += 1;
+                }
+                input.get(index)
+            }
+            Some(b'O') => {
+                index += 1;
+                input.get(index)
+            }
+            // alt + key, the key follows as usual
+            Some(_) => continue,
+        };
+        index += 1;
+        let name = match final_byte {
+            Some(b'A') => "Up",
+            Some(b'B') => "Down",
+            Some(b'C') => "Right",
+            Some(b'D') => "Left",
+            _ => continue,
+        };
+        names.push(name.to_string());
+    }
+    (names, quit)
+}
+
+/// Name of a typed character in keymaps, terminals only report printable keys reliably
+fn key_name(byte: u8) -> Option<String> {
+    match byte {
+        b' ' => Some("Space".to_string()),
+        b'\r' => Some("Return".to_string()),
+        byte if byte.is_ascii_graphic() => Some((byte as char).to_string()),
+        _ => None,
+    }
+}
+
+impl Keypad for TerminalKeypad {
+    fn poll(&mut self) -> Vec<KeyEvent> {
+        let input = TerminalKeypad::read_stdin();
+        self.handle_input(&input)
+    }
+}
+
+/// Draws the framebuffer with Unicode half blocks, two pixel rows per terminal row,
+/// next to a panel with the cpu registers
+#[derive(Default)]
+pub struct TerminalDisplay {
+    frame: Framebuffer,
+    // shown below the registers
+    keymap: Vec<String>,
+}
+
+impl TerminalDisplay {
+    /// List the key bindings in the side panel
+    pub fn show_keymap(&mut self, keymap: &Keymap) {
+        self.keymap = keymap.overlay();
+    }
+
+    /// Write the last presented frame and the state of `cpu` to `out`
+    pub fn draw<W: Write>(&self, out: &mut W, cpu: &Cpu) -> io::Result<()> {
+        let screen = render_half_blocks(&self.frame);
+        let mut panel = render_registers(cpu);
+        if !self.keymap.is_empty() {
+            panel.push(String::new());
+            panel.extend(self.keymap.iter().cloned());
+        }
+        let mut text = String::from("\x1b[H");
+        for row in 0..screen.len().max(panel.len()) {
+            if let Some(line) = screen.get(row) {
+                text.push_str(line);
+            }
+            text.push_str("\x1b[0m  ");
+            if let Some(line) = panel.get(row) {
+                text.push_str(line);
+            }
+            text.push_str("\x1b[K\n");
+        }
+        out.write_all(text.as_bytes())?;
+        out.flush()
+    }
+}
+
+impl Display for TerminalDisplay {
+    fn present(&mut self, framebuffer: &Framebuffer) {
+        if self.frame != *framebuffer {
+            self.frame.clone_from(framebuffer);
+        }
+    }
+}
+
+/// One line of text per two rows of pixels
+/// Each character is an upper half block coloured with the top pixel,
+/// on a background coloured with the bottom pixel
+pub fn render_half_blocks(framebuffer: &Framebuffer) -> Vec<String> {
+    let mut lines = Vec::new();
+    for y in (0..framebuffer.height()).step_by(2) {
+        let mut line = String::new();
+        let mut colours = None;
+        for x in 0..framebuffer.width() {
+            let top = framebuffer.get(x, y) as usize;
+            let bottom = if y + 1 < framebuffer.height() {
+                framebuffer.get(x, y + 1) as usize
+            } else {
+                0
+            };
+            // only switch colours when they change
+            if colours != Some((top, bottom)) {
+                let _ = write!(line, "\x1b[{};{}m", FOREGROUND[top], BACKGROUND[bottom]);
+                colours = Some((top, bottom));
+            }
+            line.push('▀');
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Side panel with V0-VF, I, PC, SP and the timers
+pub fn render_registers(cpu: &Cpu) -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(format!("PC {:04X}   I {:04X}", cpu.pc(), cpu.i()));
+    lines.push(format!(
+        "SP {:<4X} DT {:02X} ST {:02X}",
+        cpu.stack().len(),
+        cpu.delay_timer(),
+        cpu.sound_timer()
+    ));
+    for row in 0..8 {
+        lines.push(format!(
+            "V{:X} {:02X}    V{:X} {:02X}",
+            row,
+            cpu.registers()[row],
+            row + 8,
+            cpu.registers()[row + 8]
+        ));
+    }
+    if let Some(err) = cpu.halted() {
+        lines.push(String::new());
+        lines.push(format!("halted: {}", err));
+    }
+    lines
+}
+
+/// Run `machine` in the terminal at 60 frames per second until ESC is pressed
+/// or the program exits
+pub fn run(machine: &mut Machine, keymap: Keymap) -> io::Result<Option<CpuError>> {
+    let _raw = RawTerminal::enable()?;
+    let mut display = TerminalDisplay::default();
+    display.show_keymap(&keymap);
+    let mut keypad = TerminalKeypad::new(keymap);
+    let mut audio = NullAudio;
+    let mut stdout = io::stdout();
+    let frame_duration = Duration::from_nanos(FRAME_DURATION_NANOS);
+
+    let mut next_frame = Instant::now();
+    while !keypad.quit_requested() && !machine.cpu().has_exited() {
+        if !machine.cpu().is_halted() {
+            // the error stays visible in the side panel
+            let _ = machine.run_frame_with(&mut display, &mut keypad, &mut audio);
+        } else {
+            keypad.poll();
+        }
+        display.draw(&mut stdout, machine.cpu())?;
+
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+    Ok(machine.cpu().halted().cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use frontend::terminal::*;
+
+    #[test]
+    fn test_half_blocks() {
+        let mut fb = Framebuffer::new(2, 2);
+        fb.toggle(0, 0, 1);
+        fb.toggle(1, 1, 1);
+        let lines = render_half_blocks(&fb);
+        assert_eq!(lines, vec!["\x1b[37;40m▀\x1b[30;47m▀".to_string()]);
+    }
+
+    #[test]
+    fn test_key_release_emulation() {
+        let mut keypad = TerminalKeypad::default();
+        assert_eq!(keypad.handle_input(b"z"), vec![KeyEvent::Pressed(0xA)]);
+        // key repeat keeps the key down
+        for _ in 0..KEY_HOLD_FRAMES * 2 {
+            assert_eq!(keypad.handle_input(b"Z"), vec![]);
+        }
+        for _ in 0..KEY_HOLD_FRAMES - 1 {
+            assert_eq!(keypad.handle_input(b""), vec![]);
+        }
+        assert_eq!(keypad.handle_input(b""), vec![KeyEvent::Released(0xA)]);
+    }
+
+    #[test]
+    fn test_quit_key() {
+        let mut keypad = TerminalKeypad::default();
+        keypad.handle_input(b"1\x1b");
+        assert!(keypad.quit_requested());
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        let mut keymap = Keymap::default();
+        keymap.bind(0x2, &["Up"]);
+        keymap.bind(0x8, &["Down"]);
+        let mut keypad = TerminalKeypad::new(keymap);
+        // arrows in CSI and SS3 form, F1, F5 and alt + q
+        let events = keypad.handle_input(b"\x1b[A\x1bOB\x1bOP\x1b[15~\x1bq");
+        assert!(!keypad.quit_requested());
+        assert_eq!(
+            events,
+            vec![
+                KeyEvent::Pressed(0x2),
+                KeyEvent::Pressed(0x8),
+                KeyEvent::Pressed(0x4)
+            ]
+        );
+        keypad.handle_input(b"\x1b[A");
+        assert!(!keypad.quit_requested());
+    }
+
+    #[test]
+    fn test_register_panel() {
+        let cpu = Cpu::default().init();
+        let panel = render_registers(&cpu);
+        assert_eq!(panel[0], "PC 0200   I 0000");
+        assert_eq!(panel[2], "V0 00    V8 00");
+    }
+}
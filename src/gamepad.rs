@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+/// Stick positions closer to the center than this don't press anything
+pub const DEFAULT_DEADZONE: f64 = 0.3;
+
+/// A host key going down or up, named like in keymaps
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostKeyEvent {
+    Pressed(String),
+    Released(String),
+}
+
+/// Direction of a hat (d-pad), diagonals hold two directions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hat {
+    Centered,
+    Up,
+    Down,
+    Left,
+    Right,
+    LeftUp,
+    RightUp,
+    LeftDown,
+    RightDown,
+}
+
+impl Hat {
+    fn names(self) -> &'static [&'static str] {
+        match self {
+            Hat::Centered => &[],
+            Hat::Up => &["HatUp"],
+            Hat::Down => &["HatDown"],
+            Hat::Left => &["HatLeft"],
+            Hat::Right => &["HatRight"],
+            Hat::LeftUp => &["HatLeft", "HatUp"],
+            Hat::RightUp => &["HatRight", "HatUp"],
+            Hat::LeftDown => &["HatLeft", "HatDown"],
+            Hat::RightDown => &["HatRight", "HatDown"],
+        }
+    }
+}
+
+/// Turns controller buttons, hats and analog axes into host key names for a `Keymap`:
+/// `Button0`, `HatUp`, and `Axis1-` / `Axis1+` for a stick pushed past the deadzone.
+/// Which axis is which depends on the controller, usually axis 0 is left/right
+/// and axis 1 up/down on the left stick, with up being negative
+pub struct Gamepad {
+    deadzone: f64,
+    // the direction each axis of each controller is pushed in
+    axes: HashMap<(i32, u8), Option<bool>>,
+    hats: HashMap<(i32, u8), Hat>,
+}
+
+impl Default for Gamepad {
+    fn default() -> Gamepad {
+        Gamepad::new(DEFAULT_DEADZONE)
+    }
+}
+
+impl Gamepad {
+    pub fn new(deadzone: f64) -> Gamepad {
+        Gamepad {
+            deadzone,
+            axes: HashMap::new(),
+            hats: HashMap::new(),
+        }
+    }
+
+    pub fn button(&self, button: u8, pressed: bool) -> Vec<HostKeyEvent> {
+        let name = format!("Button{}", button);
+        if pressed {
+            vec![HostKeyEvent::Pressed(name)]
+        } else {
+            vec![HostKeyEvent::Released(name)]
+        }
+    }
+
+    /// Axis `axis` of controller `id` moved to `position`, between -1 and 1
+    pub fn axis(&mut self, id: i32, axis: u8, position: f64) -> Vec<HostKeyEvent> {
+        let direction = if position <= -self.deadzone {
+            Some(false)
+        } else if position >= self.deadzone {
+            Some(true)
+        } else {
+            None
+        };
+        let previous = self.axes.insert((id, axis), direction).unwrap_or(None);
+        if previous == direction {
+            return Vec::new();
+        }
+
+        let name = |positive: bool| format!("Axis{}{}", axis, if positive { '+' } else { '-' });
+        let mut events = Vec::new();
+        if let Some(positive) = previous {
+            events.push(HostKeyEvent::Released(name(positive)));
+        }
+        if let Some(positive) = direction {
+            events.push(HostKeyEvent::Pressed(name(positive)));
+        }
+        events
+    }
+
+    /// Hat `which` of controller `id` now points at `hat`
+    pub fn hat(&mut self, id: i32, which: u8, hat: Hat) -> Vec<HostKeyEvent> {
+        let previous = self.hats.insert((id, which), hat).unwrap_or(Hat::Centered);
+        let mut events = Vec::new();
+        for name in previous.names() {
+            if !hat.names().contains(name) {
+                events.push(HostKeyEvent::Released(name.to_string()));
+            }
+        }
+        for name in hat.names() {
+            if !previous.names().contains(name) {
+                events.push(HostKeyEvent::Pressed(name.to_string()));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gamepad::*;
+
+    fn pressed(name: &str) -> HostKeyEvent {
+        HostKeyEvent::Pressed(name.to_string())
+    }
+
+    fn released(name: &str) -> HostKeyEvent {
+        HostKeyEvent::Released(name.to_string())
+    }
+
+    #[test]
+    fn test_axis_deadzone() {
+        let mut gamepad = Gamepad::new(0.5);
+        assert_eq!(gamepad.axis(0, 1, 0.2), vec![]);
+        assert_eq!(gamepad.axis(0, 1, -0.6), vec![pressed("Axis1-")]);
+        assert_eq!(gamepad.axis(0, 1, -0.9), vec![]);
+        // flicking through the center releases the other direction
+        assert_eq!(
+            gamepad.axis(0, 1, 0.7),
+            vec![released("Axis1-"), pressed("Axis1+")]
+        );
+        assert_eq!(gamepad.axis(0, 1, 0.49), vec![released("Axis1+")]);
+        // controllers are tracked separately
+        assert_eq!(gamepad.axis(1, 1, 0.0), vec![]);
+    }
+
+    #[test]
+    fn test_hat_diagonals() {
+        let mut gamepad = Gamepad::default();
+        assert_eq!(gamepad.hat(0, 0, Hat::Up), vec![pressed("HatUp")]);
+        assert_eq!(gamepad.hat(0, 0, Hat::RightUp), vec![pressed("HatRight")]);
+        assert_eq!(
+            gamepad.hat(0, 0, Hat::Down),
+            vec![released("HatRight"), released("HatUp"), pressed("HatDown")]
+        );
+        assert_eq!(gamepad.hat(0, 0, Hat::Centered), vec![released("HatDown")]);
+    }
+
+    #[test]
+    fn test_buttons() {
+        let gamepad = Gamepad::default();
+        assert_eq!(gamepad.button(3, true), vec![pressed("Button3")]);
+        assert_eq!(gamepad.button(3, false), vec![released("Button3")]);
+    }
+}
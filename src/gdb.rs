@@ -0,0 +1,605 @@
+use cpu::FRAME_RATE;
+use debugger::{StopReason, Watch};
+use error::CpuError;
+use machine::Machine;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sent by gdb to interrupt a running target
+const INTERRUPT: u8 = 0x03;
+/// Largest packet gdb may send us, announced in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+// signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Register numbers in `g`, `p` and `P` packets and in the target description
+/// V0 to VF are 0 to 15, each one byte, the 16 bit registers are little endian
+pub const REGISTER_I: usize = 16;
+pub const REGISTER_PC: usize = 17;
+/// Number of return addresses on the stack
+pub const REGISTER_SP: usize = 18;
+pub const REGISTER_DT: usize = 19;
+pub const REGISTER_ST: usize = 20;
+
+const TARGET_XML: &str = concat!(
+    "<?xml version=\"1.0\"?>",
+    "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+    "<target><feature name=\"org.chip8.core\">",
+    "<reg name=\"v0\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v1\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v2\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v3\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v4\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v5\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v6\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v7\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v8\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"v9\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"va\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"vb\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"vc\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"vd\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"ve\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"vf\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>",
+    "<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>",
+    "<reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"dt\" bitsize=\"8\" type=\"uint8\"/>",
+    "<reg name=\"st\" bitsize=\"8\" type=\"uint8\"/>",
+    "</feature></target>"
+);
+
+/// Wait for gdb on 127.0.0.1:`port` and serve a single session,
+/// connect with `target remote localhost:<port>`
+pub fn serve(machine: &mut Machine, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    info!("Waiting for gdb on {}", listener.local_addr()?);
+    let (stream, address) = listener.accept()?;
+    info!("gdb connected from {}", address);
+    GdbSession::new(machine, stream).run()
+}
+
+/// One gdb connection, speaking the remote serial protocol
+/// The machine is paused while gdb is connected, except during `c`
+pub struct GdbSession<'a> {
+    machine: &'a mut Machine,
+    stream: TcpStream,
+    no_ack: bool,
+}
+
+impl<'a> GdbSession<'a> {
+    pub fn new(machine: &'a mut Machine, stream: TcpStream) -> GdbSession<'a> {
+        GdbSession {
+            machine,
+            stream,
+            no_ack: false,
+        }
+    }
+
+    /// Answer packets until gdb detaches, kills the target or disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        self.machine.pause();
+        while let Some(packet) = self.read_packet()? {
+            debug!("gdb: {}", packet);
+            let reply = match packet.as_bytes().first() {
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    self.machine.resume();
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => self.handle(&packet)?,
+            };
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<String> {
+        let mut chars = packet.chars();
+        let command = match chars.next() {
+            Some(command) => command,
+            // empty packets are valid, the reply to unsupported ones is empty too
+            None => return Ok(String::new()),
+        };
+        let arguments = chars.as_str();
+        let reply = match command {
+            '?' => self.stop_reply(),
+            'g' => self.read_registers(),
+            'G' => self.write_registers(arguments),
+            'p' => match parse_hex(arguments) {
+                Some(register) => self.read_register(register),
+                None => error_reply(),
+            },
+            'P' => self.write_register(arguments),
+            'm' => self.read_memory(arguments),
+            'M' => self.write_memory(arguments),
+            's' => {
+                // a halt shows up in the stop reply
+                let _ = self.machine.step();
+                self.stop_reply()
+            }
+            'c' => self.resume()?,
+            'Z' | 'z' => self.breakpoint(command == 'Z', arguments),
+            'H' => "OK".to_string(),
+            'q' | 'Q' => self.query(packet),
+            // vCont, X and everything else gdb falls back from
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:X};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_pair(range, ',') {
+                Some((offset, length)) => {
+                    let start = offset.min(TARGET_XML.len());
+                    let end = start.saturating_add(length).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[start..end])
+                }
+                None => error_reply(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn registers(&self) -> Vec<Vec<u8>> {
+        let cpu = self.machine.cpu();
+        let mut registers: Vec<Vec<u8>> = cpu.registers().iter().map(|&v| vec![v]).collect();
+        registers.push(vec![cpu.i() as u8, (cpu.i() >> 8) as u8]);
+        registers.push(vec![cpu.pc() as u8, (cpu.pc() >> 8) as u8]);
+        registers.push(vec![cpu.stack().len() as u8]);
+        registers.push(vec![cpu.delay_timer()]);
+        registers.push(vec![cpu.sound_timer()]);
+        registers
+    }
+
+    fn read_registers(&self) -> String {
+        let bytes: Vec<u8> = self.registers().into_iter().flatten().collect();
+        to_hex(&bytes)
+    }
+
+    fn read_register(&self, register: usize) -> String {
+        match self.registers().get(register) {
+            Some(bytes) => to_hex(bytes),
+            None => error_reply(),
+        }
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> String {
+        let bytes = match from_hex(arguments) {
+            Some(bytes) => bytes,
+            None => return error_reply(),
+        };
+        // V0-VF, I and PC, the read only registers behind them may be left out
+        if bytes.len() < 20 {
+            return error_reply();
+        }
+        let cpu = self.machine.cpu_mut();
+        for (register, &value) in bytes[..16].iter().enumerate() {
+            cpu.set_register(register as u8, value);
+        }
+        cpu.set_i(bytes[16] as u16 | (bytes[17] as u16) << 8);
+        cpu.set_pc(bytes[18] as u16 | (bytes[19] as u16) << 8);
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, arguments: &str) -> String {
+        let parsed = arguments.find('=').and_then(|split| {
+            Some((
+                parse_hex(&arguments[..split])?,
+                from_hex(&arguments[split + 1..])?,
+            ))
+        });
+        let (register, bytes) = match parsed {
+            Some(parsed) => parsed,
+            None => return error_reply(),
+        };
+        let cpu = self.machine.cpu_mut();
+        match (register, bytes.len()) {
+            (0..=15, 1) => cpu.set_register(register as u8, bytes[0]),
+            (REGISTER_I, 2) => cpu.set_i(bytes[0] as u16 | (bytes[1] as u16) << 8),
+            (REGISTER_PC, 2) => cpu.set_pc(bytes[0] as u16 | (bytes[1] as u16) << 8),
+            // SP and the timers can't be written
+            _ => return error_reply(),
+        }
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, arguments: &str) -> String {
+        let memory = self.machine.cpu().dump_memory();
+        match parse_pair(arguments, ',') {
+            Some((address, length)) if address < memory.len() => {
+                let end = address.saturating_add(length).min(memory.len());
+                to_hex(&memory[address..end])
+            }
+            _ => error_reply(),
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let mut parts = arguments.splitn(2, ':');
+        let range = parts.next().and_then(|range| parse_pair(range, ','));
+        let bytes = parts.next().and_then(from_hex);
+        match (range, bytes) {
+            (Some((address, length)), Some(ref bytes))
+                if bytes.len() == length && address <= u16::MAX as usize =>
+            {
+                match self.machine.cpu_mut().write_memory(address as u16, bytes) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => error_reply(),
+                }
+            }
+            _ => error_reply(),
+        }
+    }
+
+    /// Z/z TYPE,ADDR,KIND: 0 breakpoint, 2 write, 3 read and 4 access watchpoint
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let fields: Vec<usize> = arguments.split(',').filter_map(parse_hex).collect();
+        if fields.len() != 3 {
+            return error_reply();
+        }
+        let (kind, address, length) = (fields[0], fields[1] as u16, fields[2] as u16);
+        let watch = match kind {
+            0 | 1 => None,
+            2 => Some(Watch::Write),
+            3 => Some(Watch::Read),
+            4 => Some(Watch::Access),
+            _ => return String::new(),
+        };
+        let debugger = self.machine.debugger_mut();
+        match (watch, insert) {
+            (None, true) => debugger.add_breakpoint(address),
+            (None, false) => {
+                debugger.remove_breakpoint(address);
+            }
+            (Some(watch), true) => {
+                let end = address.saturating_add(length.max(1) - 1);
+                debugger.add_watchpoint(address, end, watch)
+            }
+            (Some(_), false) => {
+                debugger.remove_watchpoint(address);
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// Run at 60 frames per second until a breakpoint, a halt, the end of the program
+    /// or an interrupt from gdb
+    fn resume(&mut self) -> io::Result<String> {
+        self.machine.resume();
+        let frame = Duration::from_nanos((1e9 / FRAME_RATE) as u64);
+        let mut next_frame = Instant::now();
+        loop {
+            if self.machine.cpu().is_halted() || self.machine.cpu().has_exited() {
+                break;
+            }
+            if self.interrupted()? {
+                self.machine.pause();
+                return Ok(format!("S{:02X}", SIGINT));
+            }
+            // a halt shows up in the stop reply
+            let _ = self.machine.run_frame();
+            if self.machine.debugger().is_paused() {
+                break;
+            }
+
+            next_frame += frame;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            }
+        }
+        if !self.machine.debugger().is_paused() {
+            self.machine.pause();
+        }
+        Ok(self.stop_reply())
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            // gdb hung up, stop running
+            Ok(_) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        let cpu = self.machine.cpu();
+        if cpu.has_exited() {
+            return "W00".to_string();
+        }
+        match cpu.halted() {
+            Some(&CpuError::MemoryOutOfBounds { .. }) => return format!("S{:02X}", SIGSEGV),
+            Some(_) => return format!("S{:02X}", SIGILL),
+            None => {}
+        }
+        match self.machine.debugger().last_stop() {
+            Some(&StopReason::Watchpoint(watchpoint, access)) => {
+                let kind = match watchpoint.watch {
+                    Watch::Write => "watch",
+                    Watch::Read => "rwatch",
+                    Watch::Access => "awatch",
+                };
+                format!("T{:02X}{}:{:X};", SIGTRAP, kind, access.address)
+            }
+            _ => format!("S{:02X}", SIGTRAP),
+        }
+    }
+
+    /// Read the next packet, `None` once the connection is closed
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acks and interrupts outside of packets
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = String::from_utf8_lossy(&checksum);
+            let valid = parse_hex(&expected) == Some(checksum_of(&data) as usize);
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            warn!("gdb packet with bad checksum");
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn error_reply() -> String {
+    "E01".to_string()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn parse_pair(text: &str, separator: char) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, separator);
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|offset| u8::from_str_radix(&text[offset..offset + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use gdb::*;
+    use std::net::Shutdown;
+
+    /// Minimal client: sends packets and returns the replies
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            self.receive()
+        }
+
+        fn receive(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            assert_eq!(reply[0], b'$');
+            let data = &reply[1..];
+            assert_eq!(
+                parse_hex(&String::from_utf8_lossy(&checksum)),
+                Some(checksum_of(data) as usize)
+            );
+            String::from_utf8(data.to_vec()).unwrap()
+        }
+    }
+
+    /// Run `script` against a gdb session on `machine`, returns the replies
+    fn session<F>(machine: &mut Machine, script: F) -> Vec<String>
+    where
+        F: FnOnce(&mut Client) -> Vec<String> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(address).unwrap(),
+            };
+            let replies = script(&mut client);
+            client.stream.shutdown(Shutdown::Both).unwrap();
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        GdbSession::new(machine, stream).run().unwrap();
+        client.join().unwrap()
+    }
+
+    fn machine() -> Machine {
+        let mut machine = Machine::default();
+        // 0x200: V0 := 5, 0x202: I := 0x300, 0x204: store V0, 0x206: V0 += 1, 0x208: jump 0x206
+        machine
+            .load_bytes(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x70, 0x01, 0x12, 0x06])
+            .unwrap();
+        machine
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut machine = machine();
+        let replies = session(&mut machine, |client| {
+            vec![
+                client.send("qSupported:multiprocess+"),
+                client.send("?"),
+                client.send("s"),
+                client.send("g"),
+                client.send("p11"),
+                client.send("m200,4"),
+                client.send("M300,2:abcd"),
+                client.send("m300,2"),
+                client.send("P3=7f"),
+                client.send("p3"),
+                client.send("P13=01"),
+            ]
+        });
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], "S05");
+        assert_eq!(replies[3], format!("05{}00000202000000", "00".repeat(15)));
+        assert_eq!(replies[4], "0202");
+        assert_eq!(replies[5], "6005a300");
+        assert_eq!(replies[6], "OK");
+        assert_eq!(replies[7], "abcd");
+        assert_eq!(replies[8], "OK");
+        assert_eq!(replies[9], "7f");
+        assert_eq!(replies[10], "E01");
+        assert_eq!(machine.cpu().registers()[3], 0x7F);
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        let mut machine = machine();
+        let replies = session(&mut machine, |client| {
+            vec![
+                client.send(""),
+                client.send("\u{e9}1"),
+                client.send("m200,ffffffffffffffff"),
+                client.send("M10200,1:ab"),
+                client.send("M300,1:abc"),
+            ]
+        });
+        assert_eq!(replies[0], "");
+        assert_eq!(replies[1], "");
+        assert!(replies[2].starts_with("6005a300"));
+        assert_eq!(replies[3], "E01");
+        assert_eq!(replies[4], "E01");
+    }
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let mut machine = machine();
+        let replies = session(&mut machine, |client| {
+            vec![
+                client.send("Z0,206,2"),
+                client.send("c"),
+                client.send("p11"),
+                client.send("z0,206,2"),
+                client.send("Z2,300,2"),
+                client.send("P11=0402"),
+                client.send("c"),
+                client.send("D"),
+            ]
+        });
+        assert_eq!(replies[0], "OK");
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], "0602");
+        assert_eq!(replies[4], "OK");
+        // the first store moved I on to 0x301
+        assert_eq!(replies[6], "T05watch:301;");
+        assert_eq!(replies[7], "OK");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut machine = machine();
+        let replies = session(&mut machine, |client| {
+            client.stream.write_all(b"$c#63").unwrap();
+            let mut ack = [0];
+            client.stream.read_exact(&mut ack).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            client.stream.write_all(&[INTERRUPT]).unwrap();
+            vec![client.receive()]
+        });
+        assert_eq!(replies[0], "S02");
+    }
+
+    #[test]
+    fn test_target_description() {
+        let mut machine = machine();
+        let replies = session(&mut machine, |client| {
+            vec![
+                client.send("qXfer:features:read:target.xml:0,20"),
+                client.send("qXfer:features:read:target.xml:0,10000"),
+            ]
+        });
+        assert!(replies[0].starts_with("m<?xml"));
+        assert_eq!(replies[1], format!("l{}", TARGET_XML));
+    }
+}
@@ -0,0 +1,74 @@
+use framebuffer::Framebuffer;
+
+/// Pixel characters by plane bits, XO-CHIP can set two planes
+const PIXELS: [char; 4] = ['.', '#', '+', '%'];
+
+/// One character per pixel and a line per row: `.` for off, `#` for on,
+/// `+` and `%` for the second XO-CHIP plane
+pub fn render(framebuffer: &Framebuffer) -> String {
+    let mut image = String::new();
+    for y in 0..framebuffer.height() {
+        for x in 0..framebuffer.width() {
+            image.push(PIXELS[framebuffer.get(x, y) as usize & 3]);
+        }
+        image.push('\n');
+    }
+    image
+}
+
+/// The expected image with mismatching pixels marked, `-` for missing and `+` for extra ones
+pub fn diff(expected: &str, actual: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let expected_rows: Vec<&str> = expected.lines().collect();
+    let actual_rows: Vec<&str> = actual.lines().collect();
+    let size = |rows: &[&str]| (rows.first().map_or(0, |row| row.len()), rows.len());
+    if size(&expected_rows) != size(&actual_rows) {
+        let (expected_width, expected_height) = size(&expected_rows);
+        let (actual_width, actual_height) = size(&actual_rows);
+        return Some(format!(
+            "expected a {}x{} screen, found {}x{}\n{}",
+            expected_width, expected_height, actual_width, actual_height, actual
+        ));
+    }
+
+    let mut mismatches = 0;
+    let mut marked = String::new();
+    for (expected_row, actual_row) in expected_rows.iter().zip(&actual_rows) {
+        for (expected, actual) in expected_row.chars().zip(actual_row.chars()) {
+            marked.push(match (expected, actual) {
+                (expected, actual) if expected == actual => expected,
+                (_, '.') => '-',
+                _ => '+',
+            });
+            if expected != actual {
+                mismatches += 1;
+            }
+        }
+        marked.push('\n');
+    }
+    Some(format!(
+        "{} pixels differ (- expected but off, + on but not expected)\n{}",
+        mismatches, marked
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use golden::*;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("#.\n..\n", "#.\n..\n"), None);
+        assert_eq!(
+            diff("#.\n..\n", "..\n.#\n"),
+            Some(
+                "2 pixels differ (- expected but off, + on but not expected)\n-.\n.+\n".to_string()
+            )
+        );
+        assert!(diff("#.\n", "#..\n")
+            .unwrap()
+            .starts_with("expected a 2x1 screen, found 3x1"));
+    }
+}
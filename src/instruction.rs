@@ -0,0 +1,262 @@
+/// The CHIP-8 extension that introduced an instruction, each one builds on the previous
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+/// A decoded opcode, X and Y are register numbers, the other fields immediate values
+/// Covers CHIP-8, SUPER-CHIP 1.1 and XO-CHIP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    /// 00E0
+    ClearScreen,
+    /// 00EE
+    Return,
+    /// 00CN
+    ScrollDown(u8),
+    /// 00DN
+    ScrollUp(u8),
+    /// 00FB
+    ScrollRight,
+    /// 00FC
+    ScrollLeft,
+    /// 00FD
+    Exit,
+    /// 00FE
+    LowRes,
+    /// 00FF
+    HighRes,
+    /// 0NNN, machine code routine of the host
+    MachineCall(u16),
+    /// 1NNN
+    Jump(u16),
+    /// 2NNN
+    Call(u16),
+    /// 3XNN, skip if VX == NN
+    SkipIfEqual(u8, u8),
+    /// 4XNN, skip if VX != NN
+    SkipIfNotEqual(u8, u8),
+    /// 5XY0, skip if VX == VY
+    SkipIfRegistersEqual(u8, u8),
+    /// 5XY2, store VX to VY at I
+    StoreRange(u8, u8),
+    /// 5XY3, load VX to VY from I
+    LoadRange(u8, u8),
+    /// 6XNN
+    SetRegister(u8, u8),
+    /// 7XNN, without carry
+    AddToRegister(u8, u8),
+    /// 8XY0
+    Copy(u8, u8),
+    /// 8XY1
+    Or(u8, u8),
+    /// 8XY2
+    And(u8, u8),
+    /// 8XY3
+    Xor(u8, u8),
+    /// 8XY4, VF is the carry
+    Add(u8, u8),
+    /// 8XY5, VX -= VY
+    Subtract(u8, u8),
+    /// 8XY6
+    ShiftRight(u8, u8),
+    /// 8XY7, VX = VY - VX
+    SubtractReversed(u8, u8),
+    /// 8XYE
+    ShiftLeft(u8, u8),
+    /// 9XY0, skip if VX != VY
+    SkipIfRegistersNotEqual(u8, u8),
+    /// ANNN
+    SetIndex(u16),
+    /// BNNN
+    JumpWithOffset(u16),
+    /// CXNN
+    Random(u8, u8),
+    /// DXYN, N = 0 draws a 16x16 sprite
+    Draw(u8, u8, u8),
+    /// EX9E
+    SkipIfKeyPressed(u8),
+    /// EXA1
+    SkipIfKeyNotPressed(u8),
+    /// F000 NNNN, the address is the word after the opcode
+    SetIndexLong,
+    /// FN01, N is a bitplane mask
+    SelectPlanes(u8),
+    /// F002
+    LoadAudioPattern,
+    /// FX07
+    GetDelayTimer(u8),
+    /// FX0A
+    WaitForKey(u8),
+    /// FX15
+    SetDelayTimer(u8),
+    /// FX18
+    SetSoundTimer(u8),
+    /// FX1E
+    AddToIndex(u8),
+    /// FX29
+    FontCharacter(u8),
+    /// FX30
+    BigFontCharacter(u8),
+    /// FX33
+    StoreBcd(u8),
+    /// FX3A
+    SetPitch(u8),
+    /// FX55
+    StoreRegisters(u8),
+    /// FX65
+    LoadRegisters(u8),
+    /// FX75
+    StoreFlags(u8),
+    /// FX85
+    LoadFlags(u8),
+    /// Not part of any supported instruction set
+    Unknown(u16),
+}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Instruction {
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+        let n = (opcode & 0x000F) as u8;
+        let nn = (opcode & 0x00FF) as u8;
+        let nnn = opcode & 0x0FFF;
+
+        match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00E0 => Instruction::ClearScreen,
+                0x00EE => Instruction::Return,
+                0x00C0..=0x00CF => Instruction::ScrollDown(n),
+                0x00D0..=0x00DF => Instruction::ScrollUp(n),
+                0x00FB => Instruction::ScrollRight,
+                0x00FC => Instruction::ScrollLeft,
+                0x00FD => Instruction::Exit,
+                0x00FE => Instruction::LowRes,
+                0x00FF => Instruction::HighRes,
+                _ => Instruction::MachineCall(nnn),
+            },
+            0x1000 => Instruction::Jump(nnn),
+            0x2000 => Instruction::Call(nnn),
+            0x3000 => Instruction::SkipIfEqual(x, nn),
+            0x4000 => Instruction::SkipIfNotEqual(x, nn),
+            0x5000 => match n {
+                0x0 => Instruction::SkipIfRegistersEqual(x, y),
+                0x2 => Instruction::StoreRange(x, y),
+                0x3 => Instruction::LoadRange(x, y),
+                _ => Instruction::Unknown(opcode),
+            },
+            0x6000 => Instruction::SetRegister(x, nn),
+            0x7000 => Instruction::AddToRegister(x, nn),
+            0x8000 => match n {
+                0x0 => Instruction::Copy(x, y),
+                0x1 => Instruction::Or(x, y),
+                0x2 => Instruction::And(x, y),
+                0x3 => Instruction::Xor(x, y),
+                0x4 => Instruction::Add(x, y),
+                0x5 => Instruction::Subtract(x, y),
+                0x6 => Instruction::ShiftRight(x, y),
+                0x7 => Instruction::SubtractReversed(x, y),
+                0xE => Instruction::ShiftLeft(x, y),
+                _ => Instruction::Unknown(opcode),
+            },
+            0x9000 => Instruction::SkipIfRegistersNotEqual(x, y),
+            0xA000 => Instruction::SetIndex(nnn),
+            0xB000 => Instruction::JumpWithOffset(nnn),
+            0xC000 => Instruction::Random(x, nn),
+            0xD000 => Instruction::Draw(x, y, n),
+            0xE000 => match nn {
+                0x9E => Instruction::SkipIfKeyPressed(x),
+                0xA1 => Instruction::SkipIfKeyNotPressed(x),
+                _ => Instruction::Unknown(opcode),
+            },
+            0xF000 => match nn {
+                0x00 if x == 0 => Instruction::SetIndexLong,
+                0x01 => Instruction::SelectPlanes(x),
+                0x02 if x == 0 => Instruction::LoadAudioPattern,
+                0x07 => Instruction::GetDelayTimer(x),
+                0x0A => Instruction::WaitForKey(x),
+                0x15 => Instruction::SetDelayTimer(x),
+                0x18 => Instruction::SetSoundTimer(x),
+                0x1E => Instruction::AddToIndex(x),
+                0x29 => Instruction::FontCharacter(x),
+                0x30 => Instruction::BigFontCharacter(x),
+                0x33 => Instruction::StoreBcd(x),
+                0x3A => Instruction::SetPitch(x),
+                0x55 => Instruction::StoreRegisters(x),
+                0x65 => Instruction::LoadRegisters(x),
+                0x75 => Instruction::StoreFlags(x),
+                0x85 => Instruction::LoadFlags(x),
+                _ => Instruction::Unknown(opcode),
+            },
+            _ => Instruction::Unknown(opcode),
+        }
+    }
+
+    /// Size in bytes including operands, F000 NNNN is the only four byte instruction
+    pub fn size(&self) -> u16 {
+        match *self {
+            Instruction::SetIndexLong => 4,
+            _ => 2,
+        }
+    }
+
+    /// The oldest platform that has this instruction
+    pub fn platform(&self) -> Platform {
+        match *self {
+            Instruction::ScrollDown(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::Draw(_, _, 0)
+            | Instruction::BigFontCharacter(_)
+            | Instruction::StoreFlags(_)
+            | Instruction::LoadFlags(_) => Platform::SuperChip,
+            Instruction::ScrollUp(_)
+            | Instruction::StoreRange(_, _)
+            | Instruction::LoadRange(_, _)
+            | Instruction::SetIndexLong
+            | Instruction::SelectPlanes(_)
+            | Instruction::LoadAudioPattern
+            | Instruction::SetPitch(_) => Platform::XoChip,
+            _ => Platform::Chip8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use instruction::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(0x00E0), Instruction::ClearScreen);
+        assert_eq!(Instruction::decode(0x00C3), Instruction::ScrollDown(3));
+        assert_eq!(Instruction::decode(0x0123), Instruction::MachineCall(0x123));
+        assert_eq!(Instruction::decode(0x2ABC), Instruction::Call(0xABC));
+        assert_eq!(Instruction::decode(0x8124), Instruction::Add(1, 2));
+        assert_eq!(Instruction::decode(0xD125), Instruction::Draw(1, 2, 5));
+        assert_eq!(Instruction::decode(0xF000), Instruction::SetIndexLong);
+        assert_eq!(Instruction::decode(0xF201), Instruction::SelectPlanes(2));
+        assert_eq!(Instruction::decode(0xF365), Instruction::LoadRegisters(3));
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        for &opcode in &[0x5121, 0x8128, 0xE1FF, 0xF100, 0xF102, 0xF1FF] {
+            assert_eq!(Instruction::decode(opcode), Instruction::Unknown(opcode));
+        }
+    }
+
+    #[test]
+    fn test_platform() {
+        assert_eq!(Instruction::decode(0xD125).platform(), Platform::Chip8);
+        assert_eq!(Instruction::decode(0xD120).platform(), Platform::SuperChip);
+        assert_eq!(Instruction::decode(0x00FF).platform(), Platform::SuperChip);
+        assert_eq!(Instruction::decode(0xF201).platform(), Platform::XoChip);
+        assert!(Platform::XoChip > Platform::SuperChip);
+    }
+}
@@ -0,0 +1,334 @@
+use error::KeymapError;
+use frontend::KeyEvent;
+use gamepad::DEFAULT_DEADZONE;
+use std::fs;
+use std::path::Path;
+
+/// Hex keys in the order of the COSMAC VIP keypad, row by row
+pub const KEYPAD_LAYOUT: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+/// The left hand block of a QWERTY keyboard, in the same shape as `KEYPAD_LAYOUT`
+const QWERTY_LAYOUT: [[&str; 4]; 4] = [
+    ["1", "2", "3", "4"],
+    ["Q", "W", "E", "R"],
+    ["A", "S", "D", "F"],
+    ["Z", "X", "C", "V"],
+];
+
+/// Host keys bound to each of the 16 hex keys.
+/// Host keys are named like on the keyboard, `Q`, `1`, `Up`, `Space`, ignoring case
+///
+/// Keymap files bind one hex key per line, replacing its default keys. Lines before the first
+/// `[rom]` section apply to every rom, a section only to roms with that file name:
+///
+/// ```text
+/// # arrows for pong
+/// [pong.ch8]
+/// 1 = 1 Up
+/// 4 = Q Down
+/// ```
+///
+/// Controllers are bound with the names from `Gamepad`, e.g. `4 = Q Down HatUp Axis1-`,
+/// `deadzone = 0.5` sets how far sticks have to be pushed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    bindings: [Vec<String>; 16],
+    deadzone: f64,
+}
+
+impl Default for Keymap {
+    /// The usual 1234/QWER/ASDF/ZXCV layout
+    fn default() -> Keymap {
+        let mut keymap = Keymap {
+            bindings: Default::default(),
+            deadzone: DEFAULT_DEADZONE,
+        };
+        for (keys, names) in KEYPAD_LAYOUT.iter().zip(QWERTY_LAYOUT.iter()) {
+            for (&key, name) in keys.iter().zip(names.iter()) {
+                keymap.bind(key, &[name]);
+            }
+        }
+        keymap
+    }
+}
+
+impl Keymap {
+    /// Read the keymap file at `path` on top of the default layout,
+    /// `rom_path` selects the section for the rom
+    pub fn load<P: AsRef<Path>>(path: P, rom_path: &str) -> Result<Keymap, KeymapError> {
+        let rom_name = Path::new(rom_path)
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        Keymap::parse(&fs::read_to_string(path)?, &rom_name)
+    }
+
+    /// Apply the lines of a keymap file that are meant for `rom_name` to the default layout
+    pub fn parse(text: &str, rom_name: &str) -> Result<Keymap, KeymapError> {
+        let mut keymap = Keymap::default();
+        let mut active = true;
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[') {
+                match section.strip_suffix(']') {
+                    Some(name) => active = name.trim().eq_ignore_ascii_case(rom_name),
+                    None => return parse_error(number, "expected '[<rom file name>]'"),
+                }
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let hex = parts.next().map_or("", str::trim);
+            if hex == "deadzone" {
+                let deadzone = parts.next().and_then(|value| value.trim().parse().ok());
+                match deadzone {
+                    Some(deadzone) if (0.0..1.0).contains(&deadzone) => {
+                        if active {
+                            keymap.deadzone = deadzone;
+                        }
+                    }
+                    _ => return parse_error(number, "expected 'deadzone = <0 to 1>'"),
+                }
+                continue;
+            }
+            let key = match u8::from_str_radix(hex, 16) {
+                Ok(key) if hex.len() == 1 => key,
+                _ => {
+                    return parse_error(
+                        number,
+                        format!("'{}' is not a hex key, expected 0-9 or A-F", hex),
+                    )
+                }
+            };
+            let names: Vec<&str> = match parts.next() {
+                Some(names) => names.split_whitespace().collect(),
+                None => return parse_error(number, "expected '<hex key> = <host keys>'"),
+            };
+            if active {
+                keymap.bind(key, &names);
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// Bind `key` to the host keys `names` only, unbinding them from other hex keys
+    pub fn bind(&mut self, key: u8, names: &[&str]) {
+        let names: Vec<String> = names.iter().map(|name| normalize(name)).collect();
+        for bound in self.bindings.iter_mut() {
+            bound.retain(|name| !names.contains(name));
+        }
+        self.bindings[key as usize] = names;
+    }
+
+    /// The hex key `name` is bound to
+    pub fn key_for(&self, name: &str) -> Option<u8> {
+        let name = normalize(name);
+        self.bindings
+            .iter()
+            .position(|names| names.contains(&name))
+            .map(|key| key as u8)
+    }
+
+    /// How far sticks have to be pushed to press a key, from 0 to 1
+    pub fn deadzone(&self) -> f64 {
+        self.deadzone
+    }
+
+    /// Host keys bound to the hex key `key`, normalized to upper case
+    pub fn bindings(&self, key: u8) -> &[String] {
+        &self.bindings[key as usize]
+    }
+
+    /// The mapping laid out like the keypad, one line per row, e.g. `1:1  2:2  3:3  C:4`
+    pub fn overlay(&self) -> Vec<String> {
+        let width = self
+            .bindings
+            .iter()
+            .map(|names| names.join(",").len())
+            .max()
+            .unwrap_or(0);
+        KEYPAD_LAYOUT
+            .iter()
+            .map(|keys| {
+                let cells: Vec<String> = keys
+                    .iter()
+                    .map(|&key| {
+                        format!(
+                            "{:X}:{:<width$}",
+                            key,
+                            self.bindings(key).join(","),
+                            width = width
+                        )
+                    })
+                    .collect();
+                cells.join("  ").trim_end().to_string()
+            })
+            .collect()
+    }
+}
+
+/// Host key names compare case insensitively
+fn normalize(name: &str) -> String {
+    name.to_ascii_uppercase()
+}
+
+fn parse_error<T, S: Into<String>>(line: usize, message: S) -> Result<T, KeymapError> {
+    Err(KeymapError::Parse {
+        line,
+        message: message.into(),
+    })
+}
+
+/// Turns host key presses and releases into hex key events. A hex key bound to
+/// several host keys stays down until the last of them is released
+#[derive(Default)]
+pub struct HostKeyboard {
+    keymap: Keymap,
+    held: Vec<String>,
+}
+
+impl HostKeyboard {
+    pub fn new(keymap: Keymap) -> HostKeyboard {
+        HostKeyboard {
+            keymap,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    /// Host key `name` went down, key repeats are ignored
+    pub fn press(&mut self, name: &str) -> Option<KeyEvent> {
+        let key = self.keymap.key_for(name)?;
+        let name = normalize(name);
+        if self.held.contains(&name) {
+            return None;
+        }
+        let was_down = self.is_down(key);
+        self.held.push(name);
+        if was_down {
+            None
+        } else {
+            Some(KeyEvent::Pressed(key))
+        }
+    }
+
+    /// Host key `name` went up
+    pub fn release(&mut self, name: &str) -> Option<KeyEvent> {
+        let key = self.keymap.key_for(name)?;
+        let name = normalize(name);
+        let index = self.held.iter().position(|held| *held == name)?;
+        self.held.remove(index);
+        if self.is_down(key) {
+            None
+        } else {
+            Some(KeyEvent::Released(key))
+        }
+    }
+
+    fn is_down(&self, key: u8) -> bool {
+        self.held
+            .iter()
+            .any(|name| self.keymap.key_for(name) == Some(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keymap::*;
+
+    #[test]
+    fn test_default_layout() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.key_for("1"), Some(0x1));
+        assert_eq!(keymap.key_for("4"), Some(0xC));
+        assert_eq!(keymap.key_for("q"), Some(0x4));
+        assert_eq!(keymap.key_for("F"), Some(0xE));
+        assert_eq!(keymap.key_for("X"), Some(0x0));
+        assert_eq!(keymap.key_for("V"), Some(0xF));
+        assert_eq!(keymap.key_for("P"), None);
+        assert_eq!(
+            keymap.overlay(),
+            vec![
+                "1:1  2:2  3:3  C:4",
+                "4:Q  5:W  6:E  D:R",
+                "7:A  8:S  9:D  E:F",
+                "A:Z  0:X  B:C  F:V"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let text = "# every rom\n0 = Space\n\n[pong.ch8]\n1 = 1 up\n4 = Q Down # paddle\n[tetris.ch8]\n5 = Left\n";
+        let keymap = Keymap::parse(text, "pong.ch8").unwrap();
+        assert_eq!(keymap.key_for("Space"), Some(0x0));
+        assert_eq!(keymap.key_for("X"), None);
+        assert_eq!(keymap.bindings(0x1), &["1".to_string(), "UP".to_string()]);
+        assert_eq!(keymap.key_for("DOWN"), Some(0x4));
+        assert_eq!(keymap.key_for("Left"), None);
+        assert_eq!(keymap.key_for("W"), Some(0x5));
+
+        assert_eq!(keymap.deadzone(), DEFAULT_DEADZONE);
+
+        let keymap = Keymap::parse(text, "TETRIS.CH8").unwrap();
+        assert_eq!(keymap.key_for("Left"), Some(0x5));
+        assert_eq!(keymap.key_for("Up"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        match Keymap::parse("1 = 1\nG = Up\n", "") {
+            Err(KeymapError::Parse { line: 2, .. }) => {}
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert!(Keymap::parse("10 = Up", "").is_err());
+        assert!(Keymap::parse("1 Up", "").is_err());
+        assert!(Keymap::parse("[pong.ch8", "").is_err());
+        assert!(Keymap::parse("deadzone = 2", "").is_err());
+    }
+
+    #[test]
+    fn test_rebinding_moves_a_host_key() {
+        let mut keymap = Keymap::default();
+        keymap.bind(0x5, &["q", "Up"]);
+        assert_eq!(keymap.key_for("Q"), Some(0x5));
+        assert!(keymap.bindings(0x4).is_empty());
+    }
+
+    #[test]
+    fn test_several_host_keys_per_key() {
+        let mut keymap = Keymap::default();
+        keymap.bind(0x5, &["W", "Up"]);
+        let mut keyboard = HostKeyboard::new(keymap);
+        assert_eq!(keyboard.press("W"), Some(KeyEvent::Pressed(0x5)));
+        assert_eq!(keyboard.press("W"), None);
+        assert_eq!(keyboard.press("Up"), None);
+        assert_eq!(keyboard.release("W"), None);
+        assert_eq!(keyboard.release("Up"), Some(KeyEvent::Released(0x5)));
+        assert_eq!(keyboard.release("Up"), None);
+        assert_eq!(keyboard.press("P"), None);
+    }
+
+    #[test]
+    fn test_gamepad_bindings() {
+        let keymap = Keymap::parse(
+            "deadzone = 0.5\n[pong.ch8]\n1 = 1 Axis1- HatUp\n",
+            "pong.ch8",
+        );
+        let keymap = keymap.unwrap();
+        assert_eq!(keymap.deadzone(), 0.5);
+        assert_eq!(keymap.key_for("axis1-"), Some(0x1));
+        assert_eq!(keymap.key_for("HATUP"), Some(0x1));
+    }
+}
@@ -1,9 +1,38 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+#[cfg(all(feature = "tui", unix))]
+extern crate libc;
+#[cfg(feature = "piston")]
 extern crate piston_window;
 extern crate rand;
 
+pub mod analysis;
+pub mod assembler;
+pub mod audio;
+#[cfg(feature = "piston")]
 pub mod chip8;
+pub mod clock;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod framebuffer;
+pub mod frontend;
+pub mod gamepad;
+pub mod gdb;
+pub mod golden;
+pub mod instruction;
+pub mod keymap;
+pub mod machine;
+pub mod movie;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod savestate;
+#[cfg(feature = "piston")]
 pub use chip8::Chip8;
+pub use error::{AssembleError, CpuError, KeymapError, LoadError, MovieError, SaveStateError};
+pub use framebuffer::Framebuffer;
+pub use machine::Machine;
+pub use quirks::Quirks;
@@ -0,0 +1,480 @@
+use audio::AudioGenerator;
+use clock::FrameClock;
+use cpu::{Cpu, StepOutcome, FRAME_RATE};
+use debugger::Debugger;
+use error::{CpuError, LoadError, SaveStateError};
+use framebuffer::Framebuffer;
+use frontend::{AudioSink, Display, KeyEvent, Keypad};
+use movie::{InputEvent, Movie};
+use rewind::Rewind;
+use std::fs;
+use std::path::Path;
+
+/// Windowless emulator core: cpu with its timers, framebuffer and keypad, plus the audio generator
+/// Frontends feed it time and key events and read back the framebuffer and audio samples
+pub struct Machine {
+    cpu: Cpu,
+    audio: AudioGenerator,
+    clock: FrameClock,
+    debugger: Debugger,
+    // snapshot after every frame, `None` unless enabled with `with_rewind`
+    rewind: Option<Rewind>,
+    // frames run since the rom was loaded
+    frame: u64,
+    // key events since the rom was loaded, `None` unless enabled with `with_recording`
+    recording: Option<Movie>,
+}
+
+impl Default for Machine {
+    fn default() -> Machine {
+        Machine::new(Cpu::default().init())
+    }
+}
+
+impl Machine {
+    /// Run an already configured cpu, e.g. with quirks or extended memory
+    pub fn new(cpu: Cpu) -> Machine {
+        Machine::with_audio(cpu, AudioGenerator::default())
+    }
+
+    pub fn with_audio(cpu: Cpu, audio: AudioGenerator) -> Machine {
+        Machine {
+            cpu,
+            audio,
+            clock: FrameClock::default(),
+            debugger: Debugger::default(),
+            rewind: None,
+            frame: 0,
+            recording: None,
+        }
+    }
+
+    /// Record a snapshot after every frame, using at most `memory_limit` bytes
+    pub fn with_rewind(mut self, memory_limit: usize) -> Self {
+        self.rewind = Some(Rewind::new(memory_limit));
+        self
+    }
+
+    /// Record a movie of the key input from the next rom loaded on, see `finish_recording`
+    /// Rewinding, loading states and stepping are disabled while recording,
+    /// the movie could not replay them
+    pub fn with_recording(mut self) -> Self {
+        self.recording = Some(Movie::new(&self.cpu));
+        self
+    }
+
+    pub fn load_rom(&mut self, path: &str) -> Result<(), LoadError> {
+        let bytes = fs::read(path)?;
+
+        info!("Read rom from: {}", path);
+        self.load_bytes(&bytes)?;
+        Ok(())
+    }
+
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), CpuError> {
+        self.cpu.load_bytes(bytes)?;
+        self.frame = 0;
+        // snapshots of the previous rom don't load into this one
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.clear();
+        }
+        if self.recording.is_some() {
+            self.recording = Some(Movie::new(&self.cpu));
+        }
+        Ok(())
+    }
+
+    /// Number of frames run since the rom was loaded
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Stop recording and return the movie, ending with the current state
+    pub fn finish_recording(&mut self) -> Option<Movie> {
+        let mut movie = self.recording.take()?;
+        movie.frames = self.frame;
+        movie.state_hash = self.cpu.state_hash();
+        Some(movie)
+    }
+
+    /// Emulate one 60 Hz frame and render its audio
+    /// Nothing happens while the debugger is paused
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        if self.debugger.is_paused() {
+            return Ok(());
+        }
+        let result = self.debugger.run_frame(&mut self.cpu).map(|_| ());
+        self.frame += 1;
+        self.audio.render(&self.cpu.sound(), 1.0 / FRAME_RATE);
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.push(self.cpu.save_state());
+        }
+        result
+    }
+
+    /// Go back by one recorded frame, returns false when the history is used up,
+    /// rewind is not enabled or a movie is being recorded.
+    /// A snapshot that does not load drops the whole history
+    pub fn rewind_frame(&mut self) -> bool {
+        if self.recording.is_some() {
+            return false;
+        }
+        let state = match self.rewind {
+            Some(ref mut rewind) => rewind.pop(),
+            None => None,
+        };
+        match state.map(|state| self.cpu.load_state(&state)) {
+            Some(Ok(())) => {
+                self.frame = self.frame.saturating_sub(1);
+                true
+            }
+            Some(Err(err)) => {
+                warn!("dropping the rewind history: {}", err);
+                if let Some(ref mut rewind) = self.rewind {
+                    rewind.clear();
+                }
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Go back in time by `dt` seconds of wall time, one recorded frame for every full 1/60 s
+    pub fn rewind(&mut self, dt: f64) {
+        for _ in 0..self.clock.advance(dt) {
+            if !self.rewind_frame() {
+                self.clock.reset();
+                break;
+            }
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Breakpoints are managed here, execution is controlled with the methods below
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    pub fn pause(&mut self) {
+        self.debugger.pause();
+    }
+
+    /// Continue running until the next breakpoint
+    pub fn resume(&mut self) {
+        self.debugger.resume(&self.cpu);
+    }
+
+    /// Execute a single instruction, see `Debugger::step`
+    /// `None` while recording a movie, which can only replay whole frames
+    pub fn step(&mut self) -> Result<Option<StepOutcome>, CpuError> {
+        if self.recording.is_some() {
+            return Ok(None);
+        }
+        self.debugger.step(&mut self.cpu).map(Some)
+    }
+
+    /// Execute an instruction, running 2NNN calls to completion over the next frames
+    /// Returns false while recording a movie, like `step`
+    pub fn step_over(&mut self) -> Result<bool, CpuError> {
+        if self.recording.is_some() {
+            return Ok(false);
+        }
+        self.debugger.step_over(&mut self.cpu).map(|_| true)
+    }
+
+    /// Run until the current subroutine returned, false outside of a subroutine
+    /// or while recording a movie
+    pub fn step_out(&mut self) -> bool {
+        self.recording.is_none() && self.debugger.step_out(&self.cpu)
+    }
+
+    /// Snapshot history, if enabled
+    pub fn rewind_history(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /// Advance by `dt` seconds of wall time, running a frame for every full 1/60 s,
+    /// see `FrameClock` for how long gaps are handled
+    pub fn update(&mut self, dt: f64) -> Result<(), CpuError> {
+        for _ in 0..self.clock.advance(dt) {
+            self.run_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Emulate one frame against a frontend: deliver pending key events,
+    /// run the frame, then present the framebuffer and queue the new audio samples
+    pub fn run_frame_with<D, K, A>(
+        &mut self,
+        display: &mut D,
+        keypad: &mut K,
+        audio: &mut A,
+    ) -> Result<(), CpuError>
+    where
+        D: Display,
+        K: Keypad,
+        A: AudioSink,
+    {
+        for event in keypad.poll() {
+            match event {
+                KeyEvent::Pressed(key) => self.handle_key_press(key),
+                KeyEvent::Released(key) => self.handle_key_release(key),
+            }
+        }
+
+        let result = self.run_frame();
+
+        display.present(self.framebuffer());
+        let mut samples = vec![0.0; self.audio.available()];
+        self.audio.pull(&mut samples);
+        audio.queue(&samples);
+        result
+    }
+
+    /// Like `update`, but every frame talks to the given frontend
+    pub fn update_with<D, K, A>(
+        &mut self,
+        dt: f64,
+        display: &mut D,
+        keypad: &mut K,
+        audio: &mut A,
+    ) -> Result<(), CpuError>
+    where
+        D: Display,
+        K: Keypad,
+        A: AudioSink,
+    {
+        for _ in 0..self.clock.advance(dt) {
+            self.run_frame_with(display, keypad, audio)?;
+        }
+        Ok(())
+    }
+
+    pub fn handle_key_press(&mut self, key: u8) {
+        self.record(key, true);
+        self.cpu.handle_key_press(key);
+    }
+
+    pub fn handle_key_release(&mut self, key: u8) {
+        self.record(key, false);
+        self.cpu.handle_key_release(key);
+    }
+
+    fn record(&mut self, key: u8, pressed: bool) {
+        if let Some(ref mut movie) = self.recording {
+            movie.events.push(InputEvent {
+                frame: self.frame,
+                key,
+                pressed,
+            });
+        }
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        self.cpu.framebuffer()
+    }
+
+    /// Write the cpu state to `path`, see `Cpu::save_state`
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        fs::write(path.as_ref(), self.cpu.save_state())?;
+        info!("Saved state to: {}", path.as_ref().display());
+        Ok(())
+    }
+
+    /// Restore the cpu state from `path`, it has to be saved with the loaded rom
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        if self.recording.is_some() {
+            return Err(SaveStateError::Recording);
+        }
+        let data = fs::read(path.as_ref())?;
+        self.cpu.load_state(&data)?;
+        info!("Loaded state from: {}", path.as_ref().display());
+        Ok(())
+    }
+
+    /// Samples rendered so far, for an audio backend to pull from
+    pub fn audio_mut(&mut self) -> &mut AudioGenerator {
+        &mut self.audio
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use audio::DEFAULT_SAMPLE_RATE;
+    use clock::MAX_FRAMES_PER_ADVANCE;
+    use frontend::memory::{MemoryAudio, MemoryDisplay, MemoryKeypad};
+    use machine::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_run_frame_headless() {
+        let mut machine = Machine::default();
+        // I := sprite for 0, draw at 0,0, loop
+        machine
+            .load_bytes(&[0xA0, 0x00, 0xD0, 0x05, 0x12, 0x04])
+            .unwrap();
+        machine.run_frame().unwrap();
+        assert_eq!(machine.framebuffer().get(0, 0), 1);
+        assert_eq!(machine.framebuffer().get(1, 1), 0);
+    }
+
+    #[test]
+    fn test_update_renders_audio_per_frame() {
+        let mut machine = Machine::default();
+        // V0 := 60, sound timer := V0, loop
+        machine
+            .load_bytes(&[0x60, 0x3C, 0xF0, 0x18, 0x12, 0x04])
+            .unwrap();
+        for _ in 0..10 {
+            machine.update(3.0 / 60.0).unwrap();
+        }
+        let samples_per_frame = DEFAULT_SAMPLE_RATE as usize / 60;
+        assert_eq!(machine.audio_mut().available(), 30 * samples_per_frame);
+
+        // a long stall only runs a few frames
+        machine.update(10.0).unwrap();
+        assert_eq!(machine.frame(), 30 + MAX_FRAMES_PER_ADVANCE as u64);
+
+        let mut out = vec![0.0; samples_per_frame];
+        machine.audio_mut().pull(&mut out);
+        assert!(out.iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn test_save_state_file() {
+        let path = env::temp_dir().join(format!("chip8-test-{}.state", process::id()));
+        let mut machine = Machine::default();
+        // V0 := 1, loop
+        machine.load_bytes(&[0x60, 0x01, 0x12, 0x02]).unwrap();
+        machine.run_frame().unwrap();
+        machine.save_state(&path).unwrap();
+
+        let mut restored = Machine::default();
+        restored.load_bytes(&[0x60, 0x01, 0x12, 0x02]).unwrap();
+        restored.load_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.cpu().registers()[0], 1);
+        assert_eq!(restored.cpu().pc(), 0x202);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut machine = Machine::default().with_rewind(1 << 20);
+        // V0 += 1, loop
+        machine.load_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        for _ in 0..10 {
+            machine.run_frame().unwrap();
+        }
+        let after_ten_frames = machine.cpu().registers()[0];
+        machine.run_frame().unwrap();
+        machine.run_frame().unwrap();
+
+        assert!(machine.rewind_frame());
+        assert!(machine.rewind_frame());
+        assert_eq!(machine.cpu().registers()[0], after_ten_frames);
+
+        let history = machine.rewind_history().unwrap().len();
+        machine.rewind(1.0);
+        assert_eq!(
+            machine.rewind_history().unwrap().len(),
+            history - MAX_FRAMES_PER_ADVANCE as usize
+        );
+        machine.rewind(1.0);
+        machine.rewind(1.0);
+        assert_eq!(machine.rewind_history().unwrap().len(), 0);
+        assert!(!machine.rewind_frame());
+        // back at the first frame
+        assert_eq!(machine.cpu().registers()[0], 5);
+    }
+
+    #[test]
+    fn test_rewind_after_loading_another_rom() {
+        let mut machine = Machine::default().with_rewind(1 << 20);
+        machine.load_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        for _ in 0..5 {
+            machine.run_frame().unwrap();
+        }
+        machine.load_bytes(&[0x71, 0x01, 0x12, 0x00]).unwrap();
+        assert!(!machine.rewind_frame());
+        machine.run_frame().unwrap();
+        machine.run_frame().unwrap();
+        assert!(machine.rewind_frame());
+        assert_eq!(machine.cpu().registers()[1], 5);
+    }
+
+    #[test]
+    fn test_no_stepping_while_recording() {
+        let mut machine = Machine::default().with_recording();
+        // V0 += 1, loop
+        machine.load_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        machine.pause();
+        assert_eq!(machine.step(), Ok(None));
+        assert_eq!(machine.step_over(), Ok(false));
+        assert!(!machine.step_out());
+        assert_eq!(machine.cpu().pc(), 0x200);
+
+        let mut machine = Machine::default();
+        machine.load_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        machine.pause();
+        assert_eq!(machine.step(), Ok(Some(StepOutcome::Executed)));
+        assert_eq!(machine.cpu().pc(), 0x202);
+    }
+
+    #[test]
+    fn test_keys_reach_cpu() {
+        let mut machine = Machine::default();
+        // V0 := key, then loop
+        machine.load_bytes(&[0xF0, 0x0A, 0x12, 0x02]).unwrap();
+        machine.run_frame().unwrap();
+        assert!(machine.cpu().is_waiting_for_key());
+        machine.handle_key_press(0x5);
+        machine.handle_key_release(0x5);
+        assert!(!machine.cpu().is_waiting_for_key());
+    }
+
+    #[test]
+    fn test_run_frame_with_memory_frontend() {
+        let mut machine = Machine::default();
+        let mut display = MemoryDisplay::default();
+        let mut keypad = MemoryKeypad::default();
+        let mut audio = MemoryAudio::default();
+        // V0 := key, I := sprite for V0, draw it, V1 := 30, sound timer := V1, loop
+        machine
+            .load_bytes(&[
+                0xF0, 0x0A, 0xF0, 0x29, 0xD1, 0x15, 0x61, 0x1E, 0xF1, 0x18, 0x12, 0x0A,
+            ])
+            .unwrap();
+
+        machine
+            .run_frame_with(&mut display, &mut keypad, &mut audio)
+            .unwrap();
+        assert!(machine.cpu().is_waiting_for_key());
+        keypad.press(0x1);
+        keypad.release(0x1);
+        machine
+            .update_with(1.0 / 60.0, &mut display, &mut keypad, &mut audio)
+            .unwrap();
+
+        assert_eq!(display.frames_presented(), 2);
+        // the top row of the 1 glyph is ..#.
+        assert_eq!(display.frame().unwrap().get(0, 0), 0);
+        assert_eq!(display.frame().unwrap().get(2, 0), 1);
+        assert_eq!(
+            audio.samples().len(),
+            2 * (DEFAULT_SAMPLE_RATE as usize / 60)
+        );
+        assert!(audio.samples().iter().any(|&sample| sample != 0.0));
+    }
+}
@@ -1,20 +1,289 @@
 extern crate chip8;
 extern crate env_logger;
 
+mod cli;
+
+use chip8::analysis::Analysis;
+use chip8::assembler;
+use chip8::disasm;
+use chip8::frontend::prompt;
+use chip8::gdb;
+use chip8::golden;
+use chip8::instruction::Platform;
+use chip8::keymap::Keymap;
+use chip8::movie;
+use chip8::movie::Movie;
+use chip8::savestate;
+#[cfg(feature = "piston")]
 use chip8::Chip8;
+use chip8::Machine;
+use cli::{Cli, Command, Disassembly, Frontend, MachineOptions, RunOptions, TestOptions};
 
 use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
 
 fn main() {
-    env_logger::init();
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Cli { command, log } = match cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(filters) = log {
+        logger.parse(&filters);
+    }
+    logger.init();
+
+    match command {
+        Command::Run(options) => run(options),
+        Command::Info(options) => run_info(&options),
+        Command::Test(options) => run_test(&options),
+        Command::Disasm { rom, output } => run_disasm(&rom, output),
+        Command::Asm { source, output } => run_asm(&source, &output),
+        Command::Help => print!("{}", cli::USAGE),
+    }
+}
+
+fn fail<T>(message: String) -> T {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+/// A machine with the configured cpu and the rom loaded
+fn load_machine(options: &MachineOptions) -> Machine {
+    let rom = options.read_rom().unwrap_or_else(fail);
+    let cpu = options.cpu().unwrap_or_else(fail);
+    let mut machine = Machine::new(cpu);
+    if let Err(err) = machine.load_bytes(&rom) {
+        fail::<()>(format!("{}: {}", options.rom, err));
+    }
+    machine
+}
+
+fn run(options: RunOptions) {
+    match options.frontend {
+        Frontend::Window => run_window(options),
+        Frontend::Terminal => run_tui(options),
+        Frontend::Headless { frames } => {
+            let mut machine = load_machine(&options.machine);
+            run_frames(&mut machine, frames, &options.machine.rom);
+            print!("{}", golden::render(machine.framebuffer()));
+        }
+        Frontend::Debugger => run_debugger(load_machine(&options.machine)),
+        Frontend::Gdb(port) => run_gdb(load_machine(&options.machine), port),
+        Frontend::Replay(ref movie_path) => run_movie(load_machine(&options.machine), movie_path),
+    }
+}
+
+/// Run `frames` frames, a halt ends the process
+fn run_frames(machine: &mut Machine, frames: u64, rom_path: &str) {
+    for _ in 0..frames {
+        if let Err(err) = machine.run_frame() {
+            fail::<()>(format!(
+                "{}: halted in frame {}: {}",
+                rom_path,
+                machine.frame(),
+                err
+            ));
+        }
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "piston", all(feature = "tui", unix))),
+    allow(dead_code)
+)]
+fn keymap(options: &RunOptions) -> Keymap {
+    match options.keymap {
+        Some(ref path) => Keymap::load(path, &options.machine.rom)
+            .unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err))),
+        None => Keymap::default(),
+    }
+}
+
+fn run_info(options: &MachineOptions) {
+    let rom = options.read_rom().unwrap_or_else(fail);
+    let cpu = options.cpu().unwrap_or_else(fail);
+    let analysis = Analysis::with_start(&rom, cpu.start_address());
+    let platform = analysis
+        .code()
+        .values()
+        .map(|instruction| instruction.platform())
+        .max()
+        .unwrap_or(Platform::Chip8);
+    let (platform, preset) = match platform {
+        Platform::Chip8 => ("CHIP-8", "vip"),
+        Platform::SuperChip => ("SUPER-CHIP", "schip"),
+        Platform::XoChip => ("XO-CHIP", "xochip"),
+    };
+
+    println!("rom:          {}", options.rom);
+    println!(
+        "size:         {} bytes, 0x{:03X} to 0x{:03X}",
+        rom.len(),
+        cpu.start_address(),
+        cpu.start_address() as usize + rom.len().max(1) - 1
+    );
+    println!("hash:         {:016x}", savestate::rom_hash(&rom));
+    println!("platform:     {}, try --quirks {}", platform, preset);
+    println!("instructions: {}", analysis.code().len());
+    println!("subroutines:  {}", analysis.subroutines().len());
+    println!("jump targets: {}", analysis.jump_targets().len());
+    println!("sprites:      {}", analysis.sprites().len());
+}
+
+fn run_test(options: &TestOptions) {
+    let mut machine = load_machine(&options.machine);
+    run_frames(&mut machine, options.frames, &options.machine.rom);
+    let screen = golden::render(machine.framebuffer());
+
+    let expect = match options.expect {
+        Some(ref expect) => expect,
+        None => {
+            print!("{}", screen);
+            return;
+        }
+    };
+    if options.update {
+        if let Err(err) = fs::write(expect, &screen) {
+            fail::<()>(format!("{}: {}", expect.display(), err));
+        }
+        return;
+    }
+    let expected = fs::read_to_string(expect)
+        .unwrap_or_else(|err| fail(format!("{}: {}", expect.display(), err)));
+    match golden::diff(&expected, &screen) {
+        None => println!(
+            "{}: screen matches after {} frames",
+            options.machine.rom, options.frames
+        ),
+        Some(diff) => fail(format!(
+            "{}: screen differs from {} after {} frames\n{}",
+            options.machine.rom,
+            expect.display(),
+            options.frames,
+            diff
+        )),
+    }
+}
 
-    let mut rom_path = &String::from("roms/pong.ch8");
-    if args.len() == 2 {
-        rom_path = &args[1];
+fn run_asm(source_path: &str, output: &Path) {
+    let source = fs::read_to_string(source_path)
+        .unwrap_or_else(|err| fail(format!("{}: {}", source_path, err)));
+    let rom =
+        assembler::assemble(&source).unwrap_or_else(|err| fail(format!("{}:{}", source_path, err)));
+    if let Err(err) = fs::write(output, &rom) {
+        fail::<()>(format!("{}: {}", output.display(), err));
     }
+}
+
+fn run_disasm(rom_path: &str, output: Disassembly) {
+    let rom = cli::read_rom(rom_path).unwrap_or_else(fail);
+    match output {
+        Disassembly::Linear(syntax) => print!("{}", disasm::disassemble(&rom, syntax)),
+        Disassembly::Labelled(syntax) => print!("{}", Analysis::new(&rom).listing(syntax)),
+        Disassembly::Dot => print!("{}", Analysis::new(&rom).dot()),
+    }
+}
+
+fn run_debugger(mut machine: Machine) {
+    let stdin = io::stdin();
+    if let Err(err) = prompt::run(&mut machine, stdin.lock(), io::stdout()) {
+        fail::<()>(format!("debugger: {}", err));
+    }
+}
+
+fn run_gdb(mut machine: Machine, port: u16) {
+    println!("waiting for gdb on 127.0.0.1:{}", port);
+    if let Err(err) = gdb::serve(&mut machine, port) {
+        fail::<()>(format!("gdb: {}", err));
+    }
+}
+
+fn run_movie(mut machine: Machine, movie_path: &Path) {
+    let movie = Movie::load(movie_path)
+        .unwrap_or_else(|err| fail(format!("{}: {}", movie_path.display(), err)));
+    match movie::replay(&mut machine, &movie) {
+        Ok(None) => println!("replayed {} frames, final state matches", movie.frames),
+        Ok(Some((frame, err))) => println!(
+            "replayed {} frames, final state matches, halted in frame {}: {}",
+            movie.frames, frame, err
+        ),
+        Err(err) => fail(format!("{}: {}", movie_path.display(), err)),
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "piston", all(feature = "tui", unix))),
+    allow(dead_code)
+)]
+fn save_movie(machine: &mut Machine, movie_path: &Path) {
+    if let Some(movie) = machine.finish_recording() {
+        if let Err(err) = movie.save(movie_path) {
+            fail::<()>(format!("{}: {}", movie_path.display(), err));
+        }
+    }
+}
 
-    let mut c8 = Chip8::new();
-    c8.load_rom(rom_path);
+#[cfg(feature = "piston")]
+fn run_window(options: RunOptions) {
+    let rom_path = &options.machine.rom;
+    // read it here first for the errors about missing roms
+    options.machine.read_rom().unwrap_or_else(fail);
+    let cpu = options.machine.cpu().unwrap_or_else(fail);
+    let mut c8 = match options.record {
+        Some(_) => Chip8::with_machine(Machine::new(cpu).with_recording()),
+        None => Chip8::with_cpu(cpu),
+    }
+    .with_keymap(keymap(&options))
+    .with_scale(options.scale)
+    .with_palette(options.palette);
+    if let Err(err) = c8.load_rom(rom_path) {
+        fail::<()>(format!("{}: {}", rom_path, err));
+    }
     c8.run();
+    if let Some(ref movie_path) = options.record {
+        save_movie(c8.machine_mut(), movie_path);
+    }
+}
+
+#[cfg(not(feature = "piston"))]
+fn run_window(_options: RunOptions) {
+    fail::<()>(
+        "chip8 was built without a frontend, enable the `piston` feature to open a window, \
+         or use --headless"
+            .to_string(),
+    );
+}
+
+#[cfg(all(feature = "tui", unix))]
+fn run_tui(options: RunOptions) {
+    let rom_path = &options.machine.rom;
+    let mut machine = load_machine(&options.machine);
+    if options.record.is_some() {
+        machine = machine.with_recording();
+    }
+    let result = chip8::frontend::terminal::run(&mut machine, keymap(&options));
+    if let Some(ref movie_path) = options.record {
+        save_movie(&mut machine, movie_path);
+    }
+    match result {
+        Ok(Some(err)) => fail(format!("{}: halted: {}", rom_path, err)),
+        Ok(None) => {}
+        Err(err) => fail(format!("terminal: {}", err)),
+    }
+}
+
+#[cfg(not(all(feature = "tui", unix)))]
+fn run_tui(_options: RunOptions) {
+    fail::<()>(
+        "chip8 was built without the terminal frontend, enable the `tui` feature".to_string(),
+    );
 }
@@ -0,0 +1,435 @@
+use cpu::Cpu;
+use error::{CpuError, MovieError};
+use machine::Machine;
+use quirks::Quirks;
+use std::fs;
+use std::path::Path;
+
+const HEADER: &str = "chip8-movie 2";
+
+/// A key going down or up before the frame with the given number runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputEvent {
+    /// Frames run since the rom was loaded
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Everything needed to replay a run from power on: the rom, the cpu settings, the random seed
+/// and the key input. The state hash at the end tells whether a replay went the same way.
+///
+/// Stored as text, one event per line:
+///
+/// ```text
+/// chip8-movie 2
+/// rom 5F1B0A3C2E4D6F70
+/// seed 0000000000000001
+/// quirks shift_uses_vy vf_reset clip_sprites
+/// ipf 10
+/// start 200
+/// random splitmix
+/// frames 300
+/// state 0123456789ABCDEF
+/// 12 press 5
+/// 40 release 5
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    pub start_address: u16,
+    /// `Cpu::random_source`
+    pub random_source: String,
+    /// Ordered by frame
+    pub events: Vec<InputEvent>,
+    /// Length of the recording
+    pub frames: u64,
+    /// `Cpu::state_hash` after the last frame
+    pub state_hash: u64,
+}
+
+impl Movie {
+    /// An empty movie of the rom loaded into `cpu`, with its settings
+    pub fn new(cpu: &Cpu) -> Movie {
+        Movie {
+            rom_hash: cpu.rom_hash(),
+            seed: cpu.seed(),
+            quirks: *cpu.quirks(),
+            instructions_per_frame: cpu.instructions_per_frame(),
+            start_address: cpu.start_address(),
+            random_source: cpu.random_source().to_string(),
+            events: Vec::new(),
+            frames: 0,
+            state_hash: 0,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "{}\nrom {:016X}\nseed {:016X}\nquirks {}\nipf {}\nstart {:03X}\nrandom {}\n\
+             frames {}\nstate {:016X}\n",
+            HEADER,
+            self.rom_hash,
+            self.seed,
+            quirk_list(&self.quirks),
+            self.instructions_per_frame,
+            self.start_address,
+            self.random_source,
+            self.frames,
+            self.state_hash
+        );
+        for event in &self.events {
+            let action = if event.pressed { "press" } else { "release" };
+            text.push_str(&format!("{} {} {:X}\n", event.frame, action, event.key));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line));
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            _ => return parse_error(1, format!("expected '{}'", HEADER)),
+        }
+
+        let end = text.lines().count() + 1;
+        let mut header = |name: &str| -> Result<(usize, &str), MovieError> {
+            let (number, line) = match lines.next() {
+                Some(line) => line,
+                None => return parse_error(end, format!("missing '{}'", name)),
+            };
+            match line.strip_prefix(name) {
+                Some(value) if value.is_empty() || value.starts_with(' ') => {
+                    Ok((number, value.trim()))
+                }
+                _ => parse_error(number, format!("expected '{}'", name)),
+            }
+        };
+        let number = |(line, value): (usize, &str), radix| -> Result<u64, MovieError> {
+            u64::from_str_radix(value, radix)
+                .or_else(|_| parse_error(line, format!("expected a number, got '{}'", value)))
+        };
+
+        let rom_hash = number(header("rom")?, 16)?;
+        let seed = number(header("seed")?, 16)?;
+        let (line, names) = header("quirks")?;
+        let quirks =
+            match Quirks::from_names(names.split_whitespace().filter(|&name| name != "none")) {
+                Some(quirks) => quirks,
+                None => return parse_error(line, format!("unknown quirk in '{}'", names)),
+            };
+        let instructions_per_frame = number(header("ipf")?, 10)?;
+        let (line, start_address) = header("start")?;
+        let start_address = match u16::from_str_radix(start_address, 16) {
+            Ok(address) => address,
+            Err(_) => return parse_error(line, "expected a 16 bit start address"),
+        };
+        let random_source = header("random")?.1.to_string();
+        let frames = number(header("frames")?, 10)?;
+        let (last_header, state_hash) = header("state")?;
+        let state_hash = number((last_header, state_hash), 16)?;
+
+        let mut movie = Movie {
+            rom_hash,
+            seed,
+            quirks,
+            instructions_per_frame: instructions_per_frame as u32,
+            start_address,
+            random_source,
+            events: Vec::new(),
+            frames,
+            state_hash,
+        };
+
+        let mut last_frame = 0;
+        for (number, line) in text.lines().enumerate().skip(last_header) {
+            let number = number + 1;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let event = match words.as_slice() {
+                [] => continue,
+                [frame, action, key] => {
+                    let frame = frame.parse().ok();
+                    let pressed = match *action {
+                        "press" => Some(true),
+                        "release" => Some(false),
+                        _ => None,
+                    };
+                    let key = u8::from_str_radix(key, 16).ok().filter(|&key| key < 16);
+                    match (frame, pressed, key) {
+                        (Some(frame), Some(pressed), Some(key)) => InputEvent {
+                            frame,
+                            key,
+                            pressed,
+                        },
+                        _ => return parse_error(number, "expected '<frame> press|release <key>'"),
+                    }
+                }
+                _ => return parse_error(number, "expected '<frame> press|release <key>'"),
+            };
+            if event.frame < last_frame || event.frame > movie.frames {
+                return parse_error(number, format!("frame {} is out of order", event.frame));
+            }
+            last_frame = event.frame;
+            movie.events.push(event);
+        }
+        Ok(movie)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        fs::write(path.as_ref(), self.to_text())?;
+        info!("Saved movie to: {}", path.as_ref().display());
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::parse(&fs::read_to_string(path)?)
+    }
+}
+
+fn parse_error<T, S: Into<String>>(line: usize, message: S) -> Result<T, MovieError> {
+    Err(MovieError::Parse {
+        line,
+        message: message.into(),
+    })
+}
+
+/// Replay `movie` on a machine that just loaded its rom, feeding every key event in before
+/// the frame it was recorded in. Returns the frame and error of a halt the replay ran into,
+/// a crash in the recording shows up here again.
+/// The machine has to be set up like the recording one, apart from the seed
+pub fn replay(machine: &mut Machine, movie: &Movie) -> Result<Option<(u64, CpuError)>, MovieError> {
+    let rom_hash = machine.cpu().rom_hash();
+    if rom_hash != movie.rom_hash {
+        return Err(MovieError::RomMismatch {
+            expected: movie.rom_hash,
+            found: rom_hash,
+        });
+    }
+    check_settings(machine.cpu(), movie)?;
+    machine.cpu_mut().set_seed(movie.seed);
+
+    let mut halt = None;
+    let mut events = movie.events.iter().peekable();
+    for frame in 0..movie.frames {
+        while let Some(event) = events.next_if(|event| event.frame == frame) {
+            if event.pressed {
+                machine.handle_key_press(event.key);
+            } else {
+                machine.handle_key_release(event.key);
+            }
+        }
+        if let Err(err) = machine.run_frame() {
+            if halt.is_none() {
+                halt = Some((frame, err));
+            }
+        }
+    }
+    // input after the last frame still changes the keypad
+    for event in events {
+        if event.pressed {
+            machine.handle_key_press(event.key);
+        } else {
+            machine.handle_key_release(event.key);
+        }
+    }
+
+    let state_hash = machine.cpu().state_hash();
+    if state_hash != movie.state_hash {
+        return Err(MovieError::Desync {
+            expected: movie.state_hash,
+            found: state_hash,
+        });
+    }
+    Ok(halt)
+}
+
+/// Names of the quirks that are on, `none` without any
+fn quirk_list(quirks: &Quirks) -> String {
+    match quirks.names().join(" ") {
+        ref names if names.is_empty() => "none".to_string(),
+        names => names,
+    }
+}
+
+/// The cpu settings that change how a replay goes have to match the recording
+fn check_settings(cpu: &Cpu, movie: &Movie) -> Result<(), MovieError> {
+    let settings = [
+        (
+            "quirks",
+            quirk_list(&movie.quirks),
+            quirk_list(cpu.quirks()),
+        ),
+        (
+            "instructions per frame",
+            movie.instructions_per_frame.to_string(),
+            cpu.instructions_per_frame().to_string(),
+        ),
+        (
+            "start address",
+            format!("0x{:03X}", movie.start_address),
+            format!("0x{:03X}", cpu.start_address()),
+        ),
+        (
+            "random source",
+            movie.random_source.clone(),
+            cpu.random_source().to_string(),
+        ),
+    ];
+    for (setting, recorded, found) in settings.iter().cloned() {
+        if recorded != found {
+            return Err(MovieError::SettingMismatch {
+                setting,
+                recorded,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use movie::*;
+    use random::Sequence;
+
+    // V0 := random, V1 := key, draw the digit in V1 at V0,0, loop
+    const ROM: [u8; 10] = [0xC0, 0x3F, 0xF1, 0x0A, 0xF1, 0x29, 0xD0, 0x15, 0x12, 0x00];
+
+    fn record() -> Movie {
+        let mut machine = Machine::new(Cpu::default().init()).with_recording();
+        machine.load_bytes(&ROM).unwrap();
+        for frame in 0..30 {
+            if frame % 10 == 3 {
+                machine.handle_key_press(frame / 10 + 1);
+            }
+            if frame % 10 == 5 {
+                machine.handle_key_release(frame / 10 + 1);
+            }
+            machine.run_frame().unwrap();
+        }
+        machine.finish_recording().unwrap()
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let movie = record();
+        assert_eq!(movie.frames, 30);
+        assert_eq!(movie.events.len(), 6);
+        assert_eq!(
+            movie.events[0],
+            InputEvent {
+                frame: 3,
+                key: 1,
+                pressed: true,
+            }
+        );
+
+        // a different random seed on the replaying machine doesn't matter
+        let mut machine = Machine::new(Cpu::default().init().with_seed(!movie.seed));
+        machine.load_bytes(&ROM).unwrap();
+        assert!(replay(&mut machine, &movie).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_replay_detects_desync() {
+        let mut movie = record();
+        movie.events.remove(0);
+        let mut machine = Machine::default();
+        machine.load_bytes(&ROM).unwrap();
+        match replay(&mut machine, &movie) {
+            Err(MovieError::Desync { .. }) => {}
+            other => panic!("expected a desync, got {:?}", other),
+        }
+
+        let mut machine = Machine::default();
+        machine.load_bytes(&[0x12, 0x00]).unwrap();
+        match replay(&mut machine, &movie) {
+            Err(MovieError::RomMismatch { .. }) => {}
+            other => panic!("expected a rom mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_checks_settings() {
+        let movie = record();
+        let mismatch = |cpu: Cpu| {
+            let mut machine = Machine::new(cpu.init());
+            machine.load_bytes(&ROM).unwrap();
+            match replay(&mut machine, &movie) {
+                Err(MovieError::SettingMismatch { setting, .. }) => setting,
+                other => panic!("expected a setting mismatch, got {:?}", other),
+            }
+        };
+        assert_eq!(
+            mismatch(Cpu::default().with_quirks(Quirks::chip48())),
+            "quirks"
+        );
+        assert_eq!(
+            mismatch(Cpu::default().with_instructions_per_frame(20)),
+            "instructions per frame"
+        );
+        assert_eq!(
+            mismatch(Cpu::default().with_start_address(0x600)),
+            "start address"
+        );
+        assert_eq!(
+            mismatch(Cpu::default().with_random_source(Sequence::new(&[1]))),
+            "random source"
+        );
+
+        let mut machine = Machine::new(Cpu::default().with_quirks(Quirks::chip48()).init());
+        machine.load_bytes(&ROM).unwrap();
+        assert_eq!(
+            replay(&mut machine, &movie).unwrap_err().to_string(),
+            "movie was recorded with quirks load_store_increments_i, \
+             but this run uses clip_sprites jump_with_vx"
+        );
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let movie = record();
+        assert_eq!(Movie::parse(&movie.to_text()).unwrap(), movie);
+        assert!(movie.to_text().contains("\n13 press 2\n15 release 2\n"));
+
+        let header = "chip8-movie 2\nrom 1\nseed 2\nquirks none\nipf 10\nstart 200\n\
+                      random splitmix\nframes 10\nstate 3\n";
+        let movie = Movie::parse(header).unwrap();
+        assert_eq!(movie.quirks.names(), Vec::<&str>::new());
+        assert_eq!(movie.start_address, 0x200);
+        match Movie::parse(&format!("{}5 press 1\n4 press 2\n", header)) {
+            Err(MovieError::Parse { line: 11, .. }) => {}
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        match Movie::parse(&header.replace("none", "vf_reset wobble")) {
+            Err(MovieError::Parse { line: 4, .. }) => {}
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert!(Movie::parse("chip8-movie 1\n").is_err());
+    }
+
+    #[test]
+    fn test_replay_reproduces_halt() {
+        // waits for a key, then returns with an empty stack
+        let rom = [0xF0, 0x0A, 0x00, 0xEE];
+        let mut machine = Machine::default().with_recording();
+        machine.load_bytes(&rom).unwrap();
+        machine.run_frame().unwrap();
+        machine.handle_key_press(7);
+        machine.handle_key_release(7);
+        assert!(machine.run_frame().is_err());
+        let movie = machine.finish_recording().unwrap();
+
+        let mut machine = Machine::default();
+        machine.load_bytes(&rom).unwrap();
+        let (frame, err) = replay(&mut machine, &movie).unwrap().unwrap();
+        assert_eq!(frame, 1);
+        assert_eq!(err, CpuError::StackUnderflow { pc: 0x202 });
+    }
+}
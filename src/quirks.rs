@@ -0,0 +1,129 @@
+/// Behaviour of instructions whose semantics differ between CHIP-8 platforms
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VY and store the result in VX, instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    /// FX55/FX65 leave I pointing behind the last register stored or loaded
+    pub load_store_increments_i: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0
+    pub vf_reset: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    /// DXYN waits for the vertical blank, so at most one sprite is drawn per frame
+    pub display_wait: bool,
+    /// BNNN is read as BXNN and jumps to XNN + VX
+    pub jump_with_vx: bool,
+    /// FX1E sets VF to 1 when I leaves the 12 bit address range, and to 0 when it doesn't
+    pub add_to_i_sets_vf: bool,
+}
+
+/// Matches the behaviour this interpreter had before quirks were configurable
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            jump_with_vx: false,
+            add_to_i_sets_vf: false,
+        }
+    }
+}
+
+impl Quirks {
+    /// The original interpreter on the RCA COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+            jump_with_vx: false,
+            add_to_i_sets_vf: false,
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+            jump_with_vx: true,
+            add_to_i_sets_vf: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+            jump_with_vx: true,
+            add_to_i_sets_vf: false,
+        }
+    }
+
+    /// XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            jump_with_vx: false,
+            add_to_i_sets_vf: false,
+        }
+    }
+
+    /// Names of the quirks that are on, in declaration order, as written to movies
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut quirks = *self;
+        quirks
+            .flags()
+            .iter()
+            .filter(|flag| *flag.1)
+            .map(|flag| flag.0)
+            .collect()
+    }
+
+    /// Quirks with only the named ones on, `None` for an unknown name
+    pub fn from_names<'a, I: IntoIterator<Item = &'a str>>(names: I) -> Option<Quirks> {
+        let mut quirks = Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            jump_with_vx: false,
+            add_to_i_sets_vf: false,
+        };
+        for name in names {
+            let mut flags = quirks.flags();
+            let flag = flags.iter_mut().find(|&&mut (flag, _)| flag == name)?;
+            *flag.1 = true;
+        }
+        Some(quirks)
+    }
+
+    fn flags(&mut self) -> [(&'static str, &mut bool); 7] {
+        [
+            ("shift_uses_vy", &mut self.shift_uses_vy),
+            ("load_store_increments_i", &mut self.load_store_increments_i),
+            ("vf_reset", &mut self.vf_reset),
+            ("clip_sprites", &mut self.clip_sprites),
+            ("display_wait", &mut self.display_wait),
+            ("jump_with_vx", &mut self.jump_with_vx),
+            ("add_to_i_sets_vf", &mut self.add_to_i_sets_vf),
+        ]
+    }
+}
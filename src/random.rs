@@ -0,0 +1,260 @@
+use error::SaveStateError;
+use savestate::{StateReader, StateWriter};
+
+/// Size of the COSMAC VIP interpreter, which lives below the program at 0x000-0x1FF
+pub const VIP_INTERPRETER_SIZE: usize = 0x200;
+
+/// Where `CXNN` gets its random numbers from
+pub trait RandomSource {
+    /// The next random byte, before it is masked with NN
+    fn next_byte(&mut self) -> u8;
+
+    /// Restart from `seed`, sources without a seed start over
+    fn reseed(&mut self, seed: u64);
+
+    /// Write the generator state, so a loaded state continues with the same numbers
+    fn save_state(&self, writer: &mut StateWriter);
+
+    /// Restore a state written by `save_state`, `reader` holds nothing else.
+    /// The source is left untouched on errors
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+
+    /// Identifies the kind of source in movies
+    fn name(&self) -> &'static str;
+}
+
+/// SplitMix64, the default source. The whole state is one 64 bit counter
+#[derive(Clone, Debug, PartialEq)]
+pub struct SplitMix {
+    state: u64,
+}
+
+impl SplitMix {
+    pub fn new(seed: u64) -> SplitMix {
+        SplitMix { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SplitMix {
+    fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u64(self.state);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let state = reader.u64()?;
+        finish(reader)?;
+        self.state = state;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "splitmix"
+    }
+}
+
+/// Hands out the given bytes in order and starts over after the last one, for tests
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sequence {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl Sequence {
+    /// Panics if `bytes` is empty
+    pub fn new(bytes: &[u8]) -> Sequence {
+        assert!(
+            !bytes.is_empty(),
+            "a random sequence needs at least one byte"
+        );
+        Sequence {
+            bytes: bytes.to_vec(),
+            position: 0,
+        }
+    }
+}
+
+impl RandomSource for Sequence {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.position];
+        self.position = (self.position + 1) % self.bytes.len();
+        byte
+    }
+
+    fn reseed(&mut self, _seed: u64) {
+        self.position = 0;
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u32(self.position as u32);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let position = reader.u32()? as usize;
+        if position >= self.bytes.len() {
+            return Err(SaveStateError::Corrupt("invalid random sequence position"));
+        }
+        finish(reader)?;
+        self.position = position;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "sequence"
+    }
+}
+
+/// The random routine of the original COSMAC VIP interpreter.
+/// It steps register R9, adds the interpreter byte at 0x100 + R9.0 to R9.1 and mixes the sum
+/// with itself shifted right, the result becomes the new R9.1. The numbers depend on the
+/// interpreter code it reads, which isn't distributed with this crate, so a dump has to be supplied
+#[derive(Clone)]
+pub struct VipRandom {
+    // page 1 of the interpreter, which holds the routine
+    page: [u8; 256],
+    r9: u16,
+}
+
+impl VipRandom {
+    /// Use the interpreter in `dump`, the first 512 bytes of VIP memory.
+    /// Returns `None` if the dump is too short
+    pub fn from_interpreter(dump: &[u8]) -> Option<VipRandom> {
+        if dump.len() < VIP_INTERPRETER_SIZE {
+            return None;
+        }
+        let mut page = [0; 256];
+        page.copy_from_slice(&dump[0x100..VIP_INTERPRETER_SIZE]);
+        Some(VipRandom { page, r9: 0 })
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn next_byte(&mut self) -> u8 {
+        // INC R9, then ADD the byte at 0x100 + R9.0 to R9.1, keeping the carry in DF
+        self.r9 = self.r9.wrapping_add(1);
+        let sum = self.page[(self.r9 & 0xFF) as usize] as u16 + (self.r9 >> 8);
+        let value = sum as u8;
+        // SHRC shifts DF in from the top, ADD adds the stored sum back
+        let shifted = (value >> 1) | ((sum >> 1) & 0x80) as u8;
+        let result = value.wrapping_add(shifted);
+        self.r9 = (self.r9 & 0xFF) | (result as u16) << 8;
+        result
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.r9 = seed as u16;
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.r9);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let r9 = reader.u16()?;
+        finish(reader)?;
+        self.r9 = r9;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "vip"
+    }
+}
+
+// states of a different source don't have the same size
+fn finish(reader: &StateReader) -> Result<(), SaveStateError> {
+    if reader.is_empty() {
+        Ok(())
+    } else {
+        Err(SaveStateError::Corrupt(
+            "state of a different random source",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use random::*;
+
+    #[test]
+    fn test_split_mix_covers_every_byte() {
+        let mut source = SplitMix::new(1);
+        let mut seen = [false; 256];
+        for _ in 0..10_000 {
+            seen[source.next_byte() as usize] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn test_split_mix_is_reproducible() {
+        let mut first = SplitMix::new(42);
+        let mut second = SplitMix::new(7);
+        second.reseed(42);
+        for _ in 0..100 {
+            assert_eq!(first.next_byte(), second.next_byte());
+        }
+
+        // reference value of the splitmix64 paper implementation
+        assert_eq!(SplitMix::new(0).next_u64(), 0xE220_A839_7B1D_CDAF);
+    }
+
+    #[test]
+    fn test_sequence_repeats() {
+        let mut source = Sequence::new(&[1, 2, 3]);
+        let bytes: Vec<u8> = (0..5).map(|_| source.next_byte()).collect();
+        assert_eq!(bytes, vec![1, 2, 3, 1, 2]);
+        source.reseed(99);
+        assert_eq!(source.next_byte(), 1);
+    }
+
+    #[test]
+    fn test_vip_routine() {
+        let mut dump = vec![0; VIP_INTERPRETER_SIZE];
+        dump[0x101] = 0x10;
+        dump[0x102] = 0xF8;
+        let mut source = VipRandom::from_interpreter(&dump).unwrap();
+        // 0x10 + 0x00, plus itself shifted right
+        assert_eq!(source.next_byte(), 0x18);
+        // 0xF8 + 0x18 = 0x110, the carry is shifted back in: 0x10 + 0x88
+        assert_eq!(source.next_byte(), 0x98);
+        assert_eq!(source.r9, 0x9802);
+
+        assert!(VipRandom::from_interpreter(&dump[..0x100]).is_none());
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut source = SplitMix::new(5);
+        source.next_byte();
+        let mut writer = StateWriter::default();
+        source.save_state(&mut writer);
+        let expected = source.next_byte();
+
+        let state = writer.into_inner();
+        let mut restored = SplitMix::new(0);
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.next_byte(), expected);
+
+        let mut sequence = Sequence::new(&[1, 2]);
+        let mut writer = StateWriter::default();
+        writer.u32(2);
+        let state = writer.into_inner();
+        assert!(sequence.load_state(&mut StateReader::new(&state)).is_err());
+    }
+}
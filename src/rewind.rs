@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+
+/// Memory for rewind snapshots when nothing else is configured,
+/// enough for well over 30 seconds of one snapshot per frame for typical programs
+pub const DEFAULT_REWIND_MEMORY: usize = 4 * 1024 * 1024;
+
+/// Bounded history of save states for stepping backwards in time
+///
+/// Only the newest state is kept in full. Every older state is stored as the
+/// difference to the state after it, XORed and with runs of zero bytes compressed,
+/// so frames that change little of memory and the display cost a few bytes.
+/// Because each delta only depends on newer states, the oldest entry can be
+/// dropped whenever the memory limit is reached
+pub struct Rewind {
+    latest: Option<Vec<u8>>,
+    // oldest first
+    deltas: VecDeque<Vec<u8>>,
+    memory_limit: usize,
+    memory_used: usize,
+}
+
+impl Default for Rewind {
+    fn default() -> Rewind {
+        Rewind::new(DEFAULT_REWIND_MEMORY)
+    }
+}
+
+impl Rewind {
+    /// Keep at most `memory_limit` bytes of snapshots
+    pub fn new(memory_limit: usize) -> Rewind {
+        Rewind {
+            latest: None,
+            deltas: VecDeque::new(),
+            memory_limit,
+            memory_used: 0,
+        }
+    }
+
+    pub fn memory_limit(&self) -> usize {
+        self.memory_limit
+    }
+
+    /// Bytes currently used by snapshots
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    /// Number of states `pop` can still step back to
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.memory_used = 0;
+    }
+
+    /// Record `state` as the newest snapshot
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            let delta = compress(&xor_delta(&previous, &state));
+            self.memory_used += delta.len();
+            self.memory_used -= previous.len();
+            self.deltas.push_back(delta);
+        }
+        self.memory_used += state.len();
+        self.latest = Some(state);
+
+        while self.memory_used > self.memory_limit {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.memory_used -= oldest.len(),
+                None => {
+                    // not even a single state fits
+                    self.clear();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Step back to the state recorded before the newest one and return it,
+    /// the newest state is discarded
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.take()?;
+        let difference = decompress(&delta);
+        let previous: Vec<u8> = (0..delta_length(&delta))
+            .map(|offset| {
+                latest.get(offset).cloned().unwrap_or(0)
+                    ^ difference.get(offset).cloned().unwrap_or(0)
+            })
+            .collect();
+
+        self.memory_used -= delta.len() + latest.len();
+        self.memory_used += previous.len();
+        self.latest = Some(previous.clone());
+        Some(previous)
+    }
+}
+
+/// XOR of `a` and `b`, the shorter one padded with zeros, prefixed with the length of `a`
+fn xor_delta(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut delta = (a.len() as u32).to_le_bytes().to_vec();
+    for offset in 0..a.len().max(b.len()) {
+        let x = a.get(offset).cloned().unwrap_or(0);
+        let y = b.get(offset).cloned().unwrap_or(0);
+        delta.push(x ^ y);
+    }
+    delta
+}
+
+/// Length of the older state, stored in front of the compressed delta
+fn delta_length(compressed: &[u8]) -> usize {
+    let mut length = [0; 4];
+    length.copy_from_slice(&compressed[..4]);
+    u32::from_le_bytes(length) as usize
+}
+
+/// The length prefix is stored as is, the rest as pairs of a zero run and literal bytes,
+/// each run length a LEB128 number
+fn compress(delta: &[u8]) -> Vec<u8> {
+    let (length, data) = delta.split_at(4);
+    let mut out = length.to_vec();
+    let mut position = 0;
+    while position < data.len() {
+        let zeros = data[position..].iter().take_while(|&&b| b == 0).count();
+        position += zeros;
+        let literals = data[position..].iter().take_while(|&&b| b != 0).count();
+        push_varint(&mut out, zeros);
+        push_varint(&mut out, literals);
+        out.extend_from_slice(&data[position..position + literals]);
+        position += literals;
+    }
+    out
+}
+
+/// Inverse of `compress` without the length prefix
+fn decompress(compressed: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut position = 4;
+    while position < compressed.len() {
+        let zeros = read_varint(compressed, &mut position);
+        let literals = read_varint(compressed, &mut position);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&compressed[position..position + literals]);
+        position += literals;
+    }
+    out
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= (byte as usize & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rewind::*;
+
+    #[test]
+    fn test_push_and_pop() {
+        let mut rewind = Rewind::new(1 << 20);
+        let first = vec![0; 4096];
+        let mut second = first.clone();
+        second[100] = 1;
+        // the display switched resolution, the state grew
+        let mut third = second.clone();
+        third.resize(5000, 0);
+        third[4500] = 7;
+
+        rewind.push(first.clone());
+        rewind.push(second.clone());
+        rewind.push(third);
+        assert_eq!(rewind.len(), 2);
+        assert_eq!(rewind.pop(), Some(second));
+        assert_eq!(rewind.pop(), Some(first));
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn test_small_changes_are_cheap() {
+        let mut rewind = Rewind::new(1 << 20);
+        let mut state = vec![0xAB; 4096];
+        for frame in 0..100 {
+            state[frame] = frame as u8;
+            rewind.push(state.clone());
+        }
+        assert!(rewind.memory_used() < 4096 + 100 * 16);
+    }
+
+    #[test]
+    fn test_memory_limit_drops_oldest() {
+        let mut rewind = Rewind::new(1024);
+        for frame in 0..1000 {
+            let mut state = vec![0; 512];
+            state[frame % 512] = 0xFF;
+            rewind.push(state);
+        }
+        assert!(rewind.memory_used() <= 1024);
+        assert!(!rewind.is_empty());
+        while let Some(state) = rewind.pop() {
+            assert_eq!(state.len(), 512);
+        }
+    }
+
+    #[test]
+    fn test_state_larger_than_limit() {
+        let mut rewind = Rewind::new(16);
+        rewind.push(vec![1; 32]);
+        rewind.push(vec![2; 32]);
+        assert_eq!(rewind.memory_used(), 0);
+        assert_eq!(rewind.pop(), None);
+    }
+}
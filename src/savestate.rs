@@ -0,0 +1,240 @@
+use error::SaveStateError;
+use std::path::{Path, PathBuf};
+
+/// First bytes of every save state file
+pub const MAGIC: [u8; 4] = *b"CH8S";
+/// Bumped whenever the layout of the state changes
+pub const VERSION: u16 = 2;
+
+// magic, version, rom hash and payload length
+const HEADER_SIZE: usize = 4 + 2 + 8 + 4;
+const CHECKSUM_SIZE: usize = 4;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// 64 bit FNV-1a hash identifying a rom, also used for whole states
+pub fn rom_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// CRC-32 as used by zlib and png
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (CRC32_POLYNOMIAL & mask);
+        }
+    }
+    !crc
+}
+
+/// File name of save slot `slot` for the rom at `rom_path`, e.g. `pong.ch8.1.state`
+pub fn slot_path(rom_path: &Path, slot: u8) -> PathBuf {
+    let mut name = rom_path.as_os_str().to_owned();
+    name.push(format!(".{}.state", slot));
+    PathBuf::from(name)
+}
+
+/// Wrap `payload` in the header and append the checksum
+/// Layout, all numbers little endian:
+/// magic, version: u16, rom hash: u64, payload length: u32, payload, crc32 of everything before
+pub fn encode(rom_hash: u64, payload: &[u8]) -> Vec<u8> {
+    let mut writer = StateWriter::default();
+    writer.bytes(&MAGIC);
+    writer.u16(VERSION);
+    writer.u64(rom_hash);
+    writer.u32(payload.len() as u32);
+    writer.bytes(payload);
+    let checksum = crc32(&writer.buffer);
+    writer.u32(checksum);
+    writer.into_inner()
+}
+
+/// Check the header and checksum of `data` and return its payload
+/// The state has to belong to the rom identified by `expected_rom_hash`
+pub fn decode(data: &[u8], expected_rom_hash: u64) -> Result<&[u8], SaveStateError> {
+    if data.len() < HEADER_SIZE + CHECKSUM_SIZE || data[..4] != MAGIC {
+        return Err(SaveStateError::NotASaveState);
+    }
+
+    let mut reader = StateReader::new(&data[4..HEADER_SIZE]);
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let found = reader.u64()?;
+    let length = reader.u32()? as usize;
+    if data.len() != HEADER_SIZE + length + CHECKSUM_SIZE {
+        return Err(SaveStateError::Truncated);
+    }
+
+    let (contents, checksum) = data.split_at(HEADER_SIZE + length);
+    if StateReader::new(checksum).u32()? != crc32(contents) {
+        return Err(SaveStateError::ChecksumMismatch);
+    }
+    if found != expected_rom_hash {
+        return Err(SaveStateError::RomMismatch {
+            expected: expected_rom_hash,
+            found,
+        });
+    }
+    Ok(&contents[HEADER_SIZE..])
+}
+
+/// Appends little endian values to a buffer
+#[derive(Default)]
+pub struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&[value as u8, (value >> 8) as u8]);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.u16(value as u16);
+        self.u16((value >> 16) as u16);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.u32(value as u32);
+        self.u32((value >> 32) as u32);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// A length prefixed byte slice
+    pub fn slice(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+/// Reads values written by `StateWriter`, running out of data is `SaveStateError::Truncated`
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Corrupt("invalid flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.bytes(2)?;
+        Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(self.u16()? as u32 | (self.u16()? as u32) << 16)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() - self.position < count {
+            return Err(SaveStateError::Truncated);
+        }
+        let bytes = &self.data[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    /// A byte slice written by `StateWriter::slice`
+    pub fn slice(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let length = self.u32()? as usize;
+        self.bytes(length)
+    }
+
+    /// True once every byte was read
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use savestate::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let state = encode(42, b"payload");
+        assert_eq!(&state[..4], b"CH8S");
+        assert_eq!(decode(&state, 42).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn test_decode_rejects_other_rom() {
+        let state = encode(42, b"payload");
+        match decode(&state, 43) {
+            Err(SaveStateError::RomMismatch { expected, found }) => {
+                assert_eq!(expected, 43);
+                assert_eq!(found, 42);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_corruption() {
+        let mut state = encode(42, b"payload");
+        state[20] ^= 1;
+        match decode(&state, 42) {
+            Err(SaveStateError::ChecksumMismatch) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match decode(b"not a state at all", 42) {
+            Err(SaveStateError::NotASaveState) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_slot_path() {
+        assert_eq!(
+            slot_path(Path::new("roms/pong.ch8"), 3),
+            PathBuf::from("roms/pong.ch8.3.state")
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Runs the test roms from `roms/` without a frontend and compares the screen with the golden
+//! images in `tests/golden/`, one character per pixel.
+//! `CHIP8_UPDATE_GOLDEN=1 cargo test --test roms` writes the current screens as new goldens.
+
+extern crate chip8;
+
+use chip8::cpu::Cpu;
+use chip8::golden::{diff, render};
+use chip8::{Framebuffer, Machine, Quirks};
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const UPDATE_GOLDEN: &str = "CHIP8_UPDATE_GOLDEN";
+/// Both roms are done after a few frames, the rest makes sure the result stays on screen
+const FRAMES: u32 = 120;
+
+fn run_rom(rom: &str, quirks: Quirks, frames: u32) -> Framebuffer {
+    let mut machine = Machine::new(Cpu::default().init().with_quirks(quirks));
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(rom);
+    machine.load_rom(path.to_str().unwrap()).unwrap();
+    for frame in 0..frames {
+        if let Err(err) = machine.run_frame() {
+            panic!("{} halted in frame {}: {}", rom, frame, err);
+        }
+    }
+    machine.framebuffer().clone()
+}
+
+fn check_golden(name: &str, framebuffer: &Framebuffer) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.txt", name));
+    let actual = render(framebuffer);
+    if env::var_os(UPDATE_GOLDEN).is_some() {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "{}: {}, run with {}=1 to create it",
+            path.display(),
+            err,
+            UPDATE_GOLDEN
+        )
+    });
+    if let Some(diff) = diff(&expected, &actual) {
+        panic!(
+            "{} does not match {}\n{}run with {}=1 to accept the new screen",
+            name,
+            path.display(),
+            diff,
+            UPDATE_GOLDEN
+        );
+    }
+}
+
+#[test]
+fn bc_test() {
+    // expects FX55 and FX65 to leave I alone, shows "BON" when every test passes
+    let framebuffer = run_rom("roms/BC_test.ch8", Quirks::chip48(), FRAMES);
+    check_golden("BC_test", &framebuffer);
+}
+
+#[test]
+fn sctest() {
+    // test 24 checks that FX1E sets VF on overflow, shows "OK" when every test passes
+    let quirks = Quirks {
+        add_to_i_sets_vf: true,
+        ..Quirks::super_chip()
+    };
+    let framebuffer = run_rom("roms/sctest.c8", quirks, FRAMES);
+    check_golden("sctest", &framebuffer);
+}